@@ -1,5 +1,5 @@
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 
 use crate::map;
 type Point = map::map::Point;
@@ -110,3 +110,340 @@ pub fn astar_with_reservation(
     }
     None
 }
+
+/// CBSの制約ツリーが1エージェントに課す制約。`Vertex`は「このエージェントは時刻`time`に
+/// `pos`にいてはいけない」、`Edge`は「このエージェントは時刻`time`に`edge`の向きで
+/// 移動してはいけない」を表す。
+#[derive(Clone, Copy)]
+enum CbsConstraintKind {
+    Vertex(Point, usize),
+    Edge((Point, Point), usize),
+}
+
+#[derive(Clone, Copy)]
+struct CbsConstraint {
+    agent: usize,
+    kind: CbsConstraintKind,
+}
+
+/// 2エージェント間で見つかった衝突。`find_conflict`はパス中で最初に見つかったものだけを返す
+/// （CBSは1回の分岐につき1つの衝突だけ解決すればよい）。
+enum CbsConflict {
+    Vertex {
+        a1: usize,
+        a2: usize,
+        pos: Point,
+        time: usize,
+    },
+    Edge {
+        a1: usize,
+        a2: usize,
+        edge: (Point, Point),
+        time: usize,
+    },
+}
+
+/// 経路の終端(ゴール到達)後は、そのエージェントはゴールに留まり続けると仮定して
+/// 時刻`t`での位置を返す。これにより、先にゴールした短い経路のエージェントの上を
+/// 後発のエージェントが後から通り抜ける、という見落としを防ぐ。
+fn position_at(path: &[Point], t: usize) -> Point {
+    if t < path.len() {
+        path[t]
+    } else {
+        *path.last().expect("path must not be empty")
+    }
+}
+
+fn find_conflict(paths: &[Vec<Point>]) -> Option<CbsConflict> {
+    let horizon = paths.iter().map(|p| p.len()).max().unwrap_or(0);
+
+    for t in 0..horizon {
+        for i in 0..paths.len() {
+            for j in (i + 1)..paths.len() {
+                let pi = position_at(&paths[i], t);
+                let pj = position_at(&paths[j], t);
+                if pi == pj {
+                    return Some(CbsConflict::Vertex {
+                        a1: i,
+                        a2: j,
+                        pos: pi,
+                        time: t,
+                    });
+                }
+
+                if t > 0 {
+                    let prev_i = position_at(&paths[i], t - 1);
+                    let prev_j = position_at(&paths[j], t - 1);
+                    // i,jがすれ違うエージェント・スワップ衝突: 1ステップで互いの位置を交換している
+                    if prev_i == pj && prev_j == pi && prev_i != pi {
+                        return Some(CbsConflict::Edge {
+                            a1: i,
+                            a2: j,
+                            edge: (prev_i, pi),
+                            time: t,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// ある制約ツリーのノードが持つ制約群のうち、特定のエージェントに関するものだけを
+/// `astar_with_reservation`が読める`NodeReservation`/`EdgeReservation`に変換する。
+fn reservations_for_agent(
+    agent: usize,
+    constraints: &[CbsConstraint],
+) -> (NodeReservation, EdgeReservation) {
+    let mut node_res = NodeReservation::new();
+    let mut edge_res = EdgeReservation::new();
+    for constraint in constraints {
+        if constraint.agent != agent {
+            continue;
+        }
+        match constraint.kind {
+            CbsConstraintKind::Vertex(pos, time) => {
+                node_res.insert((pos, time));
+            }
+            CbsConstraintKind::Edge(edge, time) => {
+                edge_res.insert((edge, time));
+            }
+        }
+    }
+    (node_res, edge_res)
+}
+
+/// CBSの制約ツリーに積むノード。コストは全エージェントの経路長の合計。
+struct CbsNode {
+    constraints: Vec<CbsConstraint>,
+    solution: Vec<Vec<Point>>,
+    cost: usize,
+}
+
+impl CbsNode {
+    fn cost_of(solution: &[Vec<Point>]) -> usize {
+        solution.iter().map(|path| path.len()).sum()
+    }
+}
+
+impl PartialEq for CbsNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+impl Eq for CbsNode {}
+impl Ord for CbsNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeapは最大ヒープなので、コストが小さいノードほど優先されるよう反転する。
+        other.cost.cmp(&self.cost)
+    }
+}
+impl PartialOrd for CbsNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// 制約ツリーの展開上限。解がない場合や病的な入力で探索が終わらないことを防ぐ。
+const CBS_MAX_EXPANSIONS: usize = 10_000;
+
+/// Conflict-Based Search: `astar_with_reservation`を低レベルの単一エージェント解法として使い、
+/// 高レベルでは制約ツリーをコストの昇順（最良優先）に探索する。予約テーブルを使う
+/// 優先度順の逐次計画（早いエージェントが予約テーブルを先に埋める方式）は高速だが不完全で、
+/// デッドロックし得る。CBSは最初は制約なしで各エージェントを独立に計画し、
+/// 2エージェントが同じ時刻・同じセルに居合わせる(vertex conflict)か、1ステップで位置を
+/// 交換する(edge/swap conflict)最初の衝突を見つけるたびに、そのどちらか一方を禁止する
+/// 制約を加えた子ノードへ分岐し、対象エージェントだけ再計画する。衝突がなくなった時点の解が
+/// 最適解（コストの昇順で展開するため）。木が`CBS_MAX_EXPANSIONS`を超えて大きくなる場合は
+/// `None`を返す。
+pub fn cbs_plan(
+    grid: &[Vec<char>],
+    starts_and_goals: &[(Point, Point)],
+) -> Option<Vec<Vec<Point>>> {
+    let empty_node_res = NodeReservation::new();
+    let empty_edge_res = EdgeReservation::new();
+
+    let mut root_solution = Vec::with_capacity(starts_and_goals.len());
+    for &(start, goal) in starts_and_goals {
+        let path = astar_with_reservation(grid, start, goal, &empty_node_res, &empty_edge_res, 0)?;
+        root_solution.push(path);
+    }
+
+    let mut open = BinaryHeap::new();
+    open.push(CbsNode {
+        cost: CbsNode::cost_of(&root_solution),
+        constraints: Vec::new(),
+        solution: root_solution,
+    });
+
+    let mut expansions = 0;
+    while let Some(node) = open.pop() {
+        expansions += 1;
+        if expansions > CBS_MAX_EXPANSIONS {
+            return None;
+        }
+
+        let Some(conflict) = find_conflict(&node.solution) else {
+            return Some(node.solution);
+        };
+
+        let branches = match conflict {
+            CbsConflict::Vertex { a1, a2, pos, time } => [
+                (a1, CbsConstraintKind::Vertex(pos, time)),
+                (a2, CbsConstraintKind::Vertex(pos, time)),
+            ],
+            CbsConflict::Edge { a1, a2, edge, time } => [
+                (a1, CbsConstraintKind::Edge(edge, time)),
+                (a2, CbsConstraintKind::Edge((edge.1, edge.0), time)),
+            ],
+        };
+
+        for (agent, kind) in branches {
+            let mut constraints = node.constraints.clone();
+            constraints.push(CbsConstraint { agent, kind });
+
+            let (node_res, edge_res) = reservations_for_agent(agent, &constraints);
+            let (start, goal) = starts_and_goals[agent];
+            let Some(new_path) =
+                astar_with_reservation(grid, start, goal, &node_res, &edge_res, 0)
+            else {
+                continue; // この制約のもとでは当該エージェントの経路が存在しない
+            };
+
+            let mut solution = node.solution.clone();
+            solution[agent] = new_path;
+
+            open.push(CbsNode {
+                cost: CbsNode::cost_of(&solution),
+                constraints,
+                solution,
+            });
+        }
+    }
+
+    None
+}
+
+/// 障害物を考慮した真の最短距離ヒューリスティック。ゴールから逆方向に1回BFSすることで、
+/// 到達可能な各セルまでの距離を事前計算する。単純なマンハッタン距離と違い壁を回り込む分の
+/// 距離も正しく見積もれるため、`whca_plan`が予約のない窓の外を先読みする際に無駄な探索を
+/// 減らせる。
+fn true_distance_heuristic(grid: &[Vec<char>], goal: Point) -> HashMap<Point, usize> {
+    let dirs = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+    let mut dist = HashMap::new();
+    let mut queue = VecDeque::new();
+
+    dist.insert(goal, 0);
+    queue.push_back(goal);
+
+    while let Some(pos) = queue.pop_front() {
+        let d = dist[&pos];
+        for &(dx, dy) in &dirs {
+            let nx = pos.0 as isize + dx;
+            let ny = pos.1 as isize + dy;
+            if nx < 0 || ny < 0 {
+                continue;
+            }
+            let np = (nx as usize, ny as usize);
+            if np.1 >= map::map::HEIGHT || np.0 >= map::map::WIDTH || grid[np.1][np.0] != '.' {
+                continue;
+            }
+            if dist.contains_key(&np) {
+                continue;
+            }
+            dist.insert(np, d + 1);
+            queue.push_back(np);
+        }
+    }
+
+    dist
+}
+
+/// `astar_with_reservation`の窓付き(windowed)版。予約テーブルは開始時刻から`window`
+/// タイムステップ先までしか尊重せず、それを超えたら予約を無視してゴールまたは窓の境界に
+/// 到達した時点で打ち切り、部分パスを返す。`astar_with_reservation`は経路全体をゴールまで
+/// 予約してしまうため、1エージェントの長い経路が遠い未来のセルまで専有し、後続のエージェントが
+/// 長い迂回を強いられたり計画自体が失敗したりする。この関数は1回分の部分パスを返すだけで、
+/// 予約テーブルへの書き込みや再計画は行わない。窓の外側は予約が存在しないと分かっているぶん、
+/// `true_distance_heuristic`で事前計算した真の最短距離を使って先読みする。
+pub fn whca_plan(
+    grid: &[Vec<char>],
+    start: Point,
+    goal: Point,
+    node_res: &NodeReservation,
+    edge_res: &EdgeReservation,
+    start_time: usize,
+    window: usize,
+) -> Option<Vec<Point>> {
+    let true_dist = true_distance_heuristic(grid, goal);
+    let heuristic_at = |p: Point| true_dist.get(&p).copied().unwrap_or_else(|| heuristic(p, goal));
+
+    let dirs = [(1, 0), (-1, 0), (0, 1), (0, -1), (0, 0)]; // (0,0)はWAIT
+    let mut open = BinaryHeap::new();
+    let mut g_score = HashMap::new();
+    let mut came_from = HashMap::new();
+
+    g_score.insert((start, start_time), 0);
+    open.push(TimeNode {
+        pos: start,
+        g: start_time,
+        f: start_time + heuristic_at(start),
+    });
+
+    while let Some(TimeNode { pos, g, .. }) = open.pop() {
+        let elapsed_from_start = g - start_time;
+        if pos == goal || elapsed_from_start >= window {
+            let mut path = Vec::new();
+            let mut cur = (pos, g);
+            while let Some(&prev) = came_from.get(&cur) {
+                path.push(cur.0);
+                cur = prev;
+            }
+            path.push(start);
+            path.reverse();
+            return Some(path);
+        }
+
+        for &(dx, dy) in &dirs {
+            let nx = pos.0 as isize + dx;
+            let ny = pos.1 as isize + dy;
+            if nx < 0 || ny < 0 {
+                continue;
+            }
+            let np = (nx as usize, ny as usize);
+            if np.1 >= map::map::HEIGHT || np.0 >= map::map::WIDTH || grid[np.1][np.0] != '.' {
+                continue;
+            }
+
+            let next_time = g + 1;
+            // 窓の内側でだけ予約を尊重する。窓の外の予約は（もしあっても）どうせ次の
+            // 再計画までに古くなっているはずなので無視してよい。
+            if next_time - start_time <= window {
+                if node_res.contains(&(np, next_time)) {
+                    continue;
+                }
+                if edge_res.contains(&((pos, np), next_time))
+                    || edge_res.contains(&((np, pos), next_time))
+                {
+                    continue;
+                }
+            }
+
+            let tentative_g = next_time;
+            let key = (np, tentative_g);
+            let best = *g_score.get(&key).unwrap_or(&usize::MAX);
+            if tentative_g < best {
+                came_from.insert(key, (pos, g));
+                g_score.insert(key, tentative_g);
+                let f = tentative_g + heuristic_at(np);
+                open.push(TimeNode {
+                    pos: np,
+                    g: tentative_g,
+                    f,
+                });
+            }
+        }
+    }
+    None
+}
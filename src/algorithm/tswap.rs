@@ -1,5 +1,8 @@
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use rand::Rng;
+use rayon::prelude::*;
 
 use crate::map::agent::AgentState;
 use crate::map::map::Point;
@@ -36,12 +39,131 @@ impl Ord for Agent {
     }
 }
 
-pub fn tswap_mapd(
-    grid: &[Vec<char>],
-    initial_positions: Vec<Point>,
-    tasks: &[Task],
-) -> Vec<Vec<(Point, AgentState)>> {
-    // --- Grid to Node graph conversion ---
+/// `get_path`のA*探索を制御するプランナー設定。`weight`は重み付きA*のw(>= 1.0)で、
+/// 1.0なら従来通りの最適(admissible)探索、大きくするほど貪欲なbest-first寄りになり
+/// ノード展開数が減る代わりに経路長は最大でw倍まで最適から外れ得る。
+#[derive(Clone, Copy, Debug)]
+pub struct PlannerConfig {
+    pub weight: f64,
+}
+
+impl Default for PlannerConfig {
+    fn default() -> Self {
+        PlannerConfig { weight: 1.0 }
+    }
+}
+
+/// ゴールごとの全点間最短距離/backpointerをキャッシュする構造体。`nodes`グラフは実行中不変なので、
+/// ゴール`g`について一度だけ単位コストグラフを逆方向BFSし、`dist[g][node]`と、各ノードから`g`へ
+/// 向かう最初の1歩`next[g][node]`を埋めておけば、以降`get_path(v, g)`はO(経路長)のテーブル参照で
+/// 済む(毎タイムステップA*を再実行する必要がなくなる)。実際に使われたゴールだけを遅延展開する。
+struct ShortestPathCache {
+    dist: HashMap<usize, Vec<usize>>,
+    next: HashMap<usize, Vec<Option<usize>>>,
+}
+
+impl ShortestPathCache {
+    fn new() -> Self {
+        ShortestPathCache {
+            dist: HashMap::new(),
+            next: HashMap::new(),
+        }
+    }
+
+    fn ensure_goal(&mut self, goal: usize, nodes: &[Node]) {
+        if self.dist.contains_key(&goal) {
+            return;
+        }
+        let n = nodes.len();
+        let mut dist = vec![usize::MAX; n];
+        dist[goal] = 0;
+        let mut queue = VecDeque::new();
+        queue.push_back(goal);
+        while let Some(u) = queue.pop_front() {
+            for &v in &nodes[u].neighbors {
+                if dist[v] == usize::MAX {
+                    dist[v] = dist[u] + 1;
+                    queue.push_back(v);
+                }
+            }
+        }
+
+        // 各ノードから`goal`へ向かう最初の1歩: dist[goal]が自分よりちょうど1小さい隣接ノード
+        let mut next = vec![None; n];
+        for (u, &du) in dist.iter().enumerate() {
+            if u == goal || du == usize::MAX {
+                continue;
+            }
+            next[u] = nodes[u]
+                .neighbors
+                .iter()
+                .copied()
+                .find(|&v| dist[v] != usize::MAX && dist[v] + 1 == du);
+        }
+
+        self.dist.insert(goal, dist);
+        self.next.insert(goal, next);
+    }
+
+    /// `get_path`と同じ意味の経路(`path[0] == start`)を、キャッシュされたbackpointerを
+    /// たどるだけで返す。到達不能な場合は`start`のみを含むvecを返す(`get_path`と違い、
+    /// ゴールに近い隣接ノードへの1歩は返さない)。
+    fn path(&mut self, start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
+        if start == goal {
+            return vec![start];
+        }
+        self.ensure_goal(goal, nodes);
+        let next = &self.next[&goal];
+        let dist = &self.dist[&goal];
+        if dist[start] == usize::MAX {
+            return vec![start];
+        }
+
+        let mut path = vec![start];
+        let mut current = start;
+        while current != goal {
+            match next[current] {
+                Some(n) => {
+                    path.push(n);
+                    current = n;
+                }
+                None => break,
+            }
+        }
+        path
+    }
+
+    /// `path`と同じだが`ensure_goal`を呼ばない読み取り専用版。呼び出し側が事前に
+    /// `ensure_goal`でゴールを展開済みである前提で、rayonの並列区間から`&self`だけで
+    /// 安全に呼べるようにする(`&mut self`だと並列クロージャ間で可変借用が競合する)。
+    fn path_ready(&self, start: usize, goal: usize) -> Vec<usize> {
+        if start == goal {
+            return vec![start];
+        }
+        let (Some(next), Some(dist)) = (self.next.get(&goal), self.dist.get(&goal)) else {
+            return vec![start];
+        };
+        if dist[start] == usize::MAX {
+            return vec![start];
+        }
+
+        let mut path = vec![start];
+        let mut current = start;
+        while current != goal {
+            match next[current] {
+                Some(n) => {
+                    path.push(n);
+                    current = n;
+                }
+                None => break,
+            }
+        }
+        path
+    }
+}
+
+// grid上の通行可能マス('@'以外)からNodeグラフを作る。tswap_mapd/tswap_mapd_bundledで共有する。
+fn build_graph(grid: &[Vec<char>]) -> (Vec<Node>, HashMap<Point, usize>, Vec<Point>) {
     let mut nodes = vec![];
     let h = grid.len();
     let w = grid[0].len();
@@ -75,6 +197,27 @@ pub fn tswap_mapd(
             neighbors,
         });
     }
+    (nodes, pos2id, id2pos)
+}
+
+pub fn tswap_mapd(
+    grid: &[Vec<char>],
+    initial_positions: Vec<Point>,
+    tasks: &[Task],
+) -> Vec<Vec<(Point, AgentState)>> {
+    tswap_mapd_with_config(grid, initial_positions, tasks, &PlannerConfig::default())
+}
+
+/// `tswap_mapd`と同じだが、`get_path`のA*探索に使う重み`config.weight`を指定できる。
+/// w=1.0(デフォルト)は従来通りの最適探索、w>1.0は速度優先のbounded-suboptimalモード。
+pub fn tswap_mapd_with_config(
+    grid: &[Vec<char>],
+    initial_positions: Vec<Point>,
+    tasks: &[Task],
+    config: &PlannerConfig,
+) -> Vec<Vec<(Point, AgentState)>> {
+    let (nodes, pos2id, id2pos) = build_graph(grid);
+    let mut cache = ShortestPathCache::new();
 
     let num_agents = initial_positions.len();
     let mut paths: Vec<Vec<(Point, AgentState)>> = vec![vec![]; num_agents];
@@ -138,7 +281,7 @@ pub fn tswap_mapd(
             }
         }
 
-        tswap_step(&mut agents, &nodes);
+        tswap_step(&mut agents, &nodes, config, &mut cache);
 
         // --- Record Paths ---
         for i in 0..num_agents {
@@ -171,20 +314,48 @@ pub fn tswap_mapd(
     paths
 }
 
-fn tswap_step(agents: &mut [Agent], nodes: &[Node]) {
+fn tswap_step(
+    agents: &mut [Agent],
+    nodes: &[Node],
+    config: &PlannerConfig,
+    cache: &mut ShortestPathCache,
+) {
     let n = agents.len();
 
+    // 競合解決に入る前に、各エージェントの現在地からゴールへ向かう最初の1歩を
+    // rayonで並列に求めておく。`nodes`は不変なので読み取り専用のread-onlyワークとして
+    // 全コア分並列化でき、以降の交換/デッドロック/移動判定はこの結果を使い逐次で行う。
+    if config.weight <= 1.0 {
+        for a in agents.iter() {
+            if a.v != a.g {
+                cache.ensure_goal(a.g, nodes);
+            }
+        }
+    }
+    let intents: Vec<Option<usize>> = agents
+        .par_iter()
+        .map(|a| {
+            if a.v == a.g {
+                return None;
+            }
+            let path = if config.weight <= 1.0 {
+                cache.path_ready(a.v, a.g)
+            } else {
+                get_path(a.v, a.g, nodes, config.weight)
+            };
+            path.get(1).copied()
+        })
+        .collect();
+
     // Goal swapping phase
     for i in 0..n {
         if agents[i].v == agents[i].g {
             continue;
         }
 
-        let path = get_path(agents[i].v, agents[i].g, nodes);
-        if path.len() < 2 {
+        let Some(u) = intents[i] else {
             continue;
-        }
-        let u = path[1];
+        };
 
         if let Some(j) = agents.iter().position(|b| b.v == u) {
             if i == j {
@@ -211,11 +382,9 @@ fn tswap_step(agents: &mut [Agent], nodes: &[Node]) {
                         break;
                     }
 
-                    let b_path = get_path(b_v, b_g, nodes);
-                    if b_path.len() < 2 {
+                    let Some(w) = intents[current_b_idx] else {
                         break;
-                    }
-                    let w = b_path[1];
+                    };
 
                     if let Some(c_idx) = agents.iter().position(|c| c.v == w) {
                         if a_p.contains(&current_b_idx) {
@@ -254,18 +423,15 @@ fn tswap_step(agents: &mut [Agent], nodes: &[Node]) {
             continue;
         }
 
-        let path = get_path(agents[i].v, agents[i].g, nodes);
-        if path.len() < 2 {
+        let Some(u) = intents[i] else {
             continue;
-        }
-        let u = path[1];
+        };
 
         // 移動先が空いている、または相互交換の場合に移動
         if let Some(j) = agents.iter().position(|b| b.v == u) {
             if i != j {
                 // Check if this is a mutual swap
-                let path_j = get_path(agents[j].v, agents[j].g, nodes);
-                if path_j.len() >= 2 && path_j[1] == agents[i].v {
+                if intents[j] == Some(agents[i].v) {
                     // Mutual swap: both agents exchange positions
                     let temp_v = agents[i].v;
                     agents[i].v = agents[j].v;
@@ -279,7 +445,7 @@ fn tswap_step(agents: &mut [Agent], nodes: &[Node]) {
     }
 }
 
-fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
+fn get_path(start: usize, goal: usize, nodes: &[Node], weight: f64) -> Vec<usize> {
     if start == goal {
         return vec![start];
     }
@@ -328,7 +494,7 @@ fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
     let start_node = AstarNode {
         node_id: start,
         g_cost: 0,
-        f_cost: heuristic(start),
+        f_cost: (heuristic(start) as f64 * weight).round() as usize,
     };
     open_list.push(start_node);
 
@@ -355,8 +521,10 @@ fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
                 came_from.insert(neighbor_id, current_id);
                 g_score.insert(neighbor_id, tentative_g);
 
+                // w=1.0なら通常のadmissible A*、w>1.0ならheuristicを重み付けしてgreedy best-first寄りにする
+                // (bounded-suboptimal: 得られる経路は最適解の高々w倍)
                 let h_cost = heuristic(neighbor_id);
-                let f_cost = tentative_g + h_cost;
+                let f_cost = tentative_g + (h_cost as f64 * weight).round() as usize;
 
                 let neighbor_node = AstarNode {
                     node_id: neighbor_id,
@@ -386,3 +554,686 @@ fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
 fn manhattan_distance(p1: Point, p2: Point) -> usize {
     ((p1.0 as isize - p2.0 as isize).abs() + (p1.1 as isize - p2.1 as isize).abs()) as usize
 }
+
+// Held-Karp DPが扱えるエンドポイント数(pickup+delivery)の上限。2^m * mのテーブルが
+// 指数的に増えるため、これを超える分のタスクはバンドルに含めない。
+const MAX_BUNDLE_ENDPOINTS: usize = 12;
+
+/// `start`から出発し、`endpoints`(長さm、偶数indexがpickup・その次の奇数indexが対応する
+/// deliveryというペア構成)を全て訪問する最短巡回順序を、Held-Karp型のDPで求める。
+/// `dp[mask][j]` = 集合`mask`を訪問し終えてエンドポイント`j`にいる場合の最小コスト、
+/// 遷移は `dp[mask | 1<<k][k] = min(dp[mask][j] + dist(j, k))`。
+/// deliveryエンドポイントは対応するpickupが既に`mask`に含まれている場合のみ遷移先にできる
+/// (pickup_i → delivery_iの前後関係制約)。返り値は`endpoints`へのインデックス列。
+fn held_karp_bundle_order(
+    start: usize,
+    endpoints: &[usize],
+    nodes: &[Node],
+    cache: &mut ShortestPathCache,
+) -> Vec<usize> {
+    let m = endpoints.len();
+    if m == 0 {
+        return vec![];
+    }
+    assert!(
+        m <= MAX_BUNDLE_ENDPOINTS,
+        "held_karp_bundle_order: too many endpoints for exact DP"
+    );
+
+    // dist[0]はstart、dist[1..=m]はendpoints[0..m]に対応する距離行列
+    let waypoints: Vec<usize> = std::iter::once(start).chain(endpoints.iter().copied()).collect();
+    let mut dist = vec![vec![0usize; m + 1]; m + 1];
+    for (a, &from) in waypoints.iter().enumerate() {
+        for (b, &to) in waypoints.iter().enumerate() {
+            if a != b {
+                // バンドルの巡回順最適化は正確な距離が前提のため、ここは常にキャッシュの
+                // admissibleな最短経路(w=1.0相当)を使う
+                dist[a][b] = cache.path(from, to, nodes).len().saturating_sub(1);
+            }
+        }
+    }
+
+    let delivery_ready = |mask: usize, j: usize| -> bool {
+        // j が奇数index(delivery)なら、対応するpickup(j-1)が既にmaskに含まれている必要がある
+        j % 2 == 0 || mask & (1 << (j - 1)) != 0
+    };
+
+    let full_mask = (1usize << m) - 1;
+    let mut dp = vec![vec![usize::MAX; m]; 1 << m];
+    let mut parent = vec![vec![usize::MAX; m]; 1 << m];
+
+    for j in 0..m {
+        if j % 2 == 0 {
+            let mask = 1 << j;
+            dp[mask][j] = dist[0][j + 1];
+        }
+    }
+
+    for mask in 1..=full_mask {
+        for j in 0..m {
+            if mask & (1 << j) == 0 || dp[mask][j] == usize::MAX {
+                continue;
+            }
+            for k in 0..m {
+                if mask & (1 << k) != 0 || !delivery_ready(mask, k) {
+                    continue;
+                }
+                let new_mask = mask | (1 << k);
+                let cost = dp[mask][j] + dist[j + 1][k + 1];
+                if cost < dp[new_mask][k] {
+                    dp[new_mask][k] = cost;
+                    parent[new_mask][k] = j;
+                }
+            }
+        }
+    }
+
+    let Some(mut j) = (0..m)
+        .filter(|&j| dp[full_mask][j] != usize::MAX)
+        .min_by_key(|&j| dp[full_mask][j])
+    else {
+        return vec![];
+    };
+
+    let mut order = vec![];
+    let mut mask = full_mask;
+    loop {
+        order.push(j);
+        let prev = parent[mask][j];
+        mask &= !(1 << j);
+        if prev == usize::MAX {
+            break;
+        }
+        j = prev;
+    }
+    order.reverse();
+    order
+}
+
+/// `tswap_mapd`の「アイドル時に最も近いタスクを1つだけ貪欲に取る」単一タスク状態機械に代えて、
+/// アイドルになったエージェントへ最大`bundle_size`個のタスクをまとめて割り当て、Held-Karp DPで
+/// 求めた最適巡回順序(各タスクのpickupはdeliveryより先という前後関係制約つき)でまとめて実行する。
+/// タスクが密集している状況で、1件ずつ配り直すことによる無駄な往復移動を削減する。
+pub fn tswap_mapd_bundled(
+    grid: &[Vec<char>],
+    initial_positions: Vec<Point>,
+    tasks: &[Task],
+    bundle_size: usize,
+    config: &PlannerConfig,
+) -> Vec<Vec<(Point, AgentState)>> {
+    let (nodes, pos2id, id2pos) = build_graph(grid);
+    let mut cache = ShortestPathCache::new();
+
+    #[derive(Clone, Copy)]
+    enum EndpointKind {
+        Pickup,
+        Delivery,
+    }
+
+    let num_agents = initial_positions.len();
+    let mut paths: Vec<Vec<(Point, AgentState)>> = vec![vec![]; num_agents];
+    let mut task_used = vec![false; tasks.len()];
+    // 各エージェントの残り巡回計画：訪問すべきノードとその種別(pickup/delivery)
+    let mut agent_plan: Vec<Vec<(usize, EndpointKind)>> = vec![vec![]; num_agents];
+
+    let mut agents: Vec<Agent> = (0..num_agents)
+        .map(|i| {
+            let start_node = pos2id[&initial_positions[i]];
+            Agent {
+                id: i,
+                v: start_node,
+                g: start_node,
+            }
+        })
+        .collect();
+
+    let max_bundle_tasks = (MAX_BUNDLE_ENDPOINTS / 2).max(1).min(bundle_size.max(1));
+    let mut timestep = 0;
+    loop {
+        for i in 0..num_agents {
+            if agents[i].v == agents[i].g && !agent_plan[i].is_empty() {
+                agent_plan[i].remove(0);
+                if let Some(&(next_node, _)) = agent_plan[i].first() {
+                    agents[i].g = next_node;
+                }
+            }
+
+            if agent_plan[i].is_empty() {
+                let current_pos = id2pos[agents[i].v];
+                let mut candidates: Vec<usize> = tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| !task_used[*idx])
+                    .map(|(idx, _)| idx)
+                    .collect();
+                candidates.sort_by_key(|&idx| manhattan_distance(current_pos, tasks[idx].pickup));
+                candidates.truncate(max_bundle_tasks);
+
+                if !candidates.is_empty() {
+                    for &idx in &candidates {
+                        task_used[idx] = true;
+                    }
+                    let endpoints: Vec<usize> = candidates
+                        .iter()
+                        .flat_map(|&idx| [pos2id[&tasks[idx].pickup], pos2id[&tasks[idx].delivery]])
+                        .collect();
+                    let order =
+                        held_karp_bundle_order(agents[i].v, &endpoints, &nodes, &mut cache);
+                    agent_plan[i] = order
+                        .into_iter()
+                        .map(|endpoint_idx| {
+                            let kind = if endpoint_idx % 2 == 0 {
+                                EndpointKind::Pickup
+                            } else {
+                                EndpointKind::Delivery
+                            };
+                            (endpoints[endpoint_idx], kind)
+                        })
+                        .collect();
+                    if let Some(&(first_node, _)) = agent_plan[i].first() {
+                        agents[i].g = first_node;
+                    }
+                }
+            }
+        }
+
+        tswap_step(&mut agents, &nodes, config, &mut cache);
+
+        for i in 0..num_agents {
+            let pos = id2pos[agents[i].v];
+            let state = match agent_plan[i].first() {
+                None => AgentState::IDLE,
+                Some(&(_, EndpointKind::Pickup)) => AgentState::PICKING,
+                Some(&(_, EndpointKind::Delivery)) => {
+                    if agents[i].v == agents[i].g {
+                        AgentState::DELIVERED
+                    } else {
+                        AgentState::CARRYING
+                    }
+                }
+            };
+            paths[i].push((pos, state));
+        }
+
+        timestep += 1;
+
+        let all_tasks_done = task_used.iter().all(|&used| used);
+        let all_agents_idle = agent_plan.iter().all(|p| p.is_empty());
+        if (all_tasks_done && all_agents_idle) || timestep > 2000 {
+            break;
+        }
+    }
+    paths
+}
+
+// 予約テーブルのキー。頂点予約は(node, timestep)→agent id、辺予約は
+// (from, to, timestep)→agent id("timestepからtimestep+1にかけてfrom→toへ移動した"という意味)。
+type VertexReservations = HashMap<(usize, usize), usize>;
+type EdgeReservations = HashMap<(usize, usize, usize), usize>;
+
+/// 状態を`(node_id, timestep)`に一般化したA*。他のエージェントが既に`vertex_reservations`/
+/// `edge_reservations`に書き込んだ頂点・辺を避けて経路を求める、協調的(cooperative)経路探索。
+/// 同じノードに留まる「wait」も1タイムステップを消費する合法な行動として許可する。
+/// 返り値は`start_time`から1ステップずつのノード列(`path[0] == start`)。
+fn space_time_a_star(
+    start: usize,
+    goal: usize,
+    nodes: &[Node],
+    start_time: usize,
+    vertex_reservations: &VertexReservations,
+    edge_reservations: &EdgeReservations,
+) -> Vec<usize> {
+    #[derive(Clone)]
+    struct StNode {
+        node: usize,
+        t: usize,
+        g: usize,
+        f: usize,
+    }
+    impl PartialEq for StNode {
+        fn eq(&self, other: &Self) -> bool {
+            self.f == other.f
+        }
+    }
+    impl Eq for StNode {}
+    impl PartialOrd for StNode {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for StNode {
+        fn cmp(&self, other: &Self) -> Ordering {
+            other
+                .f
+                .cmp(&self.f)
+                .then_with(|| other.g.cmp(&self.g))
+        }
+    }
+
+    let heuristic = |node_id: usize| -> usize {
+        let (x1, y1) = nodes[node_id].pos;
+        let (x2, y2) = nodes[goal].pos;
+        ((x1 as isize - x2 as isize).abs() + (y1 as isize - y2 as isize).abs()) as usize
+    };
+
+    // 無限ループ防止の安全カットオフ。グリッドを何周もwaitし続けるケースを打ち切る
+    let max_t = start_time + nodes.len() * 2 + 50;
+
+    let mut open = BinaryHeap::new();
+    let mut g_score: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut came_from: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+
+    g_score.insert((start, start_time), 0);
+    open.push(StNode {
+        node: start,
+        t: start_time,
+        g: 0,
+        f: heuristic(start),
+    });
+
+    while let Some(current) = open.pop() {
+        if current.node == goal {
+            let mut path = vec![];
+            let mut cur = (current.node, current.t);
+            path.push(cur.0);
+            while let Some(&parent) = came_from.get(&cur) {
+                path.push(parent.0);
+                cur = parent;
+            }
+            path.reverse();
+            return path;
+        }
+
+        if current.t >= max_t {
+            continue;
+        }
+
+        // "wait"(その場に留まる)も含めた候補先。waitもvertex_reservationsで衝突しうる
+        let mut candidates = vec![current.node];
+        candidates.extend(nodes[current.node].neighbors.iter().copied());
+
+        for next_node in candidates {
+            let next_t = current.t + 1;
+            if vertex_reservations.contains_key(&(next_node, next_t)) {
+                continue;
+            }
+            // edge/swap conflict: 相手が同じtimestepにnext_node→current.nodeへ移動していたら、
+            // すれ違いざまの正面衝突になるため禁止
+            if next_node != current.node
+                && edge_reservations.contains_key(&(next_node, current.node, current.t))
+            {
+                continue;
+            }
+
+            let tentative_g = current.g + 1;
+            let key = (next_node, next_t);
+            if tentative_g < *g_score.get(&key).unwrap_or(&usize::MAX) {
+                g_score.insert(key, tentative_g);
+                came_from.insert(key, (current.node, current.t));
+                open.push(StNode {
+                    node: next_node,
+                    t: next_t,
+                    g: tentative_g,
+                    f: tentative_g + heuristic(next_node),
+                });
+            }
+        }
+    }
+
+    // 予約に阻まれて経路が見つからない場合は、`start`だけを含む経路を返す
+    vec![start]
+}
+
+/// エージェントをindex順(優先順位順)に1体ずつspace-time A*で計画し、求めた経路の頂点・辺を
+/// 予約テーブルへ書き込んでから次のエージェントへ進む。`tswap_step`の事後的な衝突解決(スワップ検出や
+/// デッドロック回転)と異なり、計画時点で頂点/辺が衝突しない経路だけを選ぶため、正面衝突や
+/// 回転デッドロックが原理的に発生しない。
+pub fn tswap_mapd_cooperative(
+    grid: &[Vec<char>],
+    initial_positions: Vec<Point>,
+    tasks: &[Task],
+) -> Vec<Vec<(Point, AgentState)>> {
+    let (nodes, pos2id, id2pos) = build_graph(grid);
+    let num_agents = initial_positions.len();
+
+    let mut vertex_reservations: VertexReservations = HashMap::new();
+    let mut edge_reservations: EdgeReservations = HashMap::new();
+
+    // 単純な貪欲割り当て: エージェントindex順(優先順位)に、その時点で最も近い未使用タスクを1つ取る
+    let mut task_used = vec![false; tasks.len()];
+    let mut agent_task: Vec<Option<Task>> = vec![None; num_agents];
+    for (i, &start_pos) in initial_positions.iter().enumerate() {
+        if let Some((idx, _)) = tasks
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !task_used[*idx])
+            .map(|(idx, t)| (idx, manhattan_distance(start_pos, t.pickup)))
+            .min_by_key(|&(_, dist)| dist)
+        {
+            task_used[idx] = true;
+            agent_task[i] = Some(tasks[idx].clone());
+        }
+    }
+
+    let mut traces: Vec<Vec<(Point, AgentState)>> = vec![vec![]; num_agents];
+
+    for i in 0..num_agents {
+        let start_node = pos2id[&initial_positions[i]];
+
+        let Some(task) = agent_task[i].clone() else {
+            vertex_reservations.insert((start_node, 0), i);
+            traces[i].push((id2pos[start_node], AgentState::IDLE));
+            continue;
+        };
+
+        let mut node = start_node;
+        let mut t = 0usize;
+        let legs = [
+            (pos2id[&task.pickup], AgentState::PICKING),
+            (pos2id[&task.delivery], AgentState::CARRYING),
+        ];
+
+        for &(goal, state) in &legs {
+            let path = space_time_a_star(node, goal, &nodes, t, &vertex_reservations, &edge_reservations);
+
+            for (step_idx, &n) in path.iter().enumerate() {
+                let step_t = t + step_idx;
+                vertex_reservations.insert((n, step_t), i);
+                if step_idx > 0 {
+                    edge_reservations.insert((path[step_idx - 1], n, step_t - 1), i);
+                }
+                let reached_goal = n == goal && step_idx == path.len() - 1;
+                let display_state = if reached_goal && goal == pos2id[&task.delivery] {
+                    AgentState::DELIVERED
+                } else {
+                    state
+                };
+                traces[i].push((id2pos[n], display_state));
+            }
+
+            t += path.len().saturating_sub(1);
+            node = goal;
+        }
+    }
+
+    // 各エージェントのトレース長を最長のものに揃える(最後の状態に留まったものとして埋める)
+    let max_len = traces.iter().map(|p| p.len()).max().unwrap_or(0);
+    for trace in traces.iter_mut() {
+        if let Some(&last) = trace.last() {
+            while trace.len() < max_len {
+                trace.push(last);
+            }
+        }
+    }
+
+    traces
+}
+
+/// エージェントiが`order`(タスクインデックス列)をこの順に処理する際の総走行距離
+/// (start -> pickup_0 -> delivery_0 -> pickup_1 -> delivery_1 -> ...)。
+fn route_cost(
+    start_node: usize,
+    order: &[usize],
+    pickup_nodes: &[usize],
+    delivery_nodes: &[usize],
+    nodes: &[Node],
+    cache: &mut ShortestPathCache,
+) -> usize {
+    let mut cost = 0;
+    let mut cur = start_node;
+    for &task_idx in order {
+        let p = pickup_nodes[task_idx];
+        let d = delivery_nodes[task_idx];
+        cost += cache.path(cur, p, nodes).len().saturating_sub(1);
+        cost += cache.path(p, d, nodes).len().saturating_sub(1);
+        cur = d;
+    }
+    cost
+}
+
+fn makespan(
+    assignment: &[Vec<usize>],
+    start_nodes: &[usize],
+    pickup_nodes: &[usize],
+    delivery_nodes: &[usize],
+    nodes: &[Node],
+    cache: &mut ShortestPathCache,
+) -> usize {
+    (0..assignment.len())
+        .map(|i| route_cost(start_nodes[i], &assignment[i], pickup_nodes, delivery_nodes, nodes, cache))
+        .max()
+        .unwrap_or(0)
+}
+
+/// 現在の割り当てから、3種の近傍操作のいずれかをランダムに適用した候補解を作る:
+/// (1) 1タスクを別のエージェントのシーケンス中のランダムな位置へ移動
+/// (2) 2つのエージェント間でタスクを1つずつ交換
+/// (3) 1エージェントのシーケンス内の区間を2-optで反転
+fn sa_neighbor(assignment: &[Vec<usize>], rng: &mut impl Rng) -> Vec<Vec<usize>> {
+    let num_agents = assignment.len();
+    let mut candidate = assignment.to_vec();
+    if num_agents < 2 {
+        // エージェントが1体しかいない場合は2-opt-reverseのみ有効
+        if let Some(seq) = candidate.first_mut() {
+            two_opt_reverse(seq, rng);
+        }
+        return candidate;
+    }
+
+    match rng.gen_range(0..3) {
+        0 => {
+            // タスクを別エージェントへ移動
+            let non_empty: Vec<usize> = (0..num_agents).filter(|&a| !candidate[a].is_empty()).collect();
+            if let Some(&from) = non_empty.get(rng.gen_range(0..non_empty.len().max(1))) {
+                let to = rng.gen_range(0..num_agents);
+                let task_pos = rng.gen_range(0..candidate[from].len());
+                let task_idx = candidate[from].remove(task_pos);
+                let insert_pos = rng.gen_range(0..=candidate[to].len());
+                candidate[to].insert(insert_pos, task_idx);
+            }
+        }
+        1 => {
+            // 2エージェント間でタスクを1つずつ交換
+            let non_empty: Vec<usize> = (0..num_agents).filter(|&a| !candidate[a].is_empty()).collect();
+            if non_empty.len() >= 2 {
+                let a = non_empty[rng.gen_range(0..non_empty.len())];
+                let b = non_empty[rng.gen_range(0..non_empty.len())];
+                if a != b {
+                    let pa = rng.gen_range(0..candidate[a].len());
+                    let pb = rng.gen_range(0..candidate[b].len());
+                    let tmp = candidate[a][pa];
+                    candidate[a][pa] = candidate[b][pb];
+                    candidate[b][pb] = tmp;
+                }
+            }
+        }
+        _ => {
+            let a = rng.gen_range(0..num_agents);
+            two_opt_reverse(&mut candidate[a], rng);
+        }
+    }
+    candidate
+}
+
+fn two_opt_reverse(seq: &mut [usize], rng: &mut impl Rng) {
+    if seq.len() < 2 {
+        return;
+    }
+    let i = rng.gen_range(0..seq.len());
+    let j = rng.gen_range(0..seq.len());
+    let (lo, hi) = if i < j { (i, j) } else { (j, i) };
+    seq[lo..=hi].reverse();
+}
+
+/// `tswap_mapd`系のオンライン貪欲割り当て(アイドルになった順に最も近いタスクを取る)に代えて、
+/// 全タスク・全エージェントを俯瞰したオフライン計画を焼きなまし法(simulated annealing)で探索する。
+/// 解は「エージェントごとのタスク実行順」(`Vec<Vec<usize>>`)で表現し、コストはメイクスパン
+/// (各エージェントの総走行距離の最大値)。近傍操作で改悪も`exp(-Δ/T)`の確率で受理しつつ`T`を
+/// 幾何冷却し、見つかった最良解を保持する。得られた割り当て順序は最終的に既存のTSWAP実行
+/// (衝突解決込みの`tswap_step`)へそのまま渡すため、衝突回避の質は変えずにタスク分配だけを
+/// 改善できる。
+pub fn tswap_mapd_sa(
+    grid: &[Vec<char>],
+    initial_positions: Vec<Point>,
+    tasks: &[Task],
+    config: &PlannerConfig,
+) -> Vec<Vec<(Point, AgentState)>> {
+    let (nodes, pos2id, id2pos) = build_graph(grid);
+    let mut cache = ShortestPathCache::new();
+
+    let num_agents = initial_positions.len();
+    let num_tasks = tasks.len();
+
+    let start_nodes: Vec<usize> = initial_positions.iter().map(|p| pos2id[p]).collect();
+    let pickup_nodes: Vec<usize> = tasks.iter().map(|t| pos2id[&t.pickup]).collect();
+    let delivery_nodes: Vec<usize> = tasks.iter().map(|t| pos2id[&t.delivery]).collect();
+
+    // 初期解: タスクをエージェントへラウンドロビンで割り当てる
+    let mut assignment: Vec<Vec<usize>> = vec![vec![]; num_agents.max(1)];
+    for task_idx in 0..num_tasks {
+        assignment[task_idx % num_agents.max(1)].push(task_idx);
+    }
+
+    if num_agents > 0 && num_tasks > 1 {
+        let mut rng = rand::thread_rng();
+        let mut best = assignment.clone();
+        let mut best_cost = makespan(&assignment, &start_nodes, &pickup_nodes, &delivery_nodes, &nodes, &mut cache);
+        let mut current_cost = best_cost;
+
+        const ITERATIONS: usize = 2000;
+        const COOLING_RATE: f64 = 0.995;
+        let mut temp = (best_cost as f64).max(1.0);
+
+        for _ in 0..ITERATIONS {
+            let candidate = sa_neighbor(&assignment, &mut rng);
+            let candidate_cost = makespan(&candidate, &start_nodes, &pickup_nodes, &delivery_nodes, &nodes, &mut cache);
+            let delta = candidate_cost as f64 - current_cost as f64;
+            if delta <= 0.0 || rng.gen::<f64>() < (-delta / temp).exp() {
+                assignment = candidate;
+                current_cost = candidate_cost;
+                if current_cost < best_cost {
+                    best_cost = current_cost;
+                    best = assignment.clone();
+                }
+            }
+            temp *= COOLING_RATE;
+        }
+        assignment = best;
+    }
+
+    // --- SAで求めた割り当て順をTSWAPの状態機械に渡して実行(衝突解決はtswap_stepに任せる) ---
+    #[derive(Clone, Copy)]
+    enum EndpointKind {
+        Pickup,
+        Delivery,
+    }
+
+    let mut agents: Vec<Agent> = (0..num_agents)
+        .map(|i| Agent {
+            id: i,
+            v: start_nodes[i],
+            g: start_nodes[i],
+        })
+        .collect();
+
+    let mut agent_plan: Vec<Vec<(usize, EndpointKind)>> = assignment
+        .iter()
+        .map(|order| {
+            order
+                .iter()
+                .flat_map(|&task_idx| {
+                    [
+                        (pickup_nodes[task_idx], EndpointKind::Pickup),
+                        (delivery_nodes[task_idx], EndpointKind::Delivery),
+                    ]
+                })
+                .collect()
+        })
+        .collect();
+
+    for i in 0..num_agents {
+        if let Some(&(first_node, _)) = agent_plan[i].first() {
+            agents[i].g = first_node;
+        }
+    }
+
+    let mut paths: Vec<Vec<(Point, AgentState)>> = vec![vec![]; num_agents];
+    let mut timestep = 0;
+    loop {
+        for i in 0..num_agents {
+            if agents[i].v == agents[i].g && !agent_plan[i].is_empty() {
+                agent_plan[i].remove(0);
+                if let Some(&(next_node, _)) = agent_plan[i].first() {
+                    agents[i].g = next_node;
+                }
+            }
+        }
+
+        tswap_step(&mut agents, &nodes, config, &mut cache);
+
+        for i in 0..num_agents {
+            let pos = id2pos[agents[i].v];
+            let state = match agent_plan[i].first() {
+                None => AgentState::IDLE,
+                Some(&(_, EndpointKind::Pickup)) => AgentState::PICKING,
+                Some(&(_, EndpointKind::Delivery)) => {
+                    if agents[i].v == agents[i].g {
+                        AgentState::DELIVERED
+                    } else {
+                        AgentState::CARRYING
+                    }
+                }
+            };
+            paths[i].push((pos, state));
+        }
+
+        timestep += 1;
+
+        let all_agents_idle = agent_plan.iter().all(|p| p.is_empty());
+        if all_agents_idle || timestep > 2000 {
+            break;
+        }
+    }
+
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_karp_bundle_order_respects_pickup_before_delivery() {
+        // 1行6マスの直線グリッド: (0,0)..(5,0)
+        let grid: Vec<Vec<char>> = vec![vec!['.'; 6]];
+        let (nodes, pos2id, _id2pos) = build_graph(&grid);
+        let mut cache = ShortestPathCache::new();
+
+        let start = pos2id[&(0, 0)];
+        // pickup0は遠い(x=4)がdelivery0は近い(x=1)ので、距離だけの貪欲な巡回順だと
+        // pickup0より先にdelivery0へ寄りたくなる。前後関係制約がなければ破綻するケース
+        let pickup0 = pos2id[&(4, 0)];
+        let delivery0 = pos2id[&(1, 0)];
+        let pickup1 = pos2id[&(2, 0)];
+        let delivery1 = pos2id[&(5, 0)];
+
+        let endpoints = vec![pickup0, delivery0, pickup1, delivery1];
+        let order = held_karp_bundle_order(start, &endpoints, &nodes, &mut cache);
+
+        let position_of = |endpoint_index: usize| {
+            order
+                .iter()
+                .position(|&idx| idx == endpoint_index)
+                .expect("endpoint index must appear exactly once in the order")
+        };
+
+        assert!(
+            position_of(0) < position_of(1),
+            "pickup0 (index 0) must be visited before delivery0 (index 1)"
+        );
+        assert!(
+            position_of(2) < position_of(3),
+            "pickup1 (index 2) must be visited before delivery1 (index 3)"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use async_trait::async_trait;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use std::collections::{BinaryHeap, HashMap, HashSet};
@@ -8,19 +9,31 @@ use std::{
     time::Duration,
 };
 
+use futures::prelude::*;
 use futures::stream::StreamExt;
 use libp2p::{
-    gossipsub, mdns, noise,
+    StreamProtocol, gossipsub, mdns, noise,
+    request_response::{self, ProtocolSupport},
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux,
 };
+use p2p_distributed_tswap::map::bloom_filter::BloomFilter;
 use p2p_distributed_tswap::map::make_node;
 use p2p_distributed_tswap::map::map::MAP;
 use p2p_distributed_tswap::map::map::Point;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::Registry;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::io::Error as IoError;
 use std::sync::Arc;
-use tokio::{io, io::AsyncBufReadExt, select};
+use tokio::{
+    io,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    select,
+};
 
 fn parse_map() -> Vec<Vec<char>> {
     let grid = MAP
@@ -77,6 +90,264 @@ struct TargetRotationRequest {
     goals: Vec<Point>,         // 各エージェントの現在のゴール
 }
 
+// タスクスワップリクエスト: pickup/deliveryに既に他エージェントがいる場合、
+// 自分のタスクと相手のタスクを直接交換する
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TaskSwapRequest {
+    from_peer: String,
+    to_peer: String,
+    task: p2p_distributed_tswap::map::task_generator::Task,
+}
+
+// タスクスワップレスポンス: 相手が自分のタスクを返す。acceptedがfalseの場合、
+// taskには拒否した側に送り返す相手自身のタスクがそのまま入る(=ノーオペ)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TaskSwapResponse {
+    from_peer: String,
+    to_peer: String,
+    accepted: bool,
+    task: p2p_distributed_tswap::map::task_generator::Task,
+}
+
+// p2pandaのreplication session-managerに倣ったフルステート収束セッション。joinしたばかりの
+// ピアとの最初の接触時に一度だけ、自分が知っている占有セルと各ピアの最新versionの要約を送り、
+// 相手はその要約と自分の状態を突き合わせて「要約側が持っていない/古い」エントリだけを返す。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReplicationSummary {
+    occupied: Vec<Point>,
+    // peer_id -> 知っている中で最新のtimestamp
+    versions: Vec<(String, u64)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SyncRequest {
+    session_id: u64,
+    summary: ReplicationSummary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SyncResponse {
+    session_id: u64,
+    occupied: Vec<Point>,
+    missing: Vec<AgentInfo>,
+}
+
+// エージェント間のゴール交換・タスクスワップ・ローテーション調整はgossipsubの全員ブロードキャストをやめ、
+// 1対1のrequest-responseで直接やり取りする（相手からの明示的なaccepted/rejectedを受け取れる）。
+// fuel-core-p2pのRequestMessage/ResponseMessageと同じ発想で、ResponseChannelがOutboundRequestIdに
+// 紐付くため、参加者数に応じたO(n)のブロードキャスト増幅やtopic全体の不要なデシリアライズが発生しない。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum TswapAgentRequest {
+    GoalSwap(GoalSwapRequest),
+    Rotation(TargetRotationRequest),
+    // CRDSスタイルのpullアンチエントロピー: 自分が持っているエントリのBloomフィルタを送り、
+    // 相手に「自分が持っていなさそうなもの」だけを返してもらう
+    CrdsPull(CrdsFilter),
+    TaskSwap(TaskSwapRequest),
+    // joinしたばかりのピアをフルステートへ収束させるセッション開始
+    Sync(SyncRequest),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum TswapAgentResponse {
+    GoalSwap(GoalSwapResponse),
+    RotationAck,
+    CrdsPush(Vec<AgentInfo>),
+    TaskSwap(TaskSwapResponse),
+    Sync(SyncResponse),
+}
+
+// request_response::Codec実装: JSON行をそのままストリームに流す
+#[derive(Clone, Default)]
+struct TswapAgentCodec;
+
+#[async_trait]
+impl request_response::Codec for TswapAgentCodec {
+    type Protocol = StreamProtocol;
+    type Request = TswapAgentRequest;
+    type Response = TswapAgentResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+}
+
+// manager.rs(src/bin/manager.rs)のKademlia市場とやり取りするためのrequest-response。
+// manager.rsとは別バイナリなので型そのものは共有できないが、`/mapd/tswap/1.0.0`という
+// 同じプロトコル文字列・同じvariant名のJSONで話すことで相互に読み書きできる
+// (manager.rs側の`TswapRequest`/`TswapResponse`と1対1で対応させること)。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ManagerTswapRequest {
+    ClaimTask(u64),
+    QueryOccupied,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ManagerTswapResponse {
+    ClaimResult {
+        task_id: u64,
+        task: Option<p2p_distributed_tswap::map::task_generator::Task>,
+    },
+    OccupiedSet(Vec<(usize, usize)>),
+}
+
+#[derive(Clone, Default)]
+struct ManagerTswapCodec;
+
+#[async_trait]
+impl request_response::Codec for ManagerTswapCodec {
+    type Protocol = StreamProtocol;
+    type Request = ManagerTswapRequest;
+    type Response = ManagerTswapResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+}
+
+// CRDS(Solana cluster_info)方式のpullアンチエントロピー用Bloomフィルタ。
+// 保持しているエントリを(peer_id, version)ペアとしてキー化し、汎用実装の`map::bloom_filter::BloomFilter`
+// に委譲する薄いラッパー(以前はダブルハッシュ法の専用実装を持っていたが、ロジックが重複していたため一本化した)。
+const CRDS_FILTER_NUM_HASHES: usize = 3;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CrdsFilter(BloomFilter);
+
+impl CrdsFilter {
+    fn new(num_bits: usize) -> Self {
+        CrdsFilter(BloomFilter::new(num_bits, CRDS_FILTER_NUM_HASHES))
+    }
+
+    fn insert(&mut self, peer_id: &str, version: u64) {
+        self.0.insert(&format!("{}:{}", peer_id, version));
+    }
+
+    fn might_contain(&self, peer_id: &str, version: u64) -> bool {
+        self.0.might_contain(&format!("{}:{}", peer_id, version))
+    }
+}
+
+// gossipsubはメッシュの構成によって同じメッセージを複数経路から再配送してくることがある。
+// 直近見たmessage_idを固定サイズのリングバッファ+HashSetで覚えておき、二重配送はIgnore報告して
+// 早期にスキップする（fuel-core-p2pのMessageAcceptance運用にならい、Reject/Ignoreを使い分ける）。
+struct SeenMessageIds {
+    order: std::collections::VecDeque<gossipsub::MessageId>,
+    seen: HashSet<gossipsub::MessageId>,
+    capacity: usize,
+}
+
+impl SeenMessageIds {
+    fn new(capacity: usize) -> Self {
+        SeenMessageIds {
+            order: std::collections::VecDeque::new(),
+            seen: HashSet::new(),
+            capacity,
+        }
+    }
+
+    /// 初見ならtrueを返して記録し、既知ならfalseを返す。
+    fn record_if_new(&mut self, id: &gossipsub::MessageId) -> bool {
+        if !self.seen.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id.clone());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
 // 近くのエージェントを管理
 struct NearbyAgents {
     agents: HashMap<String, AgentInfo>,
@@ -91,8 +362,41 @@ impl NearbyAgents {
         }
     }
 
-    fn update(&mut self, info: AgentInfo) {
-        self.agents.insert(info.peer_id.clone(), info);
+    /// LWW(last-writer-wins)でマージする: `timestamp`をバージョンとして扱い、
+    /// 既存より新しい場合のみ採用する。古い/順序が入れ替わったゴシップは静かに捨てる。
+    fn update(&mut self, info: AgentInfo) -> bool {
+        match self.agents.get(&info.peer_id) {
+            Some(existing) if existing.timestamp >= info.timestamp => false,
+            _ => {
+                self.agents.insert(info.peer_id.clone(), info);
+                true
+            }
+        }
+    }
+
+    /// 自分が持っているエントリを表すBloomフィルタを構築する（pullリクエストに添える）
+    fn build_filter(&self) -> CrdsFilter {
+        let num_bits = (self.agents.len().max(1) * 16).next_power_of_two();
+        let mut filter = CrdsFilter::new(num_bits);
+        for info in self.agents.values() {
+            filter.insert(&info.peer_id, info.timestamp);
+        }
+        filter
+    }
+
+    /// 相手のフィルタに含まれていない(=相手が持っていなさそうな)エントリだけを返す。
+    /// 死んだエージェントが復活しないよう、`max_age_secs`より古いエントリは事前に除外する。
+    fn entries_missing_from(&self, filter: &CrdsFilter, max_age_secs: u64) -> Vec<AgentInfo> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.agents
+            .values()
+            .filter(|info| now.saturating_sub(info.timestamp) < max_age_secs)
+            .filter(|info| !filter.might_contain(&info.peer_id, info.timestamp))
+            .cloned()
+            .collect()
     }
 
     fn get_nearby(&self, my_pos: Point, radius: usize, my_peer_id: &str) -> Vec<AgentInfo> {
@@ -145,14 +449,206 @@ impl NearbyAgents {
             .retain(|_, agent| now - agent.timestamp < max_age_secs);
         self.last_cleanup = std::time::Instant::now();
     }
+
+    /// セッション収束用の要約を作る: 現在地の集合と、ピアごとに知っている最新versionのリスト
+    fn build_replication_summary(&self) -> ReplicationSummary {
+        ReplicationSummary {
+            occupied: self.agents.values().map(|a| a.current_pos).collect(),
+            versions: self
+                .agents
+                .values()
+                .map(|a| (a.peer_id.clone(), a.timestamp))
+                .collect(),
+        }
+    }
+
+    /// 相手から届いた要約のversionと正確に突き合わせ、相手が持っていない/古いエントリだけを返す。
+    /// CrdsFilterのBloomと違い誤検出がないので、join直後の一度きりの収束に向く。
+    fn entries_missing_from_summary(&self, summary: &ReplicationSummary) -> Vec<AgentInfo> {
+        let known: HashMap<&str, u64> = summary
+            .versions
+            .iter()
+            .map(|(peer_id, ts)| (peer_id.as_str(), *ts))
+            .collect();
+        self.agents
+            .values()
+            .filter(|info| known.get(info.peer_id.as_str()).is_none_or(|&ts| ts < info.timestamp))
+            .cloned()
+            .collect()
+    }
+}
+
+/// p2pandaのセッションマネージャーに倣い、進行中のreplicationセッションをsession_idで追跡する。
+/// 主にログ/デバッグ用の紐付けで、実際のレスポンス相関はrequest_responseのOutboundRequestIdが担う。
+struct ReplicationSessionManager {
+    next_session_id: u64,
+    open_sessions: HashMap<u64, libp2p::PeerId>,
+}
+
+impl ReplicationSessionManager {
+    fn new() -> Self {
+        Self {
+            next_session_id: 0,
+            open_sessions: HashMap::new(),
+        }
+    }
+
+    fn open(&mut self, peer: libp2p::PeerId) -> u64 {
+        self.next_session_id += 1;
+        let session_id = self.next_session_id;
+        self.open_sessions.insert(session_id, peer);
+        session_id
+    }
+
+    fn close(&mut self, session_id: u64) -> Option<libp2p::PeerId> {
+        self.open_sessions.remove(&session_id)
+    }
 }
 
 #[derive(NetworkBehaviour)]
 struct MapdBehaviour {
     gossipsub: gossipsub::Behaviour,
     mdns: mdns::tokio::Behaviour,
+    request_response: request_response::Behaviour<TswapAgentCodec>,
+    /// manager.rsのKademlia市場(`ClaimTask`/`QueryOccupied`)と話すための別チャンネル。
+    /// エージェント同士の`request_response`(`/mapd/agent-tswap/1.0.0`)とはプロトコル文字列が異なる。
+    manager: request_response::Behaviour<ManagerTswapCodec>,
+}
+
+/// fuel-core-p2pの`P2P_METRICS`やこのリポジトリの`ManagerMetrics`と同様に、prometheus-client で
+/// TSWAP交渉/衝突待ち/タスク完了レイテンシを計測し、小さなHTTPエンドポイント（`/metrics`）で公開する。
+struct AgentMetrics {
+    registry: Arc<Registry>,
+    collision_waits: Counter,
+    goal_swaps_sent: Counter,
+    goal_swaps_accepted: Counter,
+    goal_swaps_rejected: Counter,
+    rotations_sent: Counter,
+    gossipsub_published: Counter,
+    gossipsub_received: Counter,
+    task_steps: Histogram,
+    task_latency_ms: Histogram,
+}
+
+impl AgentMetrics {
+    fn new() -> Self {
+        let mut registry = Registry::default();
+
+        let collision_waits = Counter::default();
+        registry.register(
+            "agent_collision_waits",
+            "Total TswapAction::Wait collision-avoidance stalls",
+            collision_waits.clone(),
+        );
+        let goal_swaps_sent = Counter::default();
+        registry.register(
+            "agent_goal_swaps_sent",
+            "Total goal-swap requests sent to a blocking peer",
+            goal_swaps_sent.clone(),
+        );
+        let goal_swaps_accepted = Counter::default();
+        registry.register(
+            "agent_goal_swaps_accepted",
+            "Total goal-swap requests accepted by the counterparty",
+            goal_swaps_accepted.clone(),
+        );
+        let goal_swaps_rejected = Counter::default();
+        registry.register(
+            "agent_goal_swaps_rejected",
+            "Total goal-swap requests rejected by the counterparty",
+            goal_swaps_rejected.clone(),
+        );
+        let rotations_sent = Counter::default();
+        registry.register(
+            "agent_rotations_sent",
+            "Total target-rotation requests initiated to break a deadlock cycle",
+            rotations_sent.clone(),
+        );
+        let gossipsub_published = Counter::default();
+        registry.register(
+            "agent_gossipsub_published",
+            "Total messages published on the mapd gossipsub topic",
+            gossipsub_published.clone(),
+        );
+        let gossipsub_received = Counter::default();
+        registry.register(
+            "agent_gossipsub_received",
+            "Total messages accepted after validation on the mapd gossipsub topic",
+            gossipsub_received.clone(),
+        );
+        let task_steps = Histogram::new([1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0].into_iter());
+        registry.register(
+            "agent_task_steps",
+            "Number of movement ticks spent per task from pickup to delivery",
+            task_steps.clone(),
+        );
+        let task_latency_ms = Histogram::new(
+            [50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0].into_iter(),
+        );
+        registry.register(
+            "agent_task_latency_ms",
+            "Wall-clock latency from task start to TASK COMPLETE, in milliseconds",
+            task_latency_ms.clone(),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            collision_waits,
+            goal_swaps_sent,
+            goal_swaps_accepted,
+            goal_swaps_rejected,
+            rotations_sent,
+            gossipsub_published,
+            gossipsub_received,
+            task_steps,
+            task_latency_ms,
+        }
+    }
+}
+
+/// `/metrics`だけを返す最小限のHTTPエンドポイント。`0`番ポートでOSにポートを割り振らせることで、
+/// 同一ホストで複数エージェントを起動してもポート競合が起きない。
+async fn serve_metrics(registry: Arc<Registry>) {
+    let listener = match tokio::net::TcpListener::bind("127.0.0.1:0").await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("⚠️  Failed to bind metrics endpoint: {e}");
+            return;
+        }
+    };
+    match listener.local_addr() {
+        Ok(addr) => println!("📊 Metrics available at http://{addr}/metrics"),
+        Err(e) => println!("⚠️  Failed to read metrics endpoint address: {e}"),
+    }
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            // リクエストの中身は問わない。このエンドポイントはmetricsしか返さない。
+            let _ = stream.read(&mut buf).await;
+
+            let mut body = String::new();
+            let _ = encode(&mut body, &registry);
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
 }
 
+// 1回あたりのA*ノード展開数の上限。密なマップでも1ステップの計画がswarmのポーリングを
+// 飢えさせないよう、展開しきれなかった場合はヒューリスティックで最良の近傍へ進む。
+const ASTAR_EXPANSION_BUDGET: usize = 2000;
+
 fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
     if start == goal {
         return vec![start];
@@ -206,6 +702,7 @@ fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
     };
     open_list.push(start_node);
 
+    let mut expansions = 0usize;
     while let Some(current) = open_list.pop() {
         let current_id = current.node_id;
 
@@ -222,6 +719,12 @@ fn get_path(start: usize, goal: usize, nodes: &[Node]) -> Vec<usize> {
             return path;
         }
 
+        expansions += 1;
+        if expansions > ASTAR_EXPANSION_BUDGET {
+            // 展開数の上限に達した: startの隣接ノードのうちヒューリスティックが最小のものへ一歩だけ進む経路を返す
+            break;
+        }
+
         for &neighbor_id in &nodes[current_id].neighbors {
             let tentative_g = current.g_cost + 1;
 
@@ -261,6 +764,172 @@ fn manhattan_distance(p1: Point, p2: Point) -> usize {
     ((p1.0 as isize - p2.0 as isize).abs() + (p1.1 as isize - p2.1 as isize).abs()) as usize
 }
 
+/// gossipsub publish用の優先度つき送信スケジューラ（chunk0のOutboundDispatcherと同じ発想）。
+/// swap_request/swap_response/完了通知は`Control`として即座に送出し取りこぼさない。
+/// 500ms周期の位置/ゴールブロードキャストは`Telemetry`として扱い、送信が詰まって複数件
+/// 溜まった場合はトピックごとに最新の1件だけを残して間引く。Control はTelemetryの背後に
+/// 並ばないよう、drain時は常にControlを先に吐き出す。
+struct OutboundDispatcher {
+    control_backlog: std::collections::VecDeque<Vec<u8>>,
+    telemetry_pending: Option<Vec<u8>>,
+    telemetry_coalesced: u64,
+    telemetry_dropped: u64,
+}
+
+impl OutboundDispatcher {
+    fn new() -> Self {
+        Self {
+            control_backlog: std::collections::VecDeque::new(),
+            telemetry_pending: None,
+            telemetry_coalesced: 0,
+            telemetry_dropped: 0,
+        }
+    }
+
+    fn enqueue_control(&mut self, data: Vec<u8>) {
+        self.control_backlog.push_back(data);
+    }
+
+    /// すでに送信待ちのテレメトリがあれば、それは送られないまま新しい方に置き換えられる
+    /// (=coalesceされる)。トピックごとに最新の状態だけ届けば十分なので問題ない。
+    fn enqueue_telemetry(&mut self, data: Vec<u8>) {
+        if self.telemetry_pending.replace(data).is_some() {
+            self.telemetry_coalesced += 1;
+        }
+    }
+
+    /// Controlを全て送出してから、最新のTelemetryを高々1件送る。
+    fn drain(&mut self, swarm: &mut libp2p::Swarm<MapdBehaviour>, topic: &gossipsub::IdentTopic, metrics: &AgentMetrics) {
+        while let Some(data) = self.control_backlog.pop_front() {
+            match swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                Ok(_) => metrics.gossipsub_published.inc(),
+                Err(e) => {
+                    let err_str = format!("{:?}", e);
+                    if !err_str.contains("NoPeers") {
+                        println!("⚠️  Failed to publish control message: {e:?}");
+                    }
+                }
+            }
+        }
+        if let Some(data) = self.telemetry_pending.take() {
+            match swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                Ok(_) => metrics.gossipsub_published.inc(),
+                Err(e) => {
+                    let err_str = format!("{:?}", e);
+                    if err_str.contains("NoPeers") {
+                        println!("⏳ [BROADCAST] Waiting for peers to subscribe...");
+                    } else {
+                        self.telemetry_dropped += 1;
+                        println!(
+                            "⚠️  Failed to broadcast telemetry ({} dropped so far): {e:?}",
+                            self.telemetry_dropped
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// オペレータ向け: これまでにcoalesce/dropされたtelemetryの件数（累計）
+    fn telemetry_stats(&self) -> (u64, u64) {
+        (self.telemetry_coalesced, self.telemetry_dropped)
+    }
+}
+
+/// pickup/deliveryに既に他エージェントがいる場合に、その場でタスクを直接交換する。
+/// 相手が拒否/タイムアウトした場合はNoneを返し、呼び出し側は元のタスクのまま移動を続けられる。
+async fn negotiate_task_swap(
+    swarm: &mut libp2p::Swarm<MapdBehaviour>,
+    to_peer: &str,
+    my_task: p2p_distributed_tswap::map::task_generator::Task,
+    local_peer_id_str: &str,
+) -> Option<p2p_distributed_tswap::map::task_generator::Task> {
+    let Ok(peer) = to_peer.parse::<libp2p::PeerId>() else {
+        println!("[SWAP] Invalid peer id for task swap: {}", to_peer);
+        return None;
+    };
+    let outbound_id = swarm.behaviour_mut().request_response.send_request(
+        &peer,
+        TswapAgentRequest::TaskSwap(TaskSwapRequest {
+            from_peer: local_peer_id_str.to_string(),
+            to_peer: to_peer.to_string(),
+            task: my_task,
+        }),
+    );
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while std::time::Instant::now() < deadline {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        match tokio::time::timeout(remaining, swarm.select_next_some()).await {
+            Ok(SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                request_response::Event::Message {
+                    message: request_response::Message::Response { request_id: resp_id, response },
+                    ..
+                },
+            ))) if resp_id == outbound_id => {
+                return match response {
+                    TswapAgentResponse::TaskSwap(TaskSwapResponse { accepted: true, task, .. }) => {
+                        println!("[SWAP] Task swap accepted by {}", to_peer);
+                        Some(task)
+                    }
+                    _ => {
+                        println!("[SWAP] Task swap rejected by {}", to_peer);
+                        None
+                    }
+                };
+            }
+            Ok(SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                request_response::Event::OutboundFailure { request_id: failed_id, error, .. },
+            ))) if failed_id == outbound_id => {
+                println!("[SWAP] Task swap request to {} failed: {:?}", to_peer, error);
+                return None;
+            }
+            Ok(SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(list)))) => {
+                for (peer_id, _multiaddr) in list {
+                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    println!("[SWAP] Task swap request to {} timed out", to_peer);
+    None
+}
+
+/// ターゲットローテーションを各参加者に個別送信する（受領確認は通常どおりイベントループ側で処理される）。
+/// 各参加者へローテーション要求を送り、返ってきたoutbound_idを集めて返す。
+/// 呼び出し側はこのidの集合が空になった時点（全員のRotationAck到達）で即座に待機を解除できる。
+fn send_rotation_requests(
+    swarm: &mut libp2p::Swarm<MapdBehaviour>,
+    request_id: String,
+    initiator: String,
+    participants: Vec<String>,
+    goals: Vec<Point>,
+) -> HashSet<request_response::OutboundRequestId> {
+    let mut pending_acks = HashSet::new();
+    for participant in &participants {
+        if participant == &initiator {
+            continue;
+        }
+        let Ok(peer) = participant.parse::<libp2p::PeerId>() else {
+            println!("[TSWAP] Invalid peer id for rotation participant: {}", participant);
+            continue;
+        };
+        let outbound_id = swarm.behaviour_mut().request_response.send_request(
+            &peer,
+            TswapAgentRequest::Rotation(TargetRotationRequest {
+                request_id: request_id.clone(),
+                initiator: initiator.clone(),
+                participants: participants.clone(),
+                goals: goals.clone(),
+            }),
+        );
+        pending_acks.insert(outbound_id);
+    }
+    pending_acks
+}
+
 // TSWAPベースの次の移動先を計算
 // TSWAPの判定結果
 #[derive(Debug, Clone)]
@@ -271,6 +940,71 @@ enum TswapAction {
     Wait,                                     // 単純待機
 }
 
+// ゴール交換/ローテーションの応答が返らないまま相手が切断・無応答になった場合に
+// 永久に待機し続けないための再送上限。Garageのタスクマネージャ(Active/Idle/Deadで
+// 明示管理し、一覧・キャンセルできるワーカー)にならい、超過後はWaitへフォールバックして
+// 次tickの`compute_next_move_with_tswap`に迂回先を計算させる。
+const PENDING_REQUEST_MAX_RETRIES: u32 = 2;
+
+// エージェントの移動を「`while`ループでswarmのポーリングを止める」のではなく、
+// 明示的な状態機械として持ち、tickごとに1手だけ進める（Substrateのpoll()→next_action()と同じ発想）。
+// これにより移動中もgossipsub/mDNS/request-responseイベントを処理し続けられる。
+// Phase 1(pickup)とPhase 2(delivery)は同じ`step_toward`を`to_pickup`フラグで呼び分けるだけなので、
+// 本体を二重に書かずに済む。ローテーション/ゴール交換リクエストの受信側処理も`agent_state`に関わらず
+// main loopの`select!`内で独立に処理されるため、自分が移動中でも相手からの要求に即応できる。
+#[derive(Debug, Clone)]
+enum AgentState {
+    Idle,
+    ToPickup(p2p_distributed_tswap::map::task_generator::Task),
+    ToDelivery(p2p_distributed_tswap::map::task_generator::Task),
+    WaitingGoalSwap {
+        task: p2p_distributed_tswap::map::task_generator::Task,
+        to_pickup: bool,
+        peer_id: String,
+        outbound_id: request_response::OutboundRequestId,
+        started: std::time::Instant,
+        deadline: std::time::Instant,
+        attempt: u32,
+    },
+    WaitingRotation {
+        task: p2p_distributed_tswap::map::task_generator::Task,
+        to_pickup: bool,
+        // ここが空になった時点(=全参加者からRotationAckが届いた時点)で即座に待機を解除する
+        pending_acks: HashSet<request_response::OutboundRequestId>,
+        participants: Vec<String>,
+        goals: Vec<Point>,
+        started: std::time::Instant,
+        deadline: std::time::Instant,
+        attempt: u32,
+    },
+}
+
+impl AgentState {
+    /// オペレータが詰まった交渉を調べられるように、待機中リクエストの種別・経過時間・再送回数を返す。
+    fn pending_request_age(&self) -> Option<(&'static str, Duration, u32)> {
+        match self {
+            AgentState::WaitingGoalSwap { started, attempt, .. } => {
+                Some(("goal_swap", started.elapsed(), *attempt))
+            }
+            AgentState::WaitingRotation { started, attempt, .. } => {
+                Some(("rotation", started.elapsed(), *attempt))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `task_available`ヒントを受けてmanager.rsへClaimTaskを送るべきかどうかの純粋な判定。
+/// 既に運搬中(`my_task`が`Some`、または`agent_state`が`Idle`でない)なら横取りせず、
+/// 自分が出したClaimTaskの返事(`ClaimResult`)を待っている間(`claim_pending`)も二重送信しない。
+fn should_claim_task(
+    my_task: &Option<p2p_distributed_tswap::map::task_generator::Task>,
+    agent_state: &AgentState,
+    claim_pending: bool,
+) -> bool {
+    my_task.is_none() && matches!(agent_state, AgentState::Idle) && !claim_pending
+}
+
 fn compute_next_move_with_tswap(
     my_pos: Point,
     my_goal: Point,
@@ -387,6 +1121,338 @@ fn compute_next_move_with_tswap(
     TswapAction::Move(next_pos)
 }
 
+/// `AgentState`を1手だけ進める。pickup/delivery到達時は次フェーズへ遷移し、
+/// ゴール交換/ローテーション待ちはノンブロッキングでリクエストを投げてから待機状態に入る。
+#[allow(clippy::too_many_arguments)]
+fn step_toward(
+    task: p2p_distributed_tswap::map::task_generator::Task,
+    to_pickup: bool,
+    swarm: &mut libp2p::Swarm<MapdBehaviour>,
+    my_point: &mut Option<Point>,
+    my_goal: &mut Point,
+    dispatcher: &mut OutboundDispatcher,
+    nearby_agents: &NearbyAgents,
+    grid: &[Vec<char>],
+    pos2id: &HashMap<Point, usize>,
+    tswap_nodes: &[Node],
+    local_peer_id_str: &str,
+    metrics: &AgentMetrics,
+) -> AgentState {
+    let target = if to_pickup { task.pickup } else { task.delivery };
+    let Some(current_pos) = *my_point else {
+        println!(
+            "❌ [ERROR] my_point is None, cannot advance task id={:?}",
+            task.task_id
+        );
+        return AgentState::Idle;
+    };
+
+    if current_pos == target {
+        if to_pickup {
+            println!("✅ [PHASE 1 COMPLETE] Reached PICKUP at {:?}", target);
+            *my_goal = task.delivery;
+            return AgentState::ToDelivery(task);
+        }
+        println!("✅ [PHASE 2 COMPLETE] Reached DELIVERY at {:?}", target);
+        let done_json = if let Some(task_id) = task.task_id {
+            serde_json::json!({"status": "done", "task_id": task_id}).to_string()
+        } else {
+            serde_json::json!({"status": "done"}).to_string()
+        };
+        dispatcher.enqueue_control(done_json.into_bytes());
+        println!(
+            "🎉 [TASK COMPLETE] Task ID {:?} finished! Notification queued for manager",
+            task.task_id
+        );
+        return AgentState::Idle;
+    }
+
+    *my_goal = target;
+    let nearby = nearby_agents.get_nearby(current_pos, 15, local_peer_id_str);
+    println!(
+        "  📍 Current: {:?} -> {:?} (Nearby: {})",
+        current_pos,
+        my_goal,
+        nearby.len()
+    );
+    let action = compute_next_move_with_tswap(current_pos, *my_goal, &nearby, grid, pos2id, tswap_nodes);
+
+    match action {
+        TswapAction::Move(next_pos) => {
+            if next_pos != current_pos {
+                println!("[TSWAP] Moving {:?} -> {:?}", current_pos, next_pos);
+                *my_point = Some(next_pos);
+            }
+            if to_pickup {
+                AgentState::ToPickup(task)
+            } else {
+                AgentState::ToDelivery(task)
+            }
+        }
+        TswapAction::WaitForGoalSwap(peer_id) => {
+            println!("[TSWAP] Requesting goal swap with {}", peer_id);
+            let Ok(peer) = peer_id.parse::<libp2p::PeerId>() else {
+                println!("[TSWAP] Invalid peer id for goal swap: {}", peer_id);
+                return if to_pickup {
+                    AgentState::ToPickup(task)
+                } else {
+                    AgentState::ToDelivery(task)
+                };
+            };
+            let request_id = format!(
+                "{}_{}",
+                local_peer_id_str,
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+            );
+            let outbound_id = swarm.behaviour_mut().request_response.send_request(
+                &peer,
+                TswapAgentRequest::GoalSwap(GoalSwapRequest {
+                    request_id,
+                    from_peer: local_peer_id_str.to_string(),
+                    to_peer: peer_id.clone(),
+                    my_goal: *my_goal,
+                }),
+            );
+            metrics.goal_swaps_sent.inc();
+            let now = std::time::Instant::now();
+            AgentState::WaitingGoalSwap {
+                task,
+                to_pickup,
+                peer_id,
+                outbound_id,
+                started: now,
+                deadline: now + Duration::from_secs(2),
+                attempt: 0,
+            }
+        }
+        TswapAction::WaitForRotation(participants, goals) => {
+            println!("[TSWAP] Sending target rotation request");
+            println!("[TSWAP] Participants: {:?}", participants);
+            let request_id = format!(
+                "{}_{}",
+                local_peer_id_str,
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+            );
+            let pending_acks = send_rotation_requests(
+                swarm,
+                request_id,
+                local_peer_id_str.to_string(),
+                participants.clone(),
+                goals.clone(),
+            );
+            metrics.rotations_sent.inc();
+            let now = std::time::Instant::now();
+            AgentState::WaitingRotation {
+                task,
+                to_pickup,
+                pending_acks,
+                participants,
+                goals,
+                started: now,
+                deadline: now + Duration::from_millis(500),
+                attempt: 0,
+            }
+        }
+        TswapAction::Wait => {
+            println!("[TSWAP] Waiting due to collision avoidance...");
+            metrics.collision_waits.inc();
+            if to_pickup {
+                AgentState::ToPickup(task)
+            } else {
+                AgentState::ToDelivery(task)
+            }
+        }
+    }
+}
+
+/// タイムアウトした待機状態から移動フェーズへ復帰する。
+fn resume_movement(task: p2p_distributed_tswap::map::task_generator::Task, to_pickup: bool) -> AgentState {
+    if to_pickup {
+        AgentState::ToPickup(task)
+    } else {
+        AgentState::ToDelivery(task)
+    }
+}
+
+/// `AgentState`を1tickぶん進める。`while`ループでswarmのポーリングを止めていた
+/// 旧実装と異なり、この関数は即座に戻るので呼び出し側のイベントループは毎tick必ず進行する。
+#[allow(clippy::too_many_arguments)]
+fn advance_agent_state(
+    state: AgentState,
+    swarm: &mut libp2p::Swarm<MapdBehaviour>,
+    my_point: &mut Option<Point>,
+    my_goal: &mut Point,
+    dispatcher: &mut OutboundDispatcher,
+    nearby_agents: &NearbyAgents,
+    grid: &[Vec<char>],
+    pos2id: &HashMap<Point, usize>,
+    tswap_nodes: &[Node],
+    local_peer_id_str: &str,
+    metrics: &AgentMetrics,
+) -> AgentState {
+    match state {
+        AgentState::Idle => AgentState::Idle,
+        AgentState::ToPickup(task) => step_toward(
+            task,
+            true,
+            swarm,
+            my_point,
+            my_goal,
+            dispatcher,
+            nearby_agents,
+            grid,
+            pos2id,
+            tswap_nodes,
+            local_peer_id_str,
+            metrics,
+        ),
+        AgentState::ToDelivery(task) => step_toward(
+            task,
+            false,
+            swarm,
+            my_point,
+            my_goal,
+            dispatcher,
+            nearby_agents,
+            grid,
+            pos2id,
+            tswap_nodes,
+            local_peer_id_str,
+            metrics,
+        ),
+        AgentState::WaitingGoalSwap {
+            task,
+            to_pickup,
+            peer_id,
+            outbound_id,
+            started,
+            deadline,
+            attempt,
+        } => {
+            if std::time::Instant::now() < deadline {
+                return AgentState::WaitingGoalSwap {
+                    task,
+                    to_pickup,
+                    peer_id,
+                    outbound_id,
+                    started,
+                    deadline,
+                    attempt,
+                };
+            }
+            if attempt < PENDING_REQUEST_MAX_RETRIES {
+                let Ok(peer) = peer_id.parse::<libp2p::PeerId>() else {
+                    return resume_movement(task, to_pickup);
+                };
+                println!(
+                    "[TSWAP] Goal swap with {} timed out, retrying ({}/{})",
+                    peer_id, attempt + 1, PENDING_REQUEST_MAX_RETRIES
+                );
+                let request_id = format!(
+                    "{}_{}",
+                    local_peer_id_str,
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis()
+                );
+                let outbound_id = swarm.behaviour_mut().request_response.send_request(
+                    &peer,
+                    TswapAgentRequest::GoalSwap(GoalSwapRequest {
+                        request_id,
+                        from_peer: local_peer_id_str.to_string(),
+                        to_peer: peer_id.clone(),
+                        my_goal: *my_goal,
+                    }),
+                );
+                metrics.goal_swaps_sent.inc();
+                AgentState::WaitingGoalSwap {
+                    task,
+                    to_pickup,
+                    peer_id,
+                    outbound_id,
+                    started,
+                    deadline: std::time::Instant::now() + Duration::from_secs(2),
+                    attempt: attempt + 1,
+                }
+            } else {
+                println!(
+                    "[TSWAP] Goal swap with {} exhausted {} retries, falling back to detour move",
+                    peer_id, PENDING_REQUEST_MAX_RETRIES
+                );
+                resume_movement(task, to_pickup)
+            }
+        }
+        AgentState::WaitingRotation {
+            task,
+            to_pickup,
+            pending_acks,
+            participants,
+            goals,
+            started,
+            deadline,
+            attempt,
+        } => {
+            if std::time::Instant::now() < deadline {
+                return AgentState::WaitingRotation {
+                    task,
+                    to_pickup,
+                    pending_acks,
+                    participants,
+                    goals,
+                    started,
+                    deadline,
+                    attempt,
+                };
+            }
+            if attempt < PENDING_REQUEST_MAX_RETRIES {
+                println!(
+                    "[TSWAP] Rotation timed out waiting on {} ack(s), retrying ({}/{})",
+                    pending_acks.len(), attempt + 1, PENDING_REQUEST_MAX_RETRIES
+                );
+                let request_id = format!(
+                    "{}_{}",
+                    local_peer_id_str,
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis()
+                );
+                let pending_acks = send_rotation_requests(
+                    swarm,
+                    request_id,
+                    local_peer_id_str.to_string(),
+                    participants.clone(),
+                    goals.clone(),
+                );
+                metrics.rotations_sent.inc();
+                AgentState::WaitingRotation {
+                    task,
+                    to_pickup,
+                    pending_acks,
+                    participants,
+                    goals,
+                    started,
+                    deadline: std::time::Instant::now() + Duration::from_millis(500),
+                    attempt: attempt + 1,
+                }
+            } else {
+                println!(
+                    "[TSWAP] Rotation exhausted {} retries, falling back to detour move",
+                    PENDING_REQUEST_MAX_RETRIES
+                );
+                resume_movement(task, to_pickup)
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
@@ -409,19 +1475,53 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .mesh_n_low(1) // メッシュの最小ピア数を1に設定（デフォルト4）
                 .mesh_n(2) // 目標メッシュピア数を2に設定（デフォルト6）
                 .mesh_n_high(3) // メッシュの最大ピア数を3に設定（デフォルト12）
-                .validation_mode(gossipsub::ValidationMode::Strict)
+                // 署名フォーマット自体はゆるく受け入れ、中身の妥当性(座標/身元/鮮度)は
+                // 下のvalidate_messages()経由でアプリケーション側がAccept/Reject/Ignoreを判定する
+                .validation_mode(gossipsub::ValidationMode::Permissive)
                 .message_id_fn(message_id_fn)
+                .validate_messages() // position/swapメッセージを手動でAccept/Reject/Ignoreするため
                 .build()
                 .map_err(io::Error::other)?;
 
-            let gossipsub = gossipsub::Behaviour::new(
+            let mut gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )?;
+            // Reject/Ignoreを重ねたピアをスコアで締め出す（fuel-core-p2pに倣ったしきい値）
+            gossipsub
+                .with_peer_score(
+                    gossipsub::PeerScoreParams::default(),
+                    gossipsub::PeerScoreThresholds::default(),
+                )
+                .map_err(io::Error::other)?;
 
             let mdns =
                 mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MapdBehaviour { gossipsub, mdns })
+
+            // ゴール交換とターゲットローテーションはgossipsubの全員フィルタ方式をやめ、
+            // 1対1のrequest-responseで直接やり取りする
+            let request_response = request_response::Behaviour::new(
+                [(
+                    StreamProtocol::new("/mapd/agent-tswap/1.0.0"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            );
+
+            // manager.rsのタスク市場(`/mapd/tswap/1.0.0`)と話すための別のrequest-response
+            let manager = request_response::Behaviour::new(
+                [(
+                    StreamProtocol::new("/mapd/tswap/1.0.0"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            );
+            Ok(MapdBehaviour {
+                gossipsub,
+                mdns,
+                request_response,
+                manager,
+            })
         })?
         .build();
 
@@ -436,10 +1536,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("Enter messages via STDIN and they will be sent to connected peers using MAPD topic");
     println!("PeerId: {}", local_peer_id_str);
 
+    let metrics = AgentMetrics::new();
+    tokio::spawn(serve_metrics(metrics.registry.clone()));
+
     // === Initial Position Decision ===
     let mut my_point: Option<Point> = None;
     let grid = Arc::new(parse_map());
     let mut occupied_points: HashSet<Point> = HashSet::new();
+    // CRDSスタイルの近隣エージェントマップ。起動直後のブートストラップにも使う。
+    let mut nearby_agents = NearbyAgents::new();
     let free_cells = make_node::get_free_cells(&grid);
     println!("[Initial Position Decision] Waiting for other nodes to be discovered via mDNS...");
     let wait_duration = Duration::from_secs(3);
@@ -470,67 +1575,60 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    // After peer discovery, send occupied_request and receive occupied_response
-
-    println!("[Initial Position Decision] Sending occupied_request");
-    // 1. Get peer list
-    // Use discovered_peers, which is the peer list found by mDNS above
-    // 2. If there are no peers except myself, proceed immediately
+    // After peer discovery, pull-sync the CRDS agent map from every discovered peer.
+    // This replaces the old occupied_request/occupied_response special case with the
+    // same pull anti-entropy exchange used during normal operation (see crds_pull_tick below).
+    println!("[Initial Position Decision] Pulling CRDS state from discovered peers");
     if discovered_peers.is_empty()
         || (discovered_peers.len() == 1 && discovered_peers.contains(&local_peer_id_str))
     {
         println!("[Initial Position Decision] No other peers, proceeding immediately");
     } else {
-        // 3. Collect occupied_response from all peers
-        let mut received_peers: HashSet<String> = HashSet::new();
-        let req_msg = serde_json::json!({"type": "occupied_request", "peer_id": local_peer_id_str})
-            .to_string();
-        let _ = swarm
-            .behaviour_mut()
-            .gossipsub
-            .publish(topic.clone(), req_msg.as_bytes());
+        let mut outstanding: HashSet<request_response::OutboundRequestId> = HashSet::new();
+        for peer_str in discovered_peers.iter().filter(|p| *p != &local_peer_id_str) {
+            if let Ok(peer) = peer_str.parse::<libp2p::PeerId>() {
+                let filter = nearby_agents.build_filter();
+                let id = swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, TswapAgentRequest::CrdsPull(filter));
+                outstanding.insert(id);
+            }
+        }
         let collect_timeout = std::time::Duration::from_secs(2);
         let collect_start = std::time::Instant::now();
-        while collect_start.elapsed() < collect_timeout {
-            if received_peers.len() >= discovered_peers.len() {
-                break;
-            }
+        while collect_start.elapsed() < collect_timeout && !outstanding.is_empty() {
             if let Ok(event) =
                 tokio::time::timeout(Duration::from_millis(300), swarm.select_next_some()).await
             {
-                if let SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(
-                    gossipsub::Event::Message { message, .. },
-                )) = event
-                {
-                    if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&message.data) {
-                        if val.get("type")
-                            == Some(&serde_json::Value::String("occupied_response".to_string()))
-                        {
-                            if let Some(arr) = val.get("points").and_then(|v| v.as_array()) {
-                                for p in arr {
-                                    if let (Some(x), Some(y)) = (
-                                        p.get(0).and_then(|v| v.as_u64()),
-                                        p.get(1).and_then(|v| v.as_u64()),
-                                    ) {
-                                        occupied_points.insert((x as usize, y as usize));
-                                    }
-                                }
-                            }
-                            // PeerId is obtained from message.source
-                            if let Some(peer_id) = &message.source {
-                                received_peers.insert(peer_id.to_base58());
-                            }
+                match event {
+                    SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                        request_response::Event::Message {
+                            message: request_response::Message::Response { request_id, response: TswapAgentResponse::CrdsPush(entries) },
+                            ..
+                        },
+                    )) if outstanding.remove(&request_id) => {
+                        for info in entries {
+                            nearby_agents.update(info);
                         }
                     }
+                    SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                        request_response::Event::OutboundFailure { request_id, .. },
+                    )) => {
+                        outstanding.remove(&request_id);
+                    }
+                    _ => {}
                 }
             }
         }
         println!(
-            "[Initial Position Decision] occupied_response collection complete: {}/{}",
-            received_peers.len(),
-            discovered_peers.len()
+            "[Initial Position Decision] CRDS pull complete, {} agents known",
+            nearby_agents.agents.len()
         );
     }
+    for info in nearby_agents.agents.values() {
+        occupied_points.insert(info.current_pos);
+    }
     // 3. Randomly select from free_cells excluding occupied_points
     let available_points: Vec<Point> = if occupied_points.is_empty() {
         free_cells.clone()
@@ -562,19 +1660,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut stdin = io::BufReader::new(io::stdin()).lines();
     let mut peer_positions: HashMap<String, Point> = HashMap::new();
     let mut my_task: Option<p2p_distributed_tswap::map::task_generator::Task> = None;
+    // manager.rsへClaimTaskを送った後、ClaimResultが返るまで二重claimしないためのガード
+    let mut claim_pending = false;
     let mut last_position_broadcast = std::time::Instant::now();
-    let mut first_broadcast_success = false;
+    let mut known_peers: HashSet<libp2p::PeerId> = HashSet::new();
+    let mut crds_pull_tick = tokio::time::interval(Duration::from_secs(5));
+    let mut replication_sessions = ReplicationSessionManager::new();
+    // gossipsubの再配送による二重処理を防ぐため、直近256件のmessage_idを覚えておく
+    let mut seen_message_ids = SeenMessageIds::new(256);
+    // gossipsub送信のバックプレッシャー層：swap/rotation/ack等のControlは即送出、位置/ゴールの
+    // Telemetryは輻輳時に最新の1件へcoalesceする
+    let mut dispatcher = OutboundDispatcher::new();
+    let mut dispatch_tick = tokio::time::interval(Duration::from_millis(100));
+
+    // 移動は`while`ループでブロックせず、tickごとに`AgentState`を1手進める
+    let mut agent_state = AgentState::Idle;
+    let mut movement_tick = tokio::time::interval(Duration::from_millis(500));
+    // 現在運搬中タスクの開始時刻とtick数。TASK COMPLETE到達時にレイテンシ/ステップ数のヒストグラムへ記録する
+    let mut task_started_at: Option<std::time::Instant> = None;
+    let mut task_step_count: u64 = 0;
 
-    // TSWAPのための近隣エージェント管理
-    let mut nearby_agents = NearbyAgents::new();
     let mut my_goal: Point = my_point.unwrap_or((0, 0));
 
-    // ゴール交換とターゲットローテーションの管理
-    let mut pending_goal_swap: Option<String> = None; // 交換待ちのrequest_id
-    let mut pending_rotation: Option<String> = None; // ローテーション待ちのrequest_id
-    let mut goal_swap_requests: HashMap<String, GoalSwapRequest> = HashMap::new();
-    let mut rotation_requests: HashMap<String, TargetRotationRequest> = HashMap::new();
-
     // グリッドをノードグラフに変換（TSWAPで使用）
     let mut pos2id = HashMap::new();
     let mut id2pos = vec![];
@@ -611,11 +1718,53 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     loop {
         select! {
+            _ = dispatch_tick.tick() => {
+                dispatcher.drain(&mut swarm, &topic, &metrics);
+            }
+            _ = movement_tick.tick() => {
+                let was_active = !matches!(agent_state, AgentState::Idle);
+                if was_active {
+                    task_step_count += 1;
+                }
+                agent_state = advance_agent_state(
+                    agent_state,
+                    &mut swarm,
+                    &mut my_point,
+                    &mut my_goal,
+                    &mut dispatcher,
+                    &nearby_agents,
+                    &grid,
+                    &pos2id,
+                    &tswap_nodes,
+                    &local_peer_id_str,
+                    &metrics,
+                );
+                if was_active && matches!(agent_state, AgentState::Idle) {
+                    my_task = None;
+                    if let Some(started) = task_started_at.take() {
+                        metrics.task_steps.observe(task_step_count as f64);
+                        metrics.task_latency_ms.observe(started.elapsed().as_millis() as f64);
+                    }
+                }
+            }
             Ok(Some(line)) = stdin.next_line() => {
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    println!("Publish error: {e:?}");
+                // 手入力のチャットメッセージはControl扱い：人間が打った以上取りこぼしたくない
+                dispatcher.enqueue_control(line.as_bytes().to_vec());
+            }
+            _ = crds_pull_tick.tick() => {
+                // 既知ピアから1つ選んでpullし、数サイクルの間に近隣状態へ収束させる
+                if let Some(peer) = known_peers.iter().next().cloned() {
+                    let filter = nearby_agents.build_filter();
+                    swarm.behaviour_mut().request_response.send_request(&peer, TswapAgentRequest::CrdsPull(filter));
+                }
+                nearby_agents.cleanup_old(10);
+                let (coalesced, dropped) = dispatcher.telemetry_stats();
+                if coalesced > 0 || dropped > 0 {
+                    println!("📊 [DISPATCH] telemetry coalesced={} dropped={} (mesh may be network-bound)", coalesced, dropped);
+                }
+                // スタックした交渉をオペレータが見られるように、待機中リクエストの種別・経過時間・再送回数を出す
+                if let Some((kind, age, attempt)) = agent_state.pending_request_age() {
+                    println!("⏳ [PENDING] {} request stuck for {:?} (attempt {}/{})", kind, age, attempt, PENDING_REQUEST_MAX_RETRIES);
                 }
             }
             _ = tokio::time::sleep(std::time::Duration::from_millis(500)), if last_position_broadcast.elapsed() > std::time::Duration::from_secs(1) => {
@@ -632,27 +1781,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         "goal": [my_goal.0, my_goal.1],
                         "timestamp": timestamp
                     }).to_string();
-                    match swarm.behaviour_mut().gossipsub.publish(topic.clone(), pos_json.as_bytes()) {
-                        Ok(_) => {
-                            if !first_broadcast_success {
-                                println!("📡 [BROADCAST] Successfully broadcasting position to network!");
-                                first_broadcast_success = true;
-                            }
-                            // デバッグ: 定期的に情報を表示
-                            if nearby_agents.agents.len() > 0 {
-                                println!("📡 [BROADCAST] Sent position {:?} -> goal {:?} | Nearby agents: {}",
-                                         p, my_goal, nearby_agents.agents.len());
-                            }
-                        }
-                        Err(e) => {
-                            // NoPeersSubscribedToTopic は正常（他のピアがまだ接続していない）
-                            let err_str = format!("{:?}", e);
-                            if !err_str.contains("NoPeers") {
-                                println!("⚠️  Failed to broadcast position: {e:?}");
-                            } else {
-                                println!("⏳ [BROADCAST] Waiting for peers to subscribe...");
-                            }
-                        }
+                    // Telemetryとしてキューへ。dispatch_tickのdrainで輻輳時は最新の1件だけ送出される
+                    dispatcher.enqueue_telemetry(pos_json.into_bytes());
+                    if nearby_agents.agents.len() > 0 {
+                        println!("📡 [BROADCAST] Queued position {:?} -> goal {:?} | Nearby agents: {}",
+                                 p, my_goal, nearby_agents.agents.len());
                     }
                 } else {
                     println!("⚠️  [BROADCAST] my_point is None, cannot broadcast position");
@@ -669,21 +1802,46 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discovered a new peer: {peer_id}");
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        known_peers.insert(peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discover peer has expired: {peer_id}");
                         swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        known_peers.remove(&peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
                     println!("🔗 Peer {} subscribed to topic: {}", peer_id, topic);
+                    // joinしたばかり（かもしれない）ピアをフルステートへ収束させるセッションを開く。
+                    // 定期pull(crds_pull_tick)を待たず、ここで一度だけ要約を送って早期に追いつかせる。
+                    let session_id = replication_sessions.open(peer_id);
+                    let summary = nearby_agents.build_replication_summary();
+                    swarm.behaviour_mut().request_response.send_request(
+                        &peer_id,
+                        TswapAgentRequest::Sync(SyncRequest { session_id, summary }),
+                    );
                 }
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
                     println!("❌ Peer {} unsubscribed from topic: {}", peer_id, topic);
                 }
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                })) => {
+                    // メッシュの複数経路から同じメッセージが再配送されることがあるため、
+                    // 既知のmessage_idはIgnore報告して以降の処理をスキップする
+                    if !seen_message_ids.record_if_new(&message_id) {
+                        let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            gossipsub::MessageAcceptance::Ignore,
+                        );
+                        continue;
+                    }
+                    metrics.gossipsub_received.inc();
                     // 位置情報受信（TSWAPのため、ゴール情報も保存）
                     if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&message.data) {
                         // デバッグ: 受信したメッセージのタイプを表示
@@ -698,9 +1856,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 (val.get("peer_id"), val.get("pos"), val.get("goal")) {
                                 if let (Some(peer_id_str), Some(pos), Some(goal)) =
                                     (peer_id.as_str(), pos_arr.as_array(), goal_arr.as_array()) {
-                                    // 自分自身のメッセージは無視
+                                    // 自分自身のメッセージは無視。Ignoreとして明示的に報告する
+                                    // (黙ってdropするとvalidate_messages()配下では検証待ちのまま残ってしまう)
                                     if peer_id_str == local_peer_id_str {
-                                        // println!("🔄 [SKIP] Ignoring own position message");
+                                        let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                            &message_id,
+                                            &propagation_source,
+                                            gossipsub::MessageAcceptance::Ignore,
+                                        );
                                         continue;
                                     }
 
@@ -709,12 +1872,52 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                             (pos[0].as_u64(), pos[1].as_u64(), goal[0].as_u64(), goal[1].as_u64()) {
                                             let current_pos = (px as usize, py as usize);
                                             let goal_pos = (gx as usize, gy as usize);
-                                            peer_positions.insert(peer_id_str.to_string(), current_pos);
-
-                                            // TSWAPのため近隣エージェント情報を更新
                                             let timestamp = val.get("timestamp")
                                                 .and_then(|v| v.as_u64())
                                                 .unwrap_or(0);
+
+                                            // グリッド上の空きセルか、送信元の身元、タイムスタンプの妥当性を検証してから
+                                            // Accept/Reject/Ignoreを明示的に報告する（ValidationMode::Permissive + validate_messages()向け）
+                                            let now_secs = std::time::SystemTime::now()
+                                                .duration_since(std::time::UNIX_EPOCH)
+                                                .unwrap()
+                                                .as_secs();
+                                            let identity_ok = message
+                                                .source
+                                                .map(|src| src.to_base58() == peer_id_str)
+                                                .unwrap_or(false);
+                                            // そのpeerの最後に採用したAgentInfoよりtimestampが新しくなければ(同値または巻き戻り)
+                                            // 再送/なりすましの疑いがあるのでReject（スコアを下げる）
+                                            let timestamp_regressed = nearby_agents
+                                                .agents
+                                                .get(peer_id_str)
+                                                .is_some_and(|existing| timestamp <= existing.timestamp);
+                                            let acceptance = if !identity_ok
+                                                || !pos2id.contains_key(&current_pos)
+                                                || !pos2id.contains_key(&goal_pos)
+                                                || timestamp_regressed
+                                            {
+                                                gossipsub::MessageAcceptance::Reject
+                                            } else if timestamp > now_secs + 5
+                                                || now_secs.saturating_sub(timestamp) > 10
+                                            {
+                                                gossipsub::MessageAcceptance::Ignore
+                                            } else {
+                                                gossipsub::MessageAcceptance::Accept
+                                            };
+                                            let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                                &message_id,
+                                                &propagation_source,
+                                                acceptance,
+                                            );
+                                            if acceptance != gossipsub::MessageAcceptance::Accept {
+                                                println!("🚫 [VALIDATE] position from {} => {:?}", peer_id_str, acceptance);
+                                                continue;
+                                            }
+
+                                            peer_positions.insert(peer_id_str.to_string(), current_pos);
+
+                                            // TSWAPのため近隣エージェント情報を更新
                                             nearby_agents.update(AgentInfo {
                                                 peer_id: peer_id_str.to_string(),
                                                 current_pos,
@@ -729,429 +1932,303 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 }
                             }
                         }
-                        // occupied_request/responseは既存通り
-                        if let Some(msg_type) = val.get("type") {
-                            println!("[DEBUG] message type: {:?}", msg_type);
-                        }
-                        if val.get("type") == Some(&serde_json::Value::String("occupied_request".to_string())) {
-                            // Check peer_id
-                            let peer_id_val = val.get("peer_id");
-                            if let Some(peer_id_val) = peer_id_val {
-                                println!("[DEBUG] occupied_request peer_id: {:?}, my peer_id: {}", peer_id_val, local_peer_id_str);
-                            }
-                            if let Some(p) = my_point {
-                                let points_json = serde_json::json!({
-                                    "type": "occupied_response",
-                                    "points": [[p.0, p.1]],
-                                    "peer_id": peer_id_val.unwrap_or(&serde_json::Value::String(local_peer_id_str.clone()))
-                                }).to_string();
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), points_json.as_bytes()) {
-                                    println!("Error sending occupied_response: {e:?}");
-                                } else {
-                                    println!("[occupied_response] Sent my position ({:?})", p);
+
+                        // タスク市場(manager.rsのKademlia市場)からの軽量な空き通知。
+                        // 本体は持っていないので、アイドルならrequest-responseでClaimTaskし、
+                        // 本体ごと返ってくるClaimResultを待つ（get_recordは不要）。
+                        if val.get("type") == Some(&serde_json::Value::String("task_available".to_string())) {
+                            let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                gossipsub::MessageAcceptance::Accept,
+                            );
+                            if let Some(task_id) = val.get("task_id").and_then(|v| v.as_u64()) {
+                                if should_claim_task(&my_task, &agent_state, claim_pending) {
+                                    println!("📣 [TASK MARKET] task {} available, claiming", task_id);
+                                    swarm.behaviour_mut().manager.send_request(
+                                        &propagation_source,
+                                        ManagerTswapRequest::ClaimTask(task_id),
+                                    );
+                                    claim_pending = true;
                                 }
                             }
                         }
-                        if val.get("type") == Some(&serde_json::Value::String("occupied_response".to_string())) {
-                            // If occupied_response is received from another node, add to occupied_points
-                            if let Some(arr) = val.get("points").and_then(|v| v.as_array()) {
-                                for p in arr {
-                                    if let (Some(x), Some(y)) = (
-                                        p.get(0).and_then(|v| v.as_u64()),
-                                        p.get(1).and_then(|v| v.as_u64()),
-                                    ) {
-                                        occupied_points.insert((x as usize, y as usize));
-                                    }
-                                }
+                    }
+                    // タスク受信
+                    if let Ok(task) = serde_json::from_slice::<p2p_distributed_tswap::map::task_generator::Task>(&message.data) {
+                        // position分岐と同様、validate_messages()配下では黙ってcontinueせず
+                        // 必ずAccept/Reject/Ignoreの verdict を報告する（宛先未設定は不正、
+                        // 他エージェント宛ては単に対象外として区別する）
+                        let acceptance = match &task.peer_id {
+                            None => gossipsub::MessageAcceptance::Reject,
+                            Some(peer_id) if peer_id != &local_peer_id_str => gossipsub::MessageAcceptance::Ignore,
+                            Some(_) => gossipsub::MessageAcceptance::Accept,
+                        };
+                        let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            acceptance,
+                        );
+                        if acceptance != gossipsub::MessageAcceptance::Accept {
+                            continue;
+                        }
+                        println!("=========================");
+                        println!("📦 [TASK RECEIVED] Task ID: {:?}", task.task_id);
+                        println!("   Pickup: {:?} -> Delivery: {:?}", task.pickup, task.delivery);
+                        println!("=========================");
+                        let mut task = task;
+                        my_task = Some(task.clone());
+                        // Check if another agent is at the destination; if so, negotiate a direct
+                        // task swap via request-response instead of broadcasting and hoping it arrives
+                        let swap_candidate = peer_positions
+                            .iter()
+                            .find(|(_, pos)| **pos == task.pickup || **pos == task.delivery)
+                            .map(|(peer, _)| peer.clone());
+                        if let Some(peer) = swap_candidate {
+                            println!("[SWAP] Negotiating task swap with {}", peer);
+                            if let Some(new_task) = negotiate_task_swap(&mut swarm, &peer, task.clone(), &local_peer_id_str).await {
+                                task = new_task;
+                                my_task = Some(task.clone());
+                            } else {
+                                println!("[SWAP] No swap with {}, continuing with original task", peer);
                             }
                         }
-
-                        // ゴール交換リクエスト受信
-                        if val.get("type") == Some(&serde_json::Value::String("goal_swap_request".to_string())) {
-                            if let Ok(request) = serde_json::from_value::<GoalSwapRequest>(val.clone()) {
-                                if request.to_peer == local_peer_id_str {
-                                    println!("[GOAL_SWAP] Received goal swap request from {}", request.from_peer);
-                                    println!("[GOAL_SWAP] Their goal: {:?}, My goal: {:?}", request.my_goal, my_goal);
-
-                                    // ゴール交換を受け入れる
-                                    let response = GoalSwapResponse {
+                        // Agent must go from current position to pickup, then from pickup to delivery.
+                        // 実際の移動は`movement_tick`駆動の`advance_agent_state`が担う。ここでは状態を
+                        // セットして抜けるだけなので、移動中もこのイベントループは止まらない。
+                        if my_point.is_some() {
+                            my_goal = task.pickup;
+                            println!("🚶 [PHASE 1] Moving to PICKUP at {:?}", task.pickup);
+                            task_started_at = Some(std::time::Instant::now());
+                            task_step_count = 0;
+                            agent_state = AgentState::ToPickup(task);
+                        } else {
+                            println!("❌ [ERROR] my_point is None, cannot start task id={:?}", task.task_id);
+                        }
+                        println!("=========================");
+                    }
+                },
+                SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                    request_response::Event::Message { peer, message },
+                )) => match message {
+                    request_response::Message::Request { request, channel, .. } => match request {
+                        TswapAgentRequest::GoalSwap(request) => {
+                            if request.to_peer == local_peer_id_str {
+                                println!("[GOAL_SWAP] Received goal swap request from {}", request.from_peer);
+                                println!("[GOAL_SWAP] Their goal: {:?}, My goal: {:?}", request.my_goal, my_goal);
+                                let response = GoalSwapResponse {
+                                    request_id: request.request_id.clone(),
+                                    from_peer: local_peer_id_str.clone(),
+                                    to_peer: request.from_peer.clone(),
+                                    my_goal,
+                                    accepted: true,
+                                };
+                                my_goal = request.my_goal;
+                                let _ = swarm
+                                    .behaviour_mut()
+                                    .request_response
+                                    .send_response(channel, TswapAgentResponse::GoalSwap(response));
+                                println!("[GOAL_SWAP] Accepted, swapping goals");
+                            } else {
+                                let _ = swarm.behaviour_mut().request_response.send_response(
+                                    channel,
+                                    TswapAgentResponse::GoalSwap(GoalSwapResponse {
                                         request_id: request.request_id.clone(),
                                         from_peer: local_peer_id_str.clone(),
                                         to_peer: request.from_peer.clone(),
                                         my_goal,
-                                        accepted: true,
-                                    };
-
-                                    let response_json = serde_json::to_string(&response).unwrap();
-                                    let msg = serde_json::json!({
-                                        "type": "goal_swap_response",
-                                        "data": response_json
-                                    }).to_string();
-
-                                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg.as_bytes()) {
-                                        println!("[GOAL_SWAP] Failed to send response: {e:?}");
-                                    } else {
-                                        println!("[GOAL_SWAP] Sent response, swapping goals");
-                                        // 自分のゴールを相手のゴールに変更
-                                        my_goal = request.my_goal;
-                                        goal_swap_requests.insert(request.request_id.clone(), request);
-                                    }
-                                }
+                                        accepted: false,
+                                    }),
+                                );
                             }
                         }
-
-                        // ゴール交換レスポンス受信
-                        if val.get("type") == Some(&serde_json::Value::String("goal_swap_response".to_string())) {
-                            if let Some(data_str) = val.get("data").and_then(|v| v.as_str()) {
-                                if let Ok(response) = serde_json::from_str::<GoalSwapResponse>(data_str) {
-                                    if response.to_peer == local_peer_id_str && response.accepted {
-                                        println!("[GOAL_SWAP] Goal swap accepted by {}", response.from_peer);
-                                        println!("[GOAL_SWAP] New goal: {:?}", response.my_goal);
-                                        // 自分のゴールを相手のゴールに変更
-                                        my_goal = response.my_goal;
-                                        pending_goal_swap = None;
-                                    }
+                        TswapAgentRequest::Rotation(request) => {
+                            if let Some(my_index) = request.participants.iter().position(|p| p == &local_peer_id_str) {
+                                println!("[ROTATION] Received rotation request from {}", request.initiator);
+                                println!("[ROTATION] Participants: {:?}", request.participants);
+                                let next_index = (my_index + 1) % request.participants.len();
+                                if next_index < request.goals.len() {
+                                    let new_goal = request.goals[next_index];
+                                    println!("[ROTATION] Rotating goal: {:?} -> {:?}", my_goal, new_goal);
+                                    my_goal = new_goal;
                                 }
                             }
+                            let _ = swarm
+                                .behaviour_mut()
+                                .request_response
+                                .send_response(channel, TswapAgentResponse::RotationAck);
                         }
-
-                        // ターゲットローテーションリクエスト受信
-                        if val.get("type") == Some(&serde_json::Value::String("target_rotation_request".to_string())) {
-                            if let Ok(request) = serde_json::from_value::<TargetRotationRequest>(val.clone()) {
-                                // 自分がparticipantsに含まれているかチェック
-                                if let Some(my_index) = request.participants.iter().position(|p| p == &local_peer_id_str) {
-                                    println!("[ROTATION] Received rotation request from {}", request.initiator);
-                                    println!("[ROTATION] Participants: {:?}", request.participants);
-
-                                    // 次のエージェントのゴールを自分のゴールにする（ローテーション）
-                                    let next_index = (my_index + 1) % request.participants.len();
-                                    if next_index < request.goals.len() {
-                                        let new_goal = request.goals[next_index];
-                                        println!("[ROTATION] Rotating goal: {:?} -> {:?}", my_goal, new_goal);
-                                        my_goal = new_goal;
-                                        rotation_requests.insert(request.request_id.clone(), request);
-                                    }
-                                }
-                            }
+                        TswapAgentRequest::CrdsPull(filter) => {
+                            let missing = nearby_agents.entries_missing_from(&filter, 10);
+                            println!("[CRDS] Pull from {}: sending {} entries", peer, missing.len());
+                            let _ = swarm
+                                .behaviour_mut()
+                                .request_response
+                                .send_response(channel, TswapAgentResponse::CrdsPush(missing));
                         }
-
-                        // タスクスワップリクエスト受信
-                        if val.get("type") == Some(&serde_json::Value::String("swap_request".to_string())) {
-                            // swap_request: {type: "swap_request", from_peer: ..., to_peer: ..., task: ...}
-                            if let (Some(from_peer), Some(task_val)) = (val.get("from_peer"), val.get("task")) {
-                                if let Some(from_peer_str) = from_peer.as_str() {
-                                    println!("[SWAP] swap request from {}", from_peer_str);
-                                    // Receiver swaps its own task
-                                    if let Some(my_task_val) = my_task.clone() {
-                                        let swap_response = serde_json::json!({
-                                            "type": "swap_response",
-                                            "from_peer": local_peer_id_str,
-                                            "to_peer": from_peer_str,
-                                            "task": my_task_val
-                                        }).to_string();
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), swap_response.as_bytes()) {
-                                            println!("Failed to send swap_response: {e:?}");
-                                        } else {
-                                            println!("Sent swap_response to {}", from_peer_str);
-                                        }
-                                        // 受信したタスクに切り替え
-                                        if let Ok(new_task) = serde_json::from_value::<p2p_distributed_tswap::map::task_generator::Task>(task_val.clone()) {
-                                            my_task = Some(new_task);
-                                        }
-                                    }
-                                }
-                            }
+                        TswapAgentRequest::Sync(request) => {
+                            let missing = nearby_agents.entries_missing_from_summary(&request.summary);
+                            println!(
+                                "[SYNC] Session {} from {}: sending {} missing entries, {} occupied cells known here",
+                                request.session_id, peer, missing.len(), nearby_agents.agents.len()
+                            );
+                            let occupied = nearby_agents.build_replication_summary().occupied;
+                            let _ = swarm.behaviour_mut().request_response.send_response(
+                                channel,
+                                TswapAgentResponse::Sync(SyncResponse {
+                                    session_id: request.session_id,
+                                    occupied,
+                                    missing,
+                                }),
+                            );
                         }
-                        // タスクスワップレスポンス受信
-                        if val.get("type") == Some(&serde_json::Value::String("swap_response".to_string())) {
-                    if let Some(task_val) = val.get("task") {
-                        if let Ok(new_task) = serde_json::from_value::<p2p_distributed_tswap::map::task_generator::Task>(task_val.clone()) {
-                            println!("[SWAP] Received swapped task");
-                            my_task = Some(new_task.clone());
-                            // 新しいタスクのpickup/deliveryでTSWAPベースの移動を行う
-                            let pickup = Some(new_task.pickup);
-                            let delivery = Some(new_task.delivery);
-                            if let (Some(pickup), Some(delivery), Some(mut current_pos)) = (pickup, delivery, my_point) {
-                                // 1. Move from current position to pickup with TSWAP
-                                my_goal = pickup;
-                                println!("Worker: Moving to pickup at {:?} using TSWAP (swapped task)", pickup);
-                                while current_pos != pickup {
-                                    let nearby = nearby_agents.get_nearby(current_pos, 15, &local_peer_id_str);
-                                    let action = compute_next_move_with_tswap(
-                                        current_pos, my_goal, &nearby, &grid, &pos2id, &tswap_nodes,
-                                    );
-                                    match action {
-                                        TswapAction::Move(next_pos) => {
-                                            if next_pos != current_pos {
-                                                current_pos = next_pos;
-                                                my_point = Some(current_pos);
-                                            }
-                                        }
-                                        _ => {} // 交換リクエストは省略（簡略版）
-                                    }
-                                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                                }
-
-                                // 2. Move from pickup to delivery with TSWAP
-                                my_goal = delivery;
-                                println!("Worker: Moving to delivery at {:?} using TSWAP (swapped task)", delivery);
-                                while current_pos != delivery {
-                                    let nearby = nearby_agents.get_nearby(current_pos, 15, &local_peer_id_str);
-                                    let action = compute_next_move_with_tswap(
-                                        current_pos, my_goal, &nearby, &grid, &pos2id, &tswap_nodes,
-                                    );
-                                    match action {
-                                        TswapAction::Move(next_pos) => {
-                                            if next_pos != current_pos {
-                                                current_pos = next_pos;
-                                                my_point = Some(current_pos);
-                                            }
-                                        }
-                                        _ => {} // 交換リクエストは省略（簡略版）
+                        TswapAgentRequest::TaskSwap(request) => {
+                            if request.to_peer == local_peer_id_str {
+                                println!("[SWAP] Task swap request from {}", request.from_peer);
+                                let response = if let Some(my_task_val) = my_task.clone() {
+                                    my_task = Some(request.task.clone());
+                                    TaskSwapResponse {
+                                        from_peer: local_peer_id_str.clone(),
+                                        to_peer: request.from_peer.clone(),
+                                        accepted: true,
+                                        task: my_task_val,
                                     }
-                                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                                }
-
-                                my_point = Some(current_pos);
-                                // 完了通知
-                                let done_json = if let Some(task_id) = new_task.task_id {
-                                    serde_json::json!({"status": "done", "task_id": task_id}).to_string()
                                 } else {
-                                    serde_json::json!({"status": "done"}).to_string()
+                                    // 交換できる自タスクがないので拒否し、相手のタスクをそのまま返す
+                                    TaskSwapResponse {
+                                        from_peer: local_peer_id_str.clone(),
+                                        to_peer: request.from_peer.clone(),
+                                        accepted: false,
+                                        task: request.task.clone(),
+                                    }
                                 };
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), done_json.as_bytes()) {
-                                    println!("Failed to send completion notification: {e:?}");
-                                } else {
-                                    println!("Completion notification ({}) sent", done_json);
-                                }
+                                println!("[SWAP] Task swap {}", if response.accepted { "accepted" } else { "rejected (no task to offer)" });
+                                let _ = swarm
+                                    .behaviour_mut()
+                                    .request_response
+                                    .send_response(channel, TswapAgentResponse::TaskSwap(response));
                             } else {
-                                println!("Worker: invalid pickup or delivery location for swapped task id={:?}", new_task.task_id);
+                                let _ = swarm.behaviour_mut().request_response.send_response(
+                                    channel,
+                                    TswapAgentResponse::TaskSwap(TaskSwapResponse {
+                                        from_peer: local_peer_id_str.clone(),
+                                        to_peer: request.from_peer.clone(),
+                                        accepted: false,
+                                        task: request.task.clone(),
+                                    }),
+                                );
                             }
                         }
-                        }
-                    }
-                        }
-                    // タスク受信
-                    if let Ok(task) = serde_json::from_slice::<p2p_distributed_tswap::map::task_generator::Task>(&message.data) {
-                        if let Some(ref peer_id) = task.peer_id {
-                            if peer_id != &local_peer_id_str {
-                                continue;
+                    },
+                    request_response::Message::Response { request_id, response } => match response {
+                        TswapAgentResponse::RotationAck => {
+                            println!("[ROTATION] Ack received from {}", peer);
+                            // 全参加者のackが揃ったら、500msのdeadlineを待たずその場で待機を解除する
+                            let resume = if let AgentState::WaitingRotation { pending_acks, task, to_pickup, .. } = &mut agent_state {
+                                pending_acks.remove(&request_id);
+                                pending_acks.is_empty().then(|| (task.clone(), *to_pickup))
+                            } else {
+                                None
+                            };
+                            if let Some((task, to_pickup)) = resume {
+                                println!("[ROTATION] All acks received, resuming movement early");
+                                agent_state = resume_movement(task, to_pickup);
                             }
-                        } else {
-                            continue;
                         }
-                        println!("=========================");
-                        println!("📦 [TASK RECEIVED] Task ID: {:?}", task.task_id);
-                        println!("   Pickup: {:?} -> Delivery: {:?}", task.pickup, task.delivery);
-                        println!("=========================");
-                        my_task = Some(task.clone());
-                        let pickup = Some(task.pickup);
-                        let delivery = Some(task.delivery);
-                        // Check if another agent is at the destination
-                        let mut swap_sent = false;
-                        for (peer, pos) in &peer_positions {
-                            if Some(*pos) == pickup || Some(*pos) == delivery {
-                                // Send swap request
-                                let swap_req = serde_json::json!({
-                                    "type": "swap_request",
-                                    "from_peer": local_peer_id_str,
-                                    "to_peer": peer,
-                                    "task": task
-                                }).to_string();
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), swap_req.as_bytes()) {
-                                    println!("Failed to send swap_request: {e:?}");
-                                } else {
-                                    println!("Sent swap_request to {}", peer);
+                        TswapAgentResponse::CrdsPush(entries) => {
+                            let mut applied = 0;
+                            for info in entries {
+                                if nearby_agents.update(info) {
+                                    applied += 1;
                                 }
-                                swap_sent = true;
-                                break;
                             }
+                            println!("[CRDS] Pull response from {}: {} entries applied", peer, applied);
                         }
-                        if swap_sent {
-                            println!("[SWAP] Waiting for swap response...");
-                            continue;
-                        }
-                        // Agent must go from current position to pickup, then from pickup to delivery
-                        // TSWAPベースの移動ロジックを使用
-                        if let (Some(pickup), Some(delivery), Some(mut current_pos)) = (pickup, delivery, my_point) {
-                            // 1. Move from current position to pickup with TSWAP
-                            my_goal = pickup;
-                            println!("🚶 [PHASE 1] Moving to PICKUP at {:?} (current: {:?})", pickup, current_pos);
-                            while current_pos != pickup {
-                                let nearby = nearby_agents.get_nearby(current_pos, 15, &local_peer_id_str);
-                                println!("  📍 Current: {:?} -> {:?} (Nearby: {})", current_pos, my_goal, nearby.len());
-
-                                let action = compute_next_move_with_tswap(
-                                    current_pos,
-                                    my_goal,
-                                    &nearby,
-                                    &grid,
-                                    &pos2id,
-                                    &tswap_nodes,
-                                );
-
-                                match action {
-                                    TswapAction::Move(next_pos) => {
-                                        if next_pos != current_pos {
-                                            println!("[TSWAP] Moving {} -> {}",
-                                                format!("{:?}", current_pos),
-                                                format!("{:?}", next_pos));
-                                            current_pos = next_pos;
-                                            my_point = Some(current_pos);
-                                        }
-                                    }
-                                    TswapAction::WaitForGoalSwap(peer_id) => {
-                                        println!("[TSWAP] Sending goal swap request to {}", peer_id);
-                                        let request_id = format!("{}_{}", local_peer_id_str, std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-                                        let request = GoalSwapRequest {
-                                            request_id: request_id.clone(),
-                                            from_peer: local_peer_id_str.clone(),
-                                            to_peer: peer_id,
-                                            my_goal,
-                                        };
-                                        let msg = serde_json::to_value(&request).unwrap();
-                                        let msg_with_type = serde_json::json!({
-                                            "type": "goal_swap_request",
-                                            "request_id": request.request_id,
-                                            "from_peer": request.from_peer,
-                                            "to_peer": request.to_peer,
-                                            "my_goal": [request.my_goal.0, request.my_goal.1]
-                                        }).to_string();
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg_with_type.as_bytes()) {
-                                            println!("[TSWAP] Failed to send goal swap request: {e:?}");
-                                        }
-                                        pending_goal_swap = Some(request_id);
-                                    }
-                                    TswapAction::WaitForRotation(participants, goals) => {
-                                        println!("[TSWAP] Sending target rotation request");
-                                        println!("[TSWAP] Participants: {:?}", participants);
-                                        let request_id = format!("{}_{}", local_peer_id_str, std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-                                        let request = TargetRotationRequest {
-                                            request_id: request_id.clone(),
-                                            initiator: local_peer_id_str.clone(),
-                                            participants,
-                                            goals,
-                                        };
-                                        let msg = serde_json::to_value(&request).unwrap();
-                                        let msg_with_type = serde_json::json!({
-                                            "type": "target_rotation_request",
-                                            "request_id": request.request_id,
-                                            "initiator": request.initiator,
-                                            "participants": request.participants,
-                                            "goals": request.goals.iter().map(|g| [g.0, g.1]).collect::<Vec<_>>()
-                                        }).to_string();
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg_with_type.as_bytes()) {
-                                            println!("[TSWAP] Failed to send rotation request: {e:?}");
-                                        }
-                                        pending_rotation = Some(request_id);
-                                    }
-                                    TswapAction::Wait => {
-                                        println!("[TSWAP] Waiting due to collision avoidance...");
+                        TswapAgentResponse::GoalSwap(goal_swap_response) => {
+                            // 待機中のoutbound_idと一致する場合のみ、その場でゴールを確定させて移動を再開する
+                            if let AgentState::WaitingGoalSwap { task, to_pickup, outbound_id, .. } = &agent_state {
+                                if *outbound_id == request_id {
+                                    let (task, to_pickup) = (task.clone(), *to_pickup);
+                                    if goal_swap_response.accepted {
+                                        println!(
+                                            "[TSWAP] Goal swap accepted by {}, new goal: {:?}",
+                                            peer, goal_swap_response.my_goal
+                                        );
+                                        my_goal = goal_swap_response.my_goal;
+                                        metrics.goal_swaps_accepted.inc();
+                                    } else {
+                                        println!("[TSWAP] Goal swap rejected by {}", peer);
+                                        metrics.goal_swaps_rejected.inc();
                                     }
+                                    agent_state = resume_movement(task, to_pickup);
                                 }
-
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
                             }
-                            println!("✅ [PHASE 1 COMPLETE] Reached PICKUP at {:?}", pickup);
-
-                            // 2. Move from pickup to delivery with TSWAP
-                            my_goal = delivery;
-                            println!("🚚 [PHASE 2] Moving to DELIVERY at {:?} (current: {:?})", delivery, current_pos);
-                            while current_pos != delivery {
-                                let nearby = nearby_agents.get_nearby(current_pos, 15, &local_peer_id_str);
-                                println!("  📍 Current: {:?} -> {:?} (Nearby: {})", current_pos, my_goal, nearby.len());
-
-                                let action = compute_next_move_with_tswap(
-                                    current_pos,
-                                    my_goal,
-                                    &nearby,
-                                    &grid,
-                                    &pos2id,
-                                    &tswap_nodes,
-                                );
-
-                                match action {
-                                    TswapAction::Move(next_pos) => {
-                                        if next_pos != current_pos {
-                                            println!("[TSWAP] Moving {} -> {}",
-                                                format!("{:?}", current_pos),
-                                                format!("{:?}", next_pos));
-                                            current_pos = next_pos;
-                                            my_point = Some(current_pos);
-                                        }
-                                    }
-                                    TswapAction::WaitForGoalSwap(peer_id) => {
-                                        println!("[TSWAP] Sending goal swap request to {}", peer_id);
-                                        let request_id = format!("{}_{}", local_peer_id_str, std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-                                        let request = GoalSwapRequest {
-                                            request_id: request_id.clone(),
-                                            from_peer: local_peer_id_str.clone(),
-                                            to_peer: peer_id,
-                                            my_goal,
-                                        };
-                                        let msg_with_type = serde_json::json!({
-                                            "type": "goal_swap_request",
-                                            "request_id": request.request_id,
-                                            "from_peer": request.from_peer,
-                                            "to_peer": request.to_peer,
-                                            "my_goal": [request.my_goal.0, request.my_goal.1]
-                                        }).to_string();
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg_with_type.as_bytes()) {
-                                            println!("[TSWAP] Failed to send goal swap request: {e:?}");
-                                        }
-                                        pending_goal_swap = Some(request_id);
-                                    }
-                                    TswapAction::WaitForRotation(participants, goals) => {
-                                        println!("[TSWAP] Sending target rotation request");
-                                        println!("[TSWAP] Participants: {:?}", participants);
-                                        let request_id = format!("{}_{}", local_peer_id_str, std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-                                        let request = TargetRotationRequest {
-                                            request_id: request_id.clone(),
-                                            initiator: local_peer_id_str.clone(),
-                                            participants,
-                                            goals,
-                                        };
-                                        let msg_with_type = serde_json::json!({
-                                            "type": "target_rotation_request",
-                                            "request_id": request.request_id,
-                                            "initiator": request.initiator,
-                                            "participants": request.participants,
-                                            "goals": request.goals.iter().map(|g| [g.0, g.1]).collect::<Vec<_>>()
-                                        }).to_string();
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg_with_type.as_bytes()) {
-                                            println!("[TSWAP] Failed to send rotation request: {e:?}");
-                                        }
-                                        pending_rotation = Some(request_id);
-                                    }
-                                    TswapAction::Wait => {
-                                        println!("[TSWAP] Waiting due to collision avoidance...");
+                        }
+                        TswapAgentResponse::TaskSwap(_) => {}
+                        TswapAgentResponse::Sync(response) => {
+                            if let Some(session_peer) = replication_sessions.close(response.session_id) {
+                                let mut applied = 0;
+                                for info in response.missing {
+                                    if nearby_agents.update(info) {
+                                        applied += 1;
                                     }
                                 }
-
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                                println!(
+                                    "[SYNC] Session {} with {} converged: {} entries applied, {} occupied cells reported",
+                                    response.session_id, session_peer, applied, response.occupied.len()
+                                );
                             }
-                            println!("✅ [PHASE 2 COMPLETE] Reached DELIVERY at {:?}", delivery);
-                            my_point = Some(current_pos);
-                        } else {
-                            println!("❌ [ERROR] Invalid pickup or delivery location for task id={:?}", task.task_id);
                         }
-                        let reached_goal = true; // Goal reached check (should be determined by logic)
-                        if reached_goal {
-                            // Publish completion notification including task_id
-                            let done_json = if let Some(task_id) = task.task_id {
-                                serde_json::json!({"status": "done", "task_id": task_id}).to_string()
-                            } else {
-                                serde_json::json!({"status": "done"}).to_string()
-                            };
-                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), done_json.as_bytes()) {
-                                println!("❌ [ERROR] Failed to send completion notification: {e:?}");
+                    },
+                },
+                SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                    request_response::Event::OutboundFailure { peer, error, .. },
+                )) => {
+                    println!("[TSWAP] request-response to {} failed: {:?}", peer, error);
+                }
+                // manager.rsのタスク市場とのやり取り（ClaimTask/ClaimResult, QueryOccupied/OccupiedSet）
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Manager(request_response::Event::Message {
+                    message, ..
+                })) => match message {
+                    request_response::Message::Request { channel, request, .. } => match request {
+                        // エージェントはClaimTask/QueryOccupiedの送り手であって受け手ではないので、
+                        // ここに要求が来るのは想定外のケースのみ
+                        ManagerTswapRequest::ClaimTask(_) | ManagerTswapRequest::QueryOccupied => {
+                            let _ = swarm
+                                .behaviour_mut()
+                                .manager
+                                .send_response(channel, ManagerTswapResponse::OccupiedSet(Vec::new()));
+                        }
+                    },
+                    request_response::Message::Response { response, .. } => match response {
+                        ManagerTswapResponse::ClaimResult { task_id, task: Some(task) } => {
+                            claim_pending = false;
+                            println!("🎯 [TASK MARKET] claimed task {}: {:?}", task_id, task);
+                            my_task = Some(task.clone());
+                            if my_point.is_some() {
+                                my_goal = task.pickup;
+                                println!("🚶 [PHASE 1] Moving to PICKUP at {:?}", task.pickup);
+                                task_started_at = Some(std::time::Instant::now());
+                                task_step_count = 0;
+                                agent_state = AgentState::ToPickup(task);
                             } else {
-                                println!("🎉 [TASK COMPLETE] Task ID {:?} finished! Notification sent to manager", task.task_id);
+                                println!("❌ [ERROR] my_point is None, cannot start task id={}", task_id);
                             }
                         }
-                        println!("=========================");
-                    }
+                        ManagerTswapResponse::ClaimResult { task_id, task: None } => {
+                            // 別エージェントに先取りされた：claim待ちを解除し、次の通知を待つ
+                            claim_pending = false;
+                            println!("⏭️  [TASK MARKET] task {} already claimed by someone else", task_id);
+                        }
+                        ManagerTswapResponse::OccupiedSet(_) => {}
+                    },
                 },
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Manager(
+                    request_response::Event::OutboundFailure { peer, error, .. },
+                )) => {
+                    claim_pending = false;
+                    println!("[TASK MARKET] request to manager {} failed: {:?}", peer, error);
+                }
                 _ => {}
             }
         }
@@ -1159,3 +2236,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
     #[allow(unreachable_code)]
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_task() -> p2p_distributed_tswap::map::task_generator::Task {
+        p2p_distributed_tswap::map::task_generator::Task {
+            pickup: (0, 0),
+            delivery: (1, 1),
+            peer_id: None,
+            task_id: Some(1),
+            priority: 0,
+            deadline_ms: None,
+        }
+    }
+
+    #[test]
+    fn should_claim_task_when_idle_and_no_claim_in_flight() {
+        assert!(should_claim_task(&None, &AgentState::Idle, false));
+    }
+
+    #[test]
+    fn should_not_claim_task_while_already_carrying_one() {
+        assert!(!should_claim_task(&Some(sample_task()), &AgentState::Idle, false));
+    }
+
+    #[test]
+    fn should_not_claim_task_while_not_idle() {
+        assert!(!should_claim_task(&None, &AgentState::ToPickup(sample_task()), false));
+    }
+
+    #[test]
+    fn should_not_claim_task_while_a_claim_is_already_pending() {
+        // ClaimResultが返るまでの間に同じヒントが再配送されても、二重にClaimTaskを送らない
+        assert!(!should_claim_task(&None, &AgentState::Idle, true));
+    }
+}
@@ -1,21 +1,64 @@
+use async_trait::async_trait;
+use futures::prelude::*;
 use futures::stream::StreamExt;
 use libp2p::{
-    gossipsub, mdns, noise,
+    StreamProtocol, gossipsub, mdns, noise,
+    request_response::{self, ProtocolSupport},
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux,
 };
 use p2p_distributed_tswap::map::map::MAP;
+use p2p_distributed_tswap::map::position_crdt::{AgentStateGossip, AgentTaskPhase, VersionedAgentState};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::hash_map::DefaultHasher,
     error::Error,
     hash::{Hash, Hasher},
+    io::Error as IoError,
     time::Duration,
 };
 use tokio::{io, io::AsyncBufReadExt, select};
 
 type Point = (usize, usize);
 
+// --- gossipsubピアスコアリングの閾値 ---
+// Agent-Manager間は1対1接続なので、多少のノイズでメッシュから締め出したくない分デフォルトより緩め。
+// スコアがこれを下回ったらgossip/publish対象から外される(gossipsub側のPeerScoreThresholds)
+const GOSSIP_SCORE_THRESHOLD: f64 = -10.0;
+const PUBLISH_SCORE_THRESHOLD: f64 = -20.0;
+const GRAYLIST_SCORE_THRESHOLD: f64 = -80.0;
+// これを下回ったら(グレイリストに載る前に)マネージャーへ`AgentHealth`で自己申告する
+const LOW_SCORE_NOTIFY_THRESHOLD: f64 = -5.0;
+
+// 接続が詰まってpublishが追いつかない間、position_updateを無制限に溜め込んでselect!ループを
+// ブロックしないための、容量1の「最新のみ保持」送信キュー。LWW(version/timestamp)比較で
+// 安全に古い値を捨てられるため、古い保留値は新しい値が来た時点で黙って上書き(コアレス)してよい。
+struct PositionSendQueue {
+    pending: Option<Point>,
+    coalesced_since_flush: u64,
+}
+
+impl PositionSendQueue {
+    fn new() -> Self {
+        PositionSendQueue {
+            pending: None,
+            coalesced_since_flush: 0,
+        }
+    }
+
+    /// 新しい位置を積む。既に保留中の値があれば捨てて置き換える（キューが詰まっている合図）
+    fn push(&mut self, position: Point) {
+        if self.pending.replace(position).is_some() {
+            self.coalesced_since_flush += 1;
+        }
+    }
+
+    /// 保留中の最新値を取り出す
+    fn take(&mut self) -> Option<Point> {
+        self.pending.take()
+    }
+}
+
 fn parse_map() -> Vec<Vec<char>> {
     let grid = MAP
         .replace('\r', "")
@@ -27,24 +70,45 @@ fn parse_map() -> Vec<Vec<char>> {
     grid
 }
 
+// `version`はマネージャー側のAgentStateCrdt(p2p_distributed_tswap::map::position_crdt)が
+// 重複配送や順序の入れ替わりをべき等にマージするためのLWWキー。毎秒・移動のたびに
+// 全体を再送しても、マネージャーは生JSON比較ではなくversion/wallclock_msの比較だけで
+// 最新の値を決定できる。`task_phase`/`goal_pos`はエージェント自身が申告する現在位置の
+// "自己申告版"で、マネージャーが割り当てたタスクそのものの権威はマネージャー側に残る
 #[allow(dead_code)]
-fn broadcast_position(
+fn broadcast_agent_state(
     swarm: &mut libp2p::Swarm<MapdBehaviour>,
     topic: &gossipsub::IdentTopic,
     peer_id: &str,
     position: Point,
+    task: &Option<p2p_distributed_tswap::map::task_generator::Task>,
+    version: u64,
 ) {
-    let timestamp = std::time::SystemTime::now()
+    let wallclock_ms = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_millis() as u64;
-    let position_update = serde_json::json!({
-        "type": "position_update",
-        "peer_id": peer_id,
-        "position": [position.0, position.1],
-        "timestamp": timestamp
-    });
-    if let Ok(update_bytes) = serde_json::to_vec(&position_update) {
+
+    let (task_phase, goal_pos) = match task {
+        None => (AgentTaskPhase::Idle, None),
+        Some(task) if position == task.pickup => {
+            (AgentTaskPhase::MovingToDelivery, Some(task.delivery))
+        }
+        Some(task) => (AgentTaskPhase::MovingToPickup, Some(task.pickup)),
+    };
+
+    let gossip = AgentStateGossip::new(
+        peer_id.to_string(),
+        VersionedAgentState {
+            current_pos: position,
+            goal_pos,
+            task_phase,
+            version,
+            wallclock_ms,
+            tombstone: false,
+        },
+    );
+    if let Ok(update_bytes) = serde_json::to_vec(&gossip) {
         let _ = swarm
             .behaviour_mut()
             .gossipsub
@@ -60,10 +124,569 @@ struct MoveInstruction {
     timestamp: u64,
 }
 
+// manager.rs側と対になるrequest-responseプロトコル定義。移動指示とタスク割り当ては
+// マネージャーから宛先エージェントへの1対1リクエストで届き、エージェントはタスクの
+// 受信/開始/完了をそれぞれ明示的なリクエストでマネージャーへ送り返す。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ManagerAgentRequest {
+    Move(MoveInstruction),
+    Task(p2p_distributed_tswap::map::task_generator::Task),
+    TaskStatus { task_id: u64, status: TaskAckStatus },
+    // ピアスコア低下/送信キュー詰まりを検知したエージェントが自己申告する。マネージャーは
+    // degraded=trueのエージェントへの新規タスク割り当てを一時停止する
+    AgentHealth { degraded: bool, reason: String },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum TaskAckStatus {
+    Received,
+    Started,
+    Completed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ManagerAgentResponse {
+    Ack,
+}
+
+// request_response::Codec実装: JSON行をそのままストリームに流す
+#[derive(Clone, Default)]
+struct ManagerAgentCodec;
+
+#[async_trait]
+impl request_response::Codec for ManagerAgentCodec {
+    type Protocol = StreamProtocol;
+    type Request = ManagerAgentRequest;
+    type Response = ManagerAgentResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct MapdBehaviour {
     gossipsub: gossipsub::Behaviour,
     mdns: mdns::tokio::Behaviour,
+    request_response: request_response::Behaviour<ManagerAgentCodec>,
+}
+
+// `AgentWorker`が今どの段階にいるかを表す。discovery→ready→broadcasting→task handlingという
+// 元の`main`内の逐次処理(待機ループ→初期ブロードキャスト→定常ループ)をそのまま状態として切り出した。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AgentPhase {
+    /// リスナーアドレス確定を待つ（Agent同士のmDNS発見は無視する）
+    WaitingForListener,
+    /// Gossipsubメッシュが安定するまでの固定待機（この直後に初期位置を選ぶ）
+    SettlingMesh,
+    /// マネージャーのSubscribedイベント（発見）を待つ
+    WaitingForManager,
+    /// 初期位置を確実に届けるための複数回ブロードキャスト（残り回数）
+    InitialBroadcast { remaining: u32 },
+    /// 定常状態：stdin入力・定期ブロードキャスト・backpressure flush・request-responseの処理
+    Running,
+}
+
+/// `next_action`が返す、観測可能な1ステップ分のイベント。副作用(println!)を`main`に
+/// 閉じ込めることで、合成のgossipsub/request-responseメッセージを与えるだけで
+/// 状態遷移をテストから検証できるようにする。
+#[derive(Clone, Debug, PartialEq)]
+enum AgentAction {
+    Listening(libp2p::Multiaddr),
+    InitialPositionChosen(Point),
+    NoInitialPositionAvailable,
+    ManagerSubscribed { peer: String },
+    ManagerDiscoveryTimedOut,
+    MeshReady { subscribed_peers: usize },
+    InitialPositionBroadcast { attempt: u32, position: Point },
+    StdinPublishFailed(String),
+    PositionQueued(Point),
+    PositionFlushed(Point),
+    BackpressureCoalesced { count: u64 },
+    HealthChanged { degraded: bool, reason: String },
+    Moved {
+        from: Option<Point>,
+        to: Point,
+    },
+    TaskReceived {
+        task_id: Option<u64>,
+        pickup: Point,
+        delivery: Point,
+    },
+    TaskCompleted {
+        task_id: u64,
+    },
+    OutboundFailure { peer: String },
+    InboundFailure { peer: String },
+    NoOp,
+}
+
+// mainの単一の巨大なselect!から、swarm・現在位置・タスク・タイマーを所有する状態機械として
+// 切り出したもの。`next_action`が1ステップずつ進め、呼び出し側(main)はprintln!だけを担当する。
+struct AgentWorker {
+    swarm: libp2p::Swarm<MapdBehaviour>,
+    topic: gossipsub::IdentTopic,
+    local_peer_id_str: String,
+    grid: Vec<Vec<char>>,
+    my_point: Option<Point>,
+    my_task: Option<p2p_distributed_tswap::map::task_generator::Task>,
+    position_version: u64,
+    position_queue: PositionSendQueue,
+    manager_peer: Option<libp2p::PeerId>,
+    degraded: bool,
+    phase: AgentPhase,
+    phase_start: std::time::Instant,
+    subscribed_peers_count: usize,
+    last_position_broadcast: std::time::Instant,
+    stdin: tokio::io::Lines<io::BufReader<io::Stdin>>,
+}
+
+impl AgentWorker {
+    const LISTENER_WAIT: Duration = Duration::from_secs(3);
+    const MESH_SETTLE: Duration = Duration::from_secs(3);
+    const MANAGER_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(8);
+    const MANAGER_DISCOVERY_MIN_SETTLE: Duration = Duration::from_secs(4);
+    const INITIAL_BROADCAST_COUNT: u32 = 3;
+
+    fn new(
+        swarm: libp2p::Swarm<MapdBehaviour>,
+        topic: gossipsub::IdentTopic,
+        local_peer_id_str: String,
+        grid: Vec<Vec<char>>,
+    ) -> Self {
+        AgentWorker {
+            swarm,
+            topic,
+            local_peer_id_str,
+            grid,
+            my_point: None,
+            my_task: None,
+            position_version: 0,
+            position_queue: PositionSendQueue::new(),
+            manager_peer: None,
+            degraded: false,
+            phase: AgentPhase::WaitingForListener,
+            phase_start: std::time::Instant::now(),
+            subscribed_peers_count: 0,
+            last_position_broadcast: std::time::Instant::now(),
+            stdin: io::BufReader::new(io::stdin()).lines(),
+        }
+    }
+
+    async fn next_action(&mut self) -> AgentAction {
+        match self.phase {
+            AgentPhase::WaitingForListener => self.step_waiting_for_listener().await,
+            AgentPhase::SettlingMesh => self.step_settling_mesh().await,
+            AgentPhase::WaitingForManager => self.step_waiting_for_manager().await,
+            AgentPhase::InitialBroadcast { .. } => self.step_initial_broadcast().await,
+            AgentPhase::Running => self.step_running().await,
+        }
+    }
+
+    async fn step_waiting_for_listener(&mut self) -> AgentAction {
+        if self.phase_start.elapsed() >= Self::LISTENER_WAIT {
+            self.phase = AgentPhase::SettlingMesh;
+            self.phase_start = std::time::Instant::now();
+            return AgentAction::NoOp;
+        }
+        let timeout = std::cmp::min(
+            Self::LISTENER_WAIT - self.phase_start.elapsed(),
+            Duration::from_millis(300),
+        );
+        match tokio::time::timeout(timeout, self.swarm.select_next_some()).await {
+            Ok(SwarmEvent::NewListenAddr { address, .. }) => AgentAction::Listening(address),
+            // Agent同士の接続を防ぐため、mDNS発見はこの段階でも無視する
+            Ok(_) => AgentAction::NoOp,
+            Err(_) => AgentAction::NoOp,
+        }
+    }
+
+    async fn step_settling_mesh(&mut self) -> AgentAction {
+        if self.phase_start.elapsed() < Self::MESH_SETTLE {
+            tokio::time::sleep(Self::MESH_SETTLE - self.phase_start.elapsed()).await;
+        }
+
+        use rand::seq::SliceRandom;
+        use rand::thread_rng;
+
+        let mut free_cells = vec![];
+        for y in 0..self.grid.len() {
+            for x in 0..self.grid[0].len() {
+                if self.grid[y][x] != '@' {
+                    free_cells.push((x, y));
+                }
+            }
+        }
+        self.my_point = free_cells.choose(&mut thread_rng()).cloned();
+
+        self.phase_start = std::time::Instant::now();
+        self.subscribed_peers_count = 0;
+        self.phase = AgentPhase::WaitingForManager;
+
+        match self.my_point {
+            Some(p) => {
+                self.position_version += 1;
+                broadcast_agent_state(
+                    &mut self.swarm,
+                    &self.topic,
+                    &self.local_peer_id_str,
+                    p,
+                    &self.my_task,
+                    self.position_version,
+                );
+                AgentAction::InitialPositionChosen(p)
+            }
+            None => AgentAction::NoInitialPositionAvailable,
+        }
+    }
+
+    async fn step_waiting_for_manager(&mut self) -> AgentAction {
+        if self.phase_start.elapsed() >= Self::MANAGER_DISCOVERY_TIMEOUT {
+            let subscribed_peers = self.subscribed_peers_count;
+            self.phase = AgentPhase::InitialBroadcast {
+                remaining: Self::INITIAL_BROADCAST_COUNT,
+            };
+            self.phase_start = std::time::Instant::now();
+            return if subscribed_peers == 0 {
+                AgentAction::ManagerDiscoveryTimedOut
+            } else {
+                AgentAction::MeshReady { subscribed_peers }
+            };
+        }
+
+        // 少なくとも1つのピアがsubscribeしたら、さらに1秒待ってから進む
+        if self.subscribed_peers_count > 0
+            && self.phase_start.elapsed() > Self::MANAGER_DISCOVERY_MIN_SETTLE
+        {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let subscribed_peers = self.subscribed_peers_count;
+            self.phase = AgentPhase::InitialBroadcast {
+                remaining: Self::INITIAL_BROADCAST_COUNT,
+            };
+            self.phase_start = std::time::Instant::now();
+            return AgentAction::MeshReady { subscribed_peers };
+        }
+
+        match tokio::time::timeout(Duration::from_millis(500), self.swarm.select_next_some()).await
+        {
+            Ok(SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(
+                gossipsub::Event::Subscribed { peer_id, .. },
+            ))) => {
+                self.subscribed_peers_count += 1;
+                AgentAction::ManagerSubscribed {
+                    peer: peer_id.to_base58(),
+                }
+            }
+            Ok(_) => AgentAction::NoOp,
+            Err(_) => AgentAction::NoOp,
+        }
+    }
+
+    async fn step_initial_broadcast(&mut self) -> AgentAction {
+        let remaining = match self.phase {
+            AgentPhase::InitialBroadcast { remaining } => remaining,
+            _ => 0,
+        };
+
+        // 3回とも同じ論理更新の再送なのでversionは最初の1回だけ進める
+        // (AgentStateCrdt側は同じversionの再送をべき等に扱う)
+        if remaining == Self::INITIAL_BROADCAST_COUNT {
+            self.position_version += 1;
+        }
+
+        if remaining == 0 {
+            self.phase = AgentPhase::Running;
+            self.phase_start = std::time::Instant::now();
+            self.last_position_broadcast = std::time::Instant::now();
+            return AgentAction::NoOp;
+        }
+
+        let Some(p) = self.my_point else {
+            self.phase = AgentPhase::Running;
+            return AgentAction::NoOp;
+        };
+
+        broadcast_agent_state(
+            &mut self.swarm,
+            &self.topic,
+            &self.local_peer_id_str,
+            p,
+            &self.my_task,
+            self.position_version,
+        );
+
+        let attempt = Self::INITIAL_BROADCAST_COUNT - remaining + 1;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        self.phase = AgentPhase::InitialBroadcast {
+            remaining: remaining - 1,
+        };
+        AgentAction::InitialPositionBroadcast {
+            attempt,
+            position: p,
+        }
+    }
+
+    async fn step_running(&mut self) -> AgentAction {
+        select! {
+            Ok(Some(line)) = self.stdin.next_line() => {
+                match self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), line.as_bytes()) {
+                    Ok(_) => AgentAction::NoOp,
+                    Err(e) => AgentAction::StdinPublishFailed(format!("{e:?}")),
+                }
+            }
+
+            _ = tokio::time::sleep(Duration::from_millis(500)), if self.last_position_broadcast.elapsed() > Duration::from_secs(1) => {
+                self.last_position_broadcast = std::time::Instant::now();
+                match self.my_point {
+                    Some(p) => {
+                        self.position_queue.push(p);
+                        AgentAction::PositionQueued(p)
+                    }
+                    None => AgentAction::NoOp,
+                }
+            }
+
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                self.flush_position_and_health()
+            }
+
+            event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+        }
+    }
+
+    // position_updateのbackpressureキューをflushし、送信が追いついているかを監視する。
+    // 詰まっていればコアレスした数を、ピアスコアと合わせて「degraded」イベントとして返す
+    fn flush_position_and_health(&mut self) -> AgentAction {
+        let flushed = self.position_queue.take().map(|p| {
+            self.position_version += 1;
+            broadcast_agent_state(
+                &mut self.swarm,
+                &self.topic,
+                &self.local_peer_id_str,
+                p,
+                &self.my_task,
+                self.position_version,
+            );
+            p
+        });
+
+        let coalesced = self.position_queue.coalesced_since_flush;
+        let queue_was_full = coalesced > 0;
+        if queue_was_full {
+            self.position_queue.coalesced_since_flush = 0;
+        }
+
+        let low_score = self
+            .manager_peer
+            .and_then(|peer| self.swarm.behaviour().gossipsub.peer_score(&peer))
+            .map(|score| score < LOW_SCORE_NOTIFY_THRESHOLD)
+            .unwrap_or(false);
+
+        let now_degraded = queue_was_full || low_score;
+        if now_degraded != self.degraded {
+            self.degraded = now_degraded;
+            if let Some(peer) = self.manager_peer {
+                let reason = if !now_degraded {
+                    "recovered".to_string()
+                } else if low_score {
+                    "gossipsub peer score below threshold".to_string()
+                } else {
+                    "outbound queue full (position_update backpressure)".to_string()
+                };
+                self.swarm.behaviour_mut().request_response.send_request(
+                    &peer,
+                    ManagerAgentRequest::AgentHealth {
+                        degraded: now_degraded,
+                        reason: reason.clone(),
+                    },
+                );
+                return AgentAction::HealthChanged {
+                    degraded: now_degraded,
+                    reason,
+                };
+            }
+        }
+
+        if queue_was_full {
+            return AgentAction::BackpressureCoalesced { count: coalesced };
+        }
+        match flushed {
+            Some(p) => AgentAction::PositionFlushed(p),
+            None => AgentAction::NoOp,
+        }
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<MapdBehaviourEvent>) -> AgentAction {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => AgentAction::Listening(address),
+            // Agent同士の接続を防ぐため、mDNS発見/expiredは定常状態でも無視する
+            SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(_))) => {
+                AgentAction::NoOp
+            }
+            SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Expired(_))) => {
+                AgentAction::NoOp
+            }
+            SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(
+                gossipsub::Event::Subscribed { peer_id, .. },
+            )) => AgentAction::ManagerSubscribed {
+                peer: peer_id.to_base58(),
+            },
+            // 位置情報以外のブロードキャストはここでは扱わない（move_instruction/Taskは
+            // 宛先固定のrequest-responseに移行済み）
+            SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                ..
+            })) => AgentAction::NoOp,
+            SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                request_response::Event::Message { peer, message },
+            )) => self.handle_request_response_message(peer, message),
+            SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                request_response::Event::OutboundFailure { peer, .. },
+            )) => AgentAction::OutboundFailure {
+                peer: peer.to_base58(),
+            },
+            SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                request_response::Event::InboundFailure { peer, .. },
+            )) => AgentAction::InboundFailure {
+                peer: peer.to_base58(),
+            },
+            _ => AgentAction::NoOp,
+        }
+    }
+
+    // マネージャーからの移動指示/タスク割り当て。宛先のエージェントだけに届くので、
+    // 以前のようにpeer_idでフィルタする必要はない
+    fn handle_request_response_message(
+        &mut self,
+        peer: libp2p::PeerId,
+        message: request_response::Message<ManagerAgentRequest, ManagerAgentResponse>,
+    ) -> AgentAction {
+        match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                // マネージャーのPeerIdを覚えておく（backpressure/スコア低下をAgentHealthで
+                // 自己申告する宛先として使う）
+                self.manager_peer.get_or_insert(peer);
+                let action = match request {
+                    ManagerAgentRequest::Move(instruction) => {
+                        let next_pos = instruction.next_pos;
+                        let from = self.my_point;
+                        self.my_point = Some(next_pos);
+                        // 移動後、新しい位置をキューに積む（送信はbackpressureキューのflushで行う）
+                        self.position_queue.push(next_pos);
+
+                        // タスク完了の判定（位置ベース）
+                        let mut completed_task_id = None;
+                        if let Some(task) = self.my_task.clone() {
+                            if next_pos == task.delivery {
+                                if let Some(task_id) = task.task_id {
+                                    self.swarm.behaviour_mut().request_response.send_request(
+                                        &peer,
+                                        ManagerAgentRequest::TaskStatus {
+                                            task_id,
+                                            status: TaskAckStatus::Completed,
+                                        },
+                                    );
+                                    completed_task_id = Some(task_id);
+                                }
+                                self.my_task = None;
+                            }
+                        }
+
+                        match completed_task_id {
+                            Some(task_id) => AgentAction::TaskCompleted { task_id },
+                            None => AgentAction::Moved { from, to: next_pos },
+                        }
+                    }
+                    ManagerAgentRequest::Task(task) => {
+                        self.my_task = Some(task.clone());
+
+                        if let Some(task_id) = task.task_id {
+                            self.swarm.behaviour_mut().request_response.send_request(
+                                &peer,
+                                ManagerAgentRequest::TaskStatus {
+                                    task_id,
+                                    status: TaskAckStatus::Received,
+                                },
+                            );
+                            self.swarm.behaviour_mut().request_response.send_request(
+                                &peer,
+                                ManagerAgentRequest::TaskStatus {
+                                    task_id,
+                                    status: TaskAckStatus::Started,
+                                },
+                            );
+                        }
+
+                        // マネージャーの指示に従って移動するため、ここでは特に何もしない
+                        // タスク完了判定はMove受信時にmy_pointをチェックして行う
+                        AgentAction::TaskReceived {
+                            task_id: task.task_id,
+                            pickup: task.pickup,
+                            delivery: task.delivery,
+                        }
+                    }
+                    // エージェントはTaskStatusを送る側であり、受け取ることは想定していない
+                    ManagerAgentRequest::TaskStatus { .. } => AgentAction::NoOp,
+                    // AgentHealthはエージェントからマネージャーへの一方向通知で、エージェント側で受け取ることはない
+                    ManagerAgentRequest::AgentHealth { .. } => AgentAction::NoOp,
+                };
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_response(channel, ManagerAgentResponse::Ack);
+                action
+            }
+            request_response::Message::Response { response, .. } => match response {
+                ManagerAgentResponse::Ack => AgentAction::NoOp,
+            },
+        }
+    }
 }
 
 #[tokio::main]
@@ -103,14 +726,54 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .build()
                 .map_err(io::Error::other)?;
 
-            let gossipsub = gossipsub::Behaviour::new(
+            let mut gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )?;
 
+            // ピアスコアリング: メッシュ滞在時間・最初に配送してきた回数を加点し、不正メッセージは
+            // 強く減点する。スコアが閾値を割ったピアはgossip/publish対象から外れる
+            let mut topic_score_params = gossipsub::TopicScoreParams::default();
+            topic_score_params.time_in_mesh_weight = 0.01;
+            topic_score_params.time_in_mesh_quantum = Duration::from_secs(1);
+            topic_score_params.time_in_mesh_cap = 3600.0;
+            topic_score_params.first_message_deliveries_weight = 1.0;
+            topic_score_params.first_message_deliveries_cap = 50.0;
+            topic_score_params.first_message_deliveries_decay = 0.9;
+            topic_score_params.invalid_message_deliveries_weight = -20.0;
+            topic_score_params.invalid_message_deliveries_decay = 0.3;
+
+            let mut score_params = gossipsub::PeerScoreParams::default();
+            score_params
+                .topics
+                .insert(gossipsub::IdentTopic::new("mapd").hash(), topic_score_params);
+
+            let score_thresholds = gossipsub::PeerScoreThresholds {
+                gossip_threshold: GOSSIP_SCORE_THRESHOLD,
+                publish_threshold: PUBLISH_SCORE_THRESHOLD,
+                graylist_threshold: GRAYLIST_SCORE_THRESHOLD,
+                ..Default::default()
+            };
+            gossipsub
+                .with_peer_score(score_params, score_thresholds)
+                .map_err(io::Error::other)?;
+
             let mdns =
                 mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MapdBehaviour { gossipsub, mdns })
+
+            // manager.rs側と対になる、移動指示/タスク割り当て/タスク状態通知用の1対1チャネル
+            let request_response = request_response::Behaviour::new(
+                [(
+                    StreamProtocol::new("/mapd/manager-agent/1.0.0"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            );
+            Ok(MapdBehaviour {
+                gossipsub,
+                mdns,
+                request_response,
+            })
         })?
         .build();
 
@@ -122,296 +785,100 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
 
-    // 初期位置決定（既存のagent.rsと同じロジック）
-    let mut my_point: Option<Point> = None;
-    let grid = parse_map();
-
     println!("[Initial Position] Agent will NOT connect to other agents via mDNS");
     println!("[Initial Position] Only Manager will discover and connect to this agent");
-    let wait_duration = Duration::from_secs(3);
-    let wait_start = std::time::Instant::now();
-
-    while wait_start.elapsed() < wait_duration {
-        let timeout = wait_duration - wait_start.elapsed();
-        match tokio::time::timeout(
-            std::cmp::min(timeout, Duration::from_millis(300)),
-            swarm.select_next_some(),
-        )
-        .await
-        {
-            Ok(event) => match event {
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(_list))) => {
-                    // Agent同士の接続を防ぐため、mDNS発見を完全に無視
-                    // Managerだけがadd_explicit_peerを使用してエージェントに接続
-                }
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("🎧 Listening on {address}");
-                }
-                _ => {}
-            },
-            Err(_) => {}
-        }
-    }
 
-    println!("[Initial Position] Waiting for Gossipsub mesh...");
-    tokio::time::sleep(Duration::from_secs(3)).await;
-
-    // 初期位置を取得（簡略化：グリッドから適当な空きセルを選択）
-    use rand::seq::SliceRandom;
-    use rand::thread_rng;
+    let grid = parse_map();
+    let mut worker = AgentWorker::new(swarm, topic, local_peer_id_str, grid);
 
-    let mut free_cells = vec![];
-    for y in 0..grid.len() {
-        for x in 0..grid[0].len() {
-            if grid[y][x] != '@' {
-                free_cells.push((x, y));
+    loop {
+        match worker.next_action().await {
+            AgentAction::Listening(address) => {
+                println!("🎧 Listening on {address}");
             }
-        }
-    }
-
-    my_point = free_cells.choose(&mut thread_rng()).cloned();
-
-    if let Some(p) = my_point {
-        println!("📍 My initial position: {:?}", p);
-        broadcast_position(&mut swarm, &topic, &local_peer_id_str, p);
-    } else {
-        println!("❌ No available position");
-        return Ok(());
-    }
-
-    println!("✅ [READY] Simple Agent is ready!");
-    println!("⏳ Waiting for peers and Gossipsub mesh formation...");
-
-    // Managerとの接続とGossipsub mesh形成を待つ
-    let discovery_start = std::time::Instant::now();
-    let discovery_duration = Duration::from_secs(8);
-    let mut subscribed_peers_count = 0;
-
-    while discovery_start.elapsed() < discovery_duration {
-        match tokio::time::timeout(Duration::from_millis(500), swarm.select_next_some()).await {
-            Ok(event) => match event {
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(_list))) => {
-                    // Agent同士の接続を防ぐため、mDNS発見を完全に無視
-                    // Managerだけがこのエージェントに接続する
-                }
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(
-                    gossipsub::Event::Subscribed { peer_id, .. },
-                )) => {
+            AgentAction::InitialPositionChosen(p) => {
+                println!("📍 My initial position: {:?}", p);
+            }
+            AgentAction::NoInitialPositionAvailable => {
+                println!("❌ No available position");
+                return Ok(());
+            }
+            AgentAction::ManagerSubscribed { peer } => {
+                println!(
+                    "🎯 [AGENT] Peer {} subscribed to topic!",
+                    &peer[..std::cmp::min(8, peer.len())]
+                );
+            }
+            AgentAction::ManagerDiscoveryTimedOut => {
+                println!(
+                    "⚠️  No subscribed peers detected after {}s, proceeding anyway...",
+                    AgentWorker::MANAGER_DISCOVERY_TIMEOUT.as_secs()
+                );
+            }
+            AgentAction::MeshReady { subscribed_peers } => {
+                if subscribed_peers > 0 {
                     println!(
-                        "🎯 [AGENT] Peer {} subscribed to topic!",
-                        &peer_id.to_base58()[..8]
+                        "✅ Found {} subscribed peers, finalizing mesh...",
+                        subscribed_peers
                     );
-                    subscribed_peers_count += 1;
                 }
-                _ => {}
-            },
-            Err(_) => {}
-        }
-
-        // 少なくとも1つのピアがsubscribeしたら、さらに1秒待ってから進む
-        if subscribed_peers_count > 0 && discovery_start.elapsed() > Duration::from_secs(4) {
-            println!(
-                "✅ Found {} subscribed peers, finalizing mesh...",
-                subscribed_peers_count
-            );
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            break;
-        }
-    }
-
-    if subscribed_peers_count == 0 {
-        println!(
-            "⚠️  No subscribed peers detected after {}s, proceeding anyway...",
-            discovery_duration.as_secs()
-        );
-    }
-
-    println!("🚀 Starting to broadcast position!");
-
-    // 初期位置をマネージャーに複数回送信（確実に届くように）
-    if let Some(p) = my_point {
-        println!("📡 Broadcasting initial position {} times...", 3);
-        for i in 0..3 {
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
-            let position_update = serde_json::json!({
-                "type": "position_update",
-                "peer_id": local_peer_id_str,
-                "position": [p.0, p.1],
-                "timestamp": timestamp
-            });
-            if i == 0 {
-                println!("📡 [DEBUG] Sending initial position: {:?}", position_update);
+                println!("✅ [READY] Simple Agent is ready!");
+                println!("🚀 Starting to broadcast position!");
             }
-            if let Ok(update_bytes) = serde_json::to_vec(&position_update) {
-                match swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(topic.clone(), update_bytes)
-                {
-                    Ok(_) => {
-                        if i == 0 {
-                            println!("✅ Sent initial position to manager: {:?}", p);
-                        } else if i % 3 == 0 {
-                            println!("📤 Retrying position broadcast ({}/10)...", i + 1);
-                        }
-                    }
-                    Err(e) => {
-                        println!("⚠️  Failed to send position (attempt {}): {:?}", i + 1, e);
-                    }
+            AgentAction::InitialPositionBroadcast { attempt, position } => {
+                if attempt == 1 {
+                    println!("✅ Sent initial position to manager: {:?}", position);
+                } else {
+                    println!(
+                        "📤 Retrying position broadcast ({}/{})...",
+                        attempt,
+                        AgentWorker::INITIAL_BROADCAST_COUNT
+                    );
                 }
             }
-            tokio::time::sleep(Duration::from_millis(500)).await; // 300ms→500ms
-        }
-        println!("✅ Initial position broadcast complete!");
-    }
-
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    let mut last_position_broadcast = std::time::Instant::now();
-    let mut my_task: Option<p2p_distributed_tswap::map::task_generator::Task> = None;
-
-    loop {
-        select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    println!("❌ Publish error: {e:?}");
-                }
+            AgentAction::StdinPublishFailed(err) => {
+                println!("❌ Publish error: {err}");
             }
-
-            _ = tokio::time::sleep(Duration::from_millis(500)), if last_position_broadcast.elapsed() > Duration::from_secs(1) => {
-                // 定期的に位置情報をマネージャーに送信（頻度を下げてネットワーク負荷削減）
-                if let Some(p) = my_point {
-                    broadcast_position(&mut swarm, &topic, &local_peer_id_str, p);
-                }
-                last_position_broadcast = std::time::Instant::now();
+            AgentAction::PositionQueued(_) | AgentAction::PositionFlushed(_) => {}
+            AgentAction::BackpressureCoalesced { count } => {
+                println!(
+                    "⚠️  [BACKPRESSURE] Coalesced {} stale position_update(s); keeping only the newest (LWW-safe)",
+                    count
+                );
             }
-
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("🎧 Listening on {address}");
-                }
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(_list))) => {
-                    // Agent同士の接続を防ぐため、mDNS発見を完全に無視
-                },
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Expired(_list))) => {
-                    // Agent同士の接続を防ぐため、mDNS expiredも無視
-                },
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
-                    println!("🔗 [AGENT] Peer {} subscribed to topic: {}", peer_id, topic);
-                    if peer_id.to_base58() != local_peer_id_str {
-                        println!("🎯 [AGENT] Manager likely connected: {}", peer_id);
-                    }
+            AgentAction::HealthChanged { degraded, reason } => {
+                println!(
+                    "📡 [AGENT] Reporting health to manager: degraded={} ({})",
+                    degraded, reason
+                );
+            }
+            AgentAction::Moved { from, to } => {
+                if Some(to) != from {
+                    println!("🚶 Moving: {:?} -> {:?}", from.unwrap_or(to), to);
                 }
-                SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
-                    if let Ok(val) = serde_json::from_slice::<serde_json::Value>(&message.data) {
-                        // マネージャーからの移動指示を受信
-                        if val.get("type") == Some(&serde_json::Value::String("move_instruction".to_string())) {
-                            if let Some(target_peer) = val.get("peer_id").and_then(|v| v.as_str()) {
-                                if target_peer == local_peer_id_str {
-                                    if let Some(next_pos_arr) = val.get("next_pos").and_then(|v| v.as_array()) {
-                                        if next_pos_arr.len() == 2 {
-                                            if let (Some(x), Some(y)) = (next_pos_arr[0].as_u64(), next_pos_arr[1].as_u64()) {
-                                                let next_pos = (x as usize, y as usize);
-                                                if Some(next_pos) != my_point {
-                                                    println!("🚶 Moving: {:?} -> {:?}", my_point.unwrap(), next_pos);
-                                                }
-                                                my_point = Some(next_pos);
-                                                // 移動後、即座に新しい位置をマネージャーに通知
-                                                broadcast_position(&mut swarm, &topic, &local_peer_id_str, next_pos);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        // タスク受信
-                        if let Ok(task) = serde_json::from_slice::<p2p_distributed_tswap::map::task_generator::Task>(&message.data) {
-                            if let Some(ref peer_id) = task.peer_id {
-                                if peer_id != &local_peer_id_str {
-                                    continue;
-                                }
-                            } else {
-                                continue;
-                            }
-
-                            println!("=========================");
-                            println!("📦 [TASK RECEIVED] Task ID: {:?}", task.task_id);
-                            println!("   Pickup: {:?} -> Delivery: {:?}", task.pickup, task.delivery);
-                            println!("   Waiting for manager's instructions...");
-                            println!("=========================");
-
-                            my_task = Some(task.clone());
-
-                            // タスク受信メトリクス
-                            if let Some(task_id) = task.task_id {
-                                let now_ms = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_millis() as u64;
-                                let metric_msg = serde_json::json!({
-                                    "type": "task_metric_received",
-                                    "task_id": task_id,
-                                    "peer_id": local_peer_id_str,
-                                    "timestamp_ms": now_ms
-                                }).to_string();
-                                let _ = swarm.behaviour_mut().gossipsub.publish(topic.clone(), metric_msg.as_bytes());
-
-                                // タスク開始メトリクス
-                                let metric_msg = serde_json::json!({
-                                    "type": "task_metric_started",
-                                    "task_id": task_id,
-                                    "peer_id": local_peer_id_str,
-                                    "timestamp_ms": now_ms
-                                }).to_string();
-                                let _ = swarm.behaviour_mut().gossipsub.publish(topic.clone(), metric_msg.as_bytes());
-                            }
-
-                            // マネージャーの指示に従って移動するため、ここでは特に何もしない
-                            // タスク完了判定は後でmy_pointをチェックして行う
-                        }
-
-                        // タスク完了の判定（位置ベース）
-                        if let (Some(current_pos), Some(task)) = (my_point, my_task.as_ref()) {
-                            if current_pos == task.delivery {
-                                println!("🎉 [TASK COMPLETE] Reached delivery point!");
-
-                                if let Some(task_id) = task.task_id {
-                                    let now_ms = std::time::SystemTime::now()
-                                        .duration_since(std::time::UNIX_EPOCH)
-                                        .unwrap()
-                                        .as_millis() as u64;
-                                    let metric_msg = serde_json::json!({
-                                        "type": "task_metric_completed",
-                                        "task_id": task_id,
-                                        "peer_id": local_peer_id_str,
-                                        "timestamp_ms": now_ms
-                                    }).to_string();
-                                    let _ = swarm.behaviour_mut().gossipsub.publish(topic.clone(), metric_msg.as_bytes());
-
-                                    let done_json = serde_json::json!({
-                                        "status": "done",
-                                        "task_id": task_id
-                                    }).to_string();
-
-                                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), done_json.as_bytes()) {
-                                        println!("❌ Failed to send completion: {e:?}");
-                                    } else {
-                                        println!("✅ Task completion notification sent");
-                                    }
-                                }
-
-                                my_task = None;
-                            }
-                        }
-                    }
-                },
-                _ => {}
             }
+            AgentAction::TaskReceived {
+                task_id,
+                pickup,
+                delivery,
+            } => {
+                println!("=========================");
+                println!("📦 [TASK RECEIVED] Task ID: {:?}", task_id);
+                println!("   Pickup: {:?} -> Delivery: {:?}", pickup, delivery);
+                println!("   Waiting for manager's instructions...");
+                println!("=========================");
+            }
+            AgentAction::TaskCompleted { task_id } => {
+                println!("🎉 [TASK COMPLETE] Reached delivery point!");
+                println!("✅ Task {} completion notification sent", task_id);
+            }
+            AgentAction::OutboundFailure { peer } => {
+                println!("⚠️  [AGENT] request-response to {} failed", peer);
+            }
+            AgentAction::InboundFailure { peer } => {
+                println!("⚠️  [AGENT] inbound request from {} failed", peer);
+            }
+            AgentAction::NoOp => {}
         }
     }
 }
@@ -1,22 +1,578 @@
+use async_trait::async_trait;
+use futures::prelude::*;
 use futures::stream::StreamExt;
 use libp2p::{
-    gossipsub, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    core::multiaddr::Protocol,
+    gossipsub, kad, mdns, noise, rendezvous,
+    request_response::{self, ProtocolSupport},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol,
 };
+use p2p_distributed_tswap::map::bloom_filter::BloomFilter;
 use p2p_distributed_tswap::map::map::MAP;
 use p2p_distributed_tswap::map::task_generator::{Task, TaskGeneratorAgent};
 use p2p_distributed_tswap::map::task_metrics::{
     PathComputationMetrics, TaskMetric, TaskMetricsCollector,
 };
+use p2p_distributed_tswap::map::task_store::TaskStore;
+use serde::{Deserialize, Serialize};
+use snap::raw::{Decoder, Encoder};
 
 use std::collections::HashMap;
-use std::collections::{hash_map::DefaultHasher, HashSet};
+use std::collections::{hash_map::DefaultHasher, HashSet, VecDeque};
 use std::error::Error;
 use std::hash::{Hash, Hasher};
+use std::io::Error as IoError;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc;
 use tokio::{io, io::AsyncBufReadExt, select};
+
+/// タスクライフサイクルの1イベント。gossipsubの`task_metric_*`/`done`メッセージに対応する。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum MetricEvent {
+    Received,
+    Started,
+    Completed,
+}
+
+/// Bloomフィルタによるpull/push anti-entropyでやり取りする最小のログ単位。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MetricLogEntry {
+    task_id: u64,
+    event: MetricEvent,
+    timestamp_ms: u64,
+}
+
+impl MetricLogEntry {
+    fn key(&self) -> String {
+        format!("{}:{:?}", self.task_id, self.event)
+    }
+}
+
+/// 優先度つき送信キュー(rust-libp2p PR #4914のgossipsub backpressureモデルを踏襲)。
+/// occupied_responseは`Priority`としてキューが溢れても捨てず、位置情報のような
+/// 高頻度・低価値な放送(`NonPriority`)だけを輻輳時に古いものから間引く。これにより、
+/// エージェントが大量にposition_updateを流す混雑したメッシュでもコントロールプレーンの
+/// トラフィックが詰まらない。
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DispatchPriority {
+    Priority,
+    NonPriority,
+}
+
+struct OutboundMessage {
+    priority: DispatchPriority,
+    data: Vec<u8>,
+}
+
+struct OutboundDispatcher {
+    priority_queue: VecDeque<OutboundMessage>,
+    non_priority_queue: VecDeque<OutboundMessage>,
+    capacity: usize,
+    // `metrics`コマンドで表示する、輻輳により間引かれた非優先メッセージの累計数
+    dropped_non_priority: u64,
+}
+
+impl OutboundDispatcher {
+    fn new(capacity: usize) -> Self {
+        Self {
+            priority_queue: VecDeque::new(),
+            non_priority_queue: VecDeque::new(),
+            capacity,
+            dropped_non_priority: 0,
+        }
+    }
+
+    fn enqueue(&mut self, msg: OutboundMessage) {
+        match msg.priority {
+            // Priorityは容量を無視してでも必ず送出側に残す(タスク割当/ACKを見失わないため)
+            DispatchPriority::Priority => self.priority_queue.push_back(msg),
+            DispatchPriority::NonPriority => {
+                if self.non_priority_queue.len() >= self.capacity {
+                    self.non_priority_queue.pop_front();
+                    self.dropped_non_priority += 1;
+                }
+                self.non_priority_queue.push_back(msg);
+            }
+        }
+    }
+
+    /// 優先メッセージを常に非優先より先に取り出す
+    fn pop_next(&mut self) -> Option<OutboundMessage> {
+        self.priority_queue
+            .pop_front()
+            .or_else(|| self.non_priority_queue.pop_front())
+    }
+}
+
+/// WebSocket監視クライアントへ送る1件の割り当てのスナップショット
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MonitorAssignment {
+    task_id: u64,
+    peer_id: String,
+}
+
+/// 接続直後のWebSocketクライアントへ送る、その時点の全割り当ての一覧
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct MonitorCheckpoint {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    assignments: Vec<MonitorAssignment>,
+}
+
+impl MonitorCheckpoint {
+    fn new(assignments: Vec<MonitorAssignment>) -> Self {
+        Self {
+            kind: "checkpoint",
+            assignments,
+        }
+    }
+}
+
+/// dispatch/completion/timeout/reassignのたびにsubscribe済みクライアントへ流す差分イベント
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MonitorEvent {
+    Dispatched { task_id: u64, peer_id: String },
+    Completed { task_id: u64, peer_id: String },
+    TimedOut { task_id: u64, peer_id: String },
+    Reassigned { task_id: u64, from_peer: String, to_peer: String },
+}
+
+/// クライアントから受け取るJSON制御フレーム。`{"command":"subscribe"}` / `{"command":"unsubscribe"}`
+#[derive(Clone, Debug, Deserialize)]
+struct MonitorCommand {
+    command: String,
+}
+
+/// 現在の全割り当て。WebSocketサーバタスクと`Manager`のイベントループで共有し、新規接続時の
+/// チェックポイント配信に使う（差分自体は`monitor_tx`のbroadcastで流れる）
+type MonitorState = Arc<std::sync::Mutex<Vec<MonitorAssignment>>>;
+
+/// `state`の現在値をチェックポイントとして送り、以後は`unsubscribe`が来るかソケットが
+/// 切れるまで`rx`から受け取った差分イベントを転送し続ける、monitor WebSocketの1接続分の処理。
+/// solanaのmango-fillsサービスと同様、subscribe/unsubscribeはコネクションを張ったまま
+/// JSON制御フレームで切り替える方式にしている(接続のたびに張り直す必要がない)
+async fn handle_monitor_connection(
+    stream: tokio::net::TcpStream,
+    state: MonitorState,
+    mut rx: tokio::sync::broadcast::Receiver<MonitorEvent>,
+) {
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            println!("⚠️  Monitor WebSocket handshake failed: {e:?}");
+            return;
+        }
+    };
+    let (mut sink, mut stream) = ws_stream.split();
+    let mut subscribed = false;
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                match msg {
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                        match serde_json::from_str::<MonitorCommand>(&text) {
+                            Ok(cmd) if cmd.command == "subscribe" => {
+                                subscribed = true;
+                                let checkpoint = MonitorCheckpoint::new(state.lock().unwrap().clone());
+                                if let Ok(payload) = serde_json::to_string(&checkpoint) {
+                                    if sink.send(tokio_tungstenite::tungstenite::Message::Text(payload)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                            Ok(cmd) if cmd.command == "unsubscribe" => subscribed = false,
+                            _ => println!("⚠️  Ignoring unrecognized monitor control frame: {text}"),
+                        }
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) | None => break,
+                    Some(Err(_)) => break,
+                    _ => {}
+                }
+            }
+            event = rx.recv(), if subscribed => {
+                match event {
+                    Ok(event) => {
+                        if let Ok(payload) = serde_json::to_string(&event) {
+                            if sink.send(tokio_tungstenite::tungstenite::Message::Text(payload)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    // 配信が追いつかずoldestが落とされた場合は、次のsubscribeでチェックポイントから
+                    // 再同期してもらう想定でそのまま継続する
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+/// `--monitor-addr`で指定されたアドレスでWebSocket監視サーバを待ち受ける。接続のたびに
+/// `handle_monitor_connection`をspawnする、`serve_prometheus_text`と同じ最小実装
+async fn serve_monitor_ws(
+    addr: String,
+    state: MonitorState,
+    tx: tokio::sync::broadcast::Sender<MonitorEvent>,
+) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("⚠️  Failed to bind monitor WebSocket endpoint on {addr}: {e}");
+            return;
+        }
+    };
+    println!("🔭 Monitor WebSocket available at ws://{addr}");
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        tokio::spawn(handle_monitor_connection(stream, state.clone(), tx.subscribe()));
+    }
+}
+
+// gossipsubペイロードの先頭1バイトに付けるコーデックタグ。正規のJSONは常に'{'(0x7B)か
+// '['(0x5B)で始まるため、0/1のどちらとも衝突せず、タグなしの旧フォーマットと共存できる。
+const CODEC_TAG_RAW: u8 = 0;
+const CODEC_TAG_SNAPPY: u8 = 1;
+// これより小さいメッセージはsnappyのヘッダ分のオーバーヘッドの方が大きくなるので圧縮しない
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// 大きめのgossipsubペイロード(タスク本体、occupied_responseの全エージェント位置リスト等)を
+/// snappyで圧縮し、先頭にコーデックタグを付けて送る。小さい制御メッセージはタグだけ付けて
+/// 生のまま送る(圧縮のオーバーヘッドが割に合わないため)。
+fn encode_payload(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() < COMPRESSION_THRESHOLD {
+        let mut out = Vec::with_capacity(bytes.len() + 1);
+        out.push(CODEC_TAG_RAW);
+        out.extend_from_slice(bytes);
+        return out;
+    }
+    match Encoder::new().compress_vec(bytes) {
+        Ok(compressed) if compressed.len() + 1 < bytes.len() + 1 => {
+            let mut out = Vec::with_capacity(compressed.len() + 1);
+            out.push(CODEC_TAG_SNAPPY);
+            out.extend_from_slice(&compressed);
+            out
+        }
+        _ => {
+            let mut out = Vec::with_capacity(bytes.len() + 1);
+            out.push(CODEC_TAG_RAW);
+            out.extend_from_slice(bytes);
+            out
+        }
+    }
+}
+
+/// `encode_payload`の逆変換。先頭バイトがタグでなければ(=0/1以外なら)、このチャンク以前の
+/// 旧ピアが送った生JSONとみなしてそのまま返す(後方互換)。
+fn decode_payload(data: &[u8]) -> Vec<u8> {
+    match data.first() {
+        Some(&CODEC_TAG_RAW) => data[1..].to_vec(),
+        Some(&CODEC_TAG_SNAPPY) => Decoder::new().decompress_vec(&data[1..]).unwrap_or_default(),
+        _ => data.to_vec(),
+    }
+}
+
+// occupied_request/position_update/task_metric_*/path_metric/doneの既知フォーマットを検証し、
+// Accept/Reject/Ignoreを判定する(agent.rsのvalidate_messages()運用に合わせる)。
+// JSONとして壊れている、またはグリッド範囲外の座標はReject、未知のtype/statusはIgnoreとする。
+/// `task_id`を参照するメッセージ(task_metric_*/done)の裁定。`task_peer_map`にまだ載っていて
+/// 送信者がその担当ピア自身ならAccept、誰か別人の`task_id`を騙っていればReject（偽装/誤配送）、
+/// `task_peer_map`には無いが過去に見た`task_id`（完了/再割り当て済み）ならIgnore（重複通知）、
+/// 一度も存在しない`task_id`ならReject（でっち上げ）とする
+fn classify_task_reference(
+    task_id: u64,
+    peer_id: &libp2p::PeerId,
+    task_peer_map: &HashMap<u64, (libp2p::PeerId, request_response::OutboundRequestId)>,
+    metrics_collector: &TaskMetricsCollector,
+) -> gossipsub::MessageAcceptance {
+    match task_peer_map.get(&task_id) {
+        Some((owner, _)) if owner == peer_id => gossipsub::MessageAcceptance::Accept,
+        Some(_) => gossipsub::MessageAcceptance::Reject,
+        None if metrics_collector.metrics.contains_key(&task_id) => gossipsub::MessageAcceptance::Ignore,
+        None => gossipsub::MessageAcceptance::Reject,
+    }
+}
+
+fn classify_gossip_message(
+    val: &serde_json::Value,
+    grid: &[Vec<char>],
+    peer_id: &libp2p::PeerId,
+    task_peer_map: &HashMap<u64, (libp2p::PeerId, request_response::OutboundRequestId)>,
+    metrics_collector: &TaskMetricsCollector,
+) -> gossipsub::MessageAcceptance {
+    let in_bounds_free = |x: u64, y: u64| -> bool {
+        grid.get(y as usize)
+            .and_then(|row| row.get(x as usize))
+            .is_some_and(|&c| c == '.')
+    };
+
+    if let Some(msg_type) = val.get("type").and_then(|v| v.as_str()) {
+        return match msg_type {
+            "occupied_request" => gossipsub::MessageAcceptance::Accept,
+            "position_update" => {
+                let pos = val.get("position").and_then(|v| v.as_array());
+                match (val.get("peer_id").and_then(|v| v.as_str()), pos) {
+                    (Some(_), Some(pos)) if pos.len() == 2 => {
+                        match (pos[0].as_u64(), pos[1].as_u64()) {
+                            (Some(x), Some(y)) if in_bounds_free(x, y) => {
+                                gossipsub::MessageAcceptance::Accept
+                            }
+                            _ => gossipsub::MessageAcceptance::Reject,
+                        }
+                    }
+                    _ => gossipsub::MessageAcceptance::Reject,
+                }
+            }
+            "task_metric_received" | "task_metric_started" | "task_metric_completed" => {
+                match val.get("task_id").and_then(|v| v.as_u64()) {
+                    Some(task_id) => classify_task_reference(task_id, peer_id, task_peer_map, metrics_collector),
+                    None => gossipsub::MessageAcceptance::Reject,
+                }
+            }
+            "path_metric" => {
+                if val.get("duration_micros").and_then(|v| v.as_u64()).is_some() {
+                    gossipsub::MessageAcceptance::Accept
+                } else {
+                    gossipsub::MessageAcceptance::Reject
+                }
+            }
+            _ => gossipsub::MessageAcceptance::Ignore,
+        };
+    }
+
+    if val.get("status") == Some(&serde_json::Value::String("done".to_string())) {
+        return match val.get("task_id").and_then(|v| v.as_u64()) {
+            Some(task_id) => classify_task_reference(task_id, peer_id, task_peer_map, metrics_collector),
+            None => gossipsub::MessageAcceptance::Accept,
+        };
+    }
+
+    gossipsub::MessageAcceptance::Ignore
+}
+
+// mesh_n=2という小さいメッシュを1体のノイジー/不正なエージェントから守るための
+// グレーリスト閾値。gossipsubのPeerScoreThresholds.graylist_thresholdと揃えて使う
+const GRAYLIST_SCORE_THRESHOLD: f64 = -80.0;
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// gossipsubでの`task_metric_*`/`done`通知は、マネージャーが落ちていたりメッシュが
+// 未形成の瞬間には失われたまま二度と届かない。CRDS(Solana cluster_info)方式のpull
+// アンチエントロピーを補助回線として設け、直近のイベントログをBloomフィルタで要約して
+// 相手に送り、相手に「自分が持っていなさそうなもの」だけを送り返してもらうことで、
+// 取りこぼしを後から埋め合わせる。
+struct MetricAntiEntropyLog {
+    entries: VecDeque<MetricLogEntry>,
+    seen_keys: HashSet<String>,
+    capacity: usize,
+}
+
+impl MetricAntiEntropyLog {
+    fn new(capacity: usize) -> Self {
+        MetricAntiEntropyLog {
+            entries: VecDeque::new(),
+            seen_keys: HashSet::new(),
+            capacity,
+        }
+    }
+
+    /// 初見のイベントならログに記録してtrueを返す。既知なら何もせずfalseを返す
+    /// （gossipsub経由とpull経由で同じイベントが二重に届いても冪等に扱える）。
+    fn record_entry(&mut self, entry: MetricLogEntry) -> bool {
+        if !self.seen_keys.insert(entry.key()) {
+            return false;
+        }
+        self.entries.push_back(entry);
+        if self.entries.len() > self.capacity {
+            if let Some(old) = self.entries.pop_front() {
+                self.seen_keys.remove(&old.key());
+            }
+        }
+        true
+    }
+
+    fn record(&mut self, task_id: u64, event: MetricEvent) -> bool {
+        self.record_entry(MetricLogEntry {
+            task_id,
+            event,
+            timestamp_ms: now_ms(),
+        })
+    }
+
+    /// 自分が持っているエントリを表すBloomフィルタを構築する（pullリクエストに添える）
+    fn build_filter(&self) -> BloomFilter {
+        let num_bits = (self.entries.len().max(1) * 16).next_power_of_two();
+        let mut filter = BloomFilter::new(num_bits, 3);
+        for entry in &self.entries {
+            filter.insert(&entry.key());
+        }
+        filter
+    }
+
+    /// 相手のフィルタに含まれていない(=相手が持っていなさそうな)エントリだけを返す。
+    fn entries_missing_from(&self, filter: &BloomFilter) -> Vec<MetricLogEntry> {
+        self.entries
+            .iter()
+            .filter(|e| !filter.might_contain(&e.key()))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum MetricsAntiEntropyRequest {
+    Pull(BloomFilter),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum MetricsAntiEntropyResponse {
+    Push(Vec<MetricLogEntry>),
+}
+
+// request_response::Codec実装: JSON行をそのままストリームに流す
+#[derive(Clone, Default)]
+struct MetricsAntiEntropyCodec;
+
+#[async_trait]
+impl request_response::Codec for MetricsAntiEntropyCodec {
+    type Protocol = StreamProtocol;
+    type Request = MetricsAntiEntropyRequest;
+    type Response = MetricsAntiEntropyResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+}
+// タスク本体をgossipsubの全員フィルタ方式で撒くのをやめ、宛先の1ピアとだけ
+// request-responseでやり取りする。送達確認(TaskAck)が取れて初めてそのピアをbusyにする
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TaskRequest {
+    task_id: u64,
+    payload: Task,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TaskAck {
+    task_id: u64,
+    accepted: bool,
+}
+
+// request_response::Codec実装: JSON行をそのままストリームに流す
+#[derive(Clone, Default)]
+struct TaskDispatchCodec;
+
+#[async_trait]
+impl request_response::Codec for TaskDispatchCodec {
+    type Protocol = StreamProtocol;
+    type Request = TaskRequest;
+    type Response = TaskAck;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(IoError::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(IoError::other)?;
+        io.write_all(&bytes).await
+    }
+}
+
 fn parse_map() -> Vec<Vec<char>> {
     let grid = MAP
         .replace('\r', "")
@@ -36,312 +592,636 @@ fn parse_map() -> Vec<Vec<char>> {
 #[derive(NetworkBehaviour)]
 struct MapdBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    // `--no-mdns`時はOffにする。multicastが届かないネットワーク間でも動かせるようにするため
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    // mDNSがOffの間だけ有効にする代替ディスカバリ。`--bootstrap`で与えたピアを起点に広げる
+    kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    metrics_anti_entropy: request_response::Behaviour<MetricsAntiEntropyCodec>,
+    // タスクの宛先ピアは`task.peer_id`で1つに決まっているのに全員へgossipsub broadcastしていたのを
+    // やめ、宛先1ピアだけに`TaskRequest`/`TaskAck`のrequest-responseで届ける
+    task_dispatch: request_response::Behaviour<TaskDispatchCodec>,
+    // `--rendezvous`未指定なら丸ごとOffにできる、静的bootstrapに頼らないワーカー発見の代替経路
+    rendezvous: Toggle<rendezvous::client::Behaviour>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Check for --clean flag to ignore mDNS discoveries
-    let args: Vec<String> = std::env::args().collect();
-    let ignore_mdns = args.contains(&"--clean".to_string());
+/// `/p2p/<PeerId>`の形で終わるマルチアドレスからPeerIdを取り出す（`--bootstrap`用）
+fn peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|p| match p {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
 
-    if ignore_mdns {
-        println!("🧹 Running in CLEAN mode - ignoring mDNS discoveries");
-    }
+#[derive(Debug)]
+enum ManagerCommand {
+    GenerateTasks(usize),
+    GenerateTask,
+    Metrics,
+    SaveMetrics { path: String },
+    SavePath { path: String },
+    Reset,
+    Broadcast(String),
+}
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| {
-            let message_id_fn = |message: &gossipsub::Message| {
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
-            };
-
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_millis(500)) // Heartbeat every 500ms
-                .heartbeat_initial_delay(Duration::from_millis(100)) // Initial heartbeat after 100ms (immediate mesh construction)
-                .mesh_n_low(1) // Minimum mesh peers set to 1 (default 4)
-                .mesh_n(2) // Target mesh peers set to 2 (default 6)
-                .mesh_n_high(3) // Maximum mesh peers set to 3 (default 12)
-                .validation_mode(gossipsub::ValidationMode::Permissive)
-                .message_id_fn(message_id_fn)
-                .history_length(5)  // メッセージ履歴を5に制限（デフォルト5だが明示）
-                .history_gossip(3)  // Gossip履歴を3に制限（デフォルト3だが明示）
-                .max_transmit_size(1_048_576)  // 最大送信サイズを1MBに制限
-                .build()
-                .map_err(io::Error::other)?;
-
-            let gossipsub = gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(key.clone()),
-                gossipsub_config,
-            )?;
-
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MapdBehaviour { gossipsub, mdns })
-        })?
-        .build();
-
-    let topic = gossipsub::IdentTopic::new("mapd");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
-    println!("Peer ID: {}", swarm.local_peer_id());
-
-    // Create grid (pass appropriate grid in actual use)
-    let grid = Arc::new(parse_map());
-    let mut task_gen = TaskGeneratorAgent::new(&grid);
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+/// `next_event`が呼び出し元に通知する、1ターン分の処理結果。stdinパース・swarmイベント・
+/// タイマーが単一の巨大な`select!`に混在していると、どれかの枝が同期処理で詰まった際に
+/// 他の枝が飢餓を起こしうる。manager.rsの`ManagerWorker::next_action()`に倣い、1回の呼び出しで
+/// 高々1つのコマンド/イベントだけを処理してから制御を返す。
+#[derive(Debug)]
+enum ManagerEvent {
+    TaskAssigned { peer: libp2p::PeerId, task_id: u64 },
+    TaskCompleted { peer: libp2p::PeerId, task_id: Option<u64> },
+    // タイムアウトまたは担当ピアの切断を検知し、別ピアへ再配布した（誰も空いていなければ断念した）
+    TaskReassigned { peer: libp2p::PeerId, task_id: u64 },
+    PeerDiscovered(libp2p::PeerId),
+    PeerExpired(libp2p::PeerId),
+    PeerGraylisted(libp2p::PeerId),
+    Idle,
+}
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+/// manager本体が持つ全ての状態（swarmとタスク台帳）を1箇所にまとめたワーカー。
+/// `main`はこれを生成して`next_event`をループで呼ぶだけになり、stdinは`ManagerCommand`を
+/// 送る薄いクライアントになるので、GUIやテストハーネストがREPLを介さずこの構造体を
+/// 直接インプロセスで駆動できる。
+struct Manager {
+    swarm: libp2p::Swarm<MapdBehaviour>,
+    topic: gossipsub::IdentTopic,
+    grid: Arc<Vec<Vec<char>>>,
+    ignore_mdns: bool,
+    known_peers: HashSet<libp2p::PeerId>,
+    subscribed_peers: HashSet<libp2p::PeerId>,
+    peer_task_map: HashMap<libp2p::PeerId, Option<Task>>,
+    task_peer_map: HashMap<u64, (libp2p::PeerId, request_response::OutboundRequestId)>,
+    // TaskAck/OutboundFailureが来た時に元のタスク内容へ戻れるようにするための相関マップ
+    outstanding_tasks: HashMap<request_response::OutboundRequestId, (u64, Task)>,
+    // 現在誰かに割り当て中（Ack待ちも含む）の全タスクの実体。タイムアウト/切断検知での
+    // 再配布に使う。完了/恒久的な失敗で取り除かれる
+    task_registry: HashMap<u64, (libp2p::PeerId, Task)>,
+    task_deadlines: HashMap<u64, std::time::Instant>,
+    task_timeout: Duration,
+    timeout_check_tick: tokio::time::Interval,
+    // 進行中の割り当て/メトリクスをディスクへwrite-throughし、クラッシュ後も再開できるようにする
+    task_store: TaskStore,
+    // `--rendezvous`が指定された時だけSome。接続確立後にそのピアへ`register`/`discover`する
+    rendezvous_peer: Option<PeerId>,
+    rendezvous_namespace: rendezvous::Namespace,
+    rendezvous_cookie: Option<rendezvous::Cookie>,
+    rendezvous_discover_tick: tokio::time::Interval,
+    task_counter: u64,
+    peer_positions: HashMap<String, (usize, usize)>,
+    metrics_collector: TaskMetricsCollector,
+    path_metrics: PathComputationMetrics,
+    metric_log: MetricAntiEntropyLog,
+    last_metrics_pull: std::time::Instant,
+    metrics_pull_interval: Duration,
+    dispatcher: OutboundDispatcher,
+    dispatch_tick: tokio::time::Interval,
+    score_check_tick: tokio::time::Interval,
+    // `--monitor-addr`のWebSocketサーバと共有する、現在の全割り当てのスナップショット
+    monitor_state: MonitorState,
+    // dispatch/completion/timeout/reassignを監視クライアントへ配信するbroadcastチャネル
+    monitor_tx: tokio::sync::broadcast::Sender<MonitorEvent>,
+    cmd_rx: mpsc::Receiver<ManagerCommand>,
+}
 
-    println!("✅ Manager started fresh!");
-    println!("⏳ Clearing any cached peer information...");
+impl Manager {
+    async fn new(
+        ignore_mdns: bool,
+        no_mdns: bool,
+        bootstrap: Vec<Multiaddr>,
+        db_path: String,
+        rendezvous_addr: Option<Multiaddr>,
+        rendezvous_namespace: String,
+        monitor_addr: Option<String>,
+        cmd_rx: mpsc::Receiver<ManagerCommand>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_behaviour(|key| {
+                let message_id_fn = |message: &gossipsub::Message| {
+                    let mut s = DefaultHasher::new();
+                    message.data.hash(&mut s);
+                    gossipsub::MessageId::from(s.finish().to_string())
+                };
 
-    // Give a brief moment to ensure clean state
-    tokio::time::sleep(Duration::from_millis(500)).await;
+                let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(Duration::from_millis(500)) // Heartbeat every 500ms
+                    .heartbeat_initial_delay(Duration::from_millis(100)) // Initial heartbeat after 100ms (immediate mesh construction)
+                    .mesh_n_low(1) // Minimum mesh peers set to 1 (default 4)
+                    .mesh_n(2) // Target mesh peers set to 2 (default 6)
+                    .mesh_n_high(3) // Maximum mesh peers set to 3 (default 12)
+                    .validation_mode(gossipsub::ValidationMode::Permissive)
+                    .validate_messages() // JSONパース/グリッド範囲を見てAccept/Reject/Ignoreを手動報告するため
+                    .message_id_fn(message_id_fn)
+                    .history_length(5)  // メッセージ履歴を5に制限（デフォルト5だが明示）
+                    .history_gossip(3)  // Gossip履歴を3に制限（デフォルト3だが明示）
+                    .max_transmit_size(1_048_576)  // 最大送信サイズを1MBに制限
+                    .build()
+                    .map_err(io::Error::other)?;
 
-    println!("Enter messages via STDIN and they will be sent to connected peers using MAPD topic");
-    println!("Type 'task' to generate and send a task to agents.");
-    println!(
-        "Use 'metrics' for summary stats, 'save <filename>' for task metrics CSV, and 'save path <filename>' for path computation CSV."
-    );
-    println!(
-        "⚠️  IMPORTANT: Wait 3-5 seconds after all agents connect before sending tasks (for Gossipsub mesh to form)!"
-    );
-    println!(
-        "💡 TIP: Look for '🔗 Peer XXX subscribed to topic: mapd' messages to confirm mesh is ready!"
-    );
-    println!("⏳ Waiting 1 second for initial Gossipsub mesh setup...");
-
-    // Wait for Gossipsub mesh initialization
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    println!("✅ Manager ready! Listening for agents...");
-
-    // Management variables
-    let mut known_peers: HashSet<libp2p::PeerId> = HashSet::new();
-    // Peers subscribed to topic (joined Gossipsub mesh)
-    let mut subscribed_peers: HashSet<libp2p::PeerId> = HashSet::new();
-    // Task in progress for each peer: peer_id -> Option<Task>
-    let mut peer_task_map: HashMap<libp2p::PeerId, Option<Task>> = HashMap::new();
-    // Task ID to peer mapping: task_id -> peer_id
-    let mut task_peer_map: HashMap<u64, libp2p::PeerId> = HashMap::new();
-    // Task generation counter
-    let mut task_counter: u64 = 0;
-    // Track current position of each agent: peer_id -> (x, y)
-    let mut peer_positions: HashMap<String, (usize, usize)> = HashMap::new();
-
-    // === Task Metrics Collection ===
-    let mut metrics_collector = TaskMetricsCollector::new();
-    let mut path_metrics = PathComputationMetrics::new();
-    println!("📊 Task metrics collection initialized");
-    println!("⏱️ Path computation metrics collection initialized");
+                let mut gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?;
 
-    loop {
-        select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                let trimmed = line.trim();
-
-                // メトリクス表示コマンド
-                if trimmed == "metrics" {
-                    let stats = metrics_collector.get_statistics();
-                    println!("{}", stats);
-                    if let Some(path_stats) = path_metrics.get_statistics() {
-                        println!("{}", path_stats);
-                    } else {
-                        println!("⏱️ Path Computation: no samples yet");
-                    }
-                    continue;
-                }
+                // 不正/重複メッセージを繰り返す、あるいはmDNS発見後に一向にsubscribeしない
+                // エージェントを自動でグレーリストするためのスコアリング
+                let peer_score_thresholds = gossipsub::PeerScoreThresholds {
+                    gossip_threshold: -10.0,
+                    publish_threshold: -50.0,
+                    graylist_threshold: GRAYLIST_SCORE_THRESHOLD,
+                    ..Default::default()
+                };
+                gossipsub
+                    .with_peer_score(gossipsub::PeerScoreParams::default(), peer_score_thresholds)
+                    .map_err(io::Error::other)?;
 
-                // Peerをリセットするコマンド
-                if trimmed == "reset" {
-                    known_peers.clear();
-                    subscribed_peers.clear();
-                    peer_task_map.clear();
-                    task_peer_map.clear();
-                    peer_positions.clear();
-                    metrics_collector = TaskMetricsCollector::new();
-                    task_counter = 0;
-                    path_metrics.clear();
-                    println!("✅ All peers and state cleared. Ready for fresh start!");
-                    continue;
+                // multicastが届かない環境向けに`--no-mdns`でOffにできるようにする
+                let mdns = if no_mdns {
+                    Toggle::from(None)
+                } else {
+                    Toggle::from(Some(mdns::tokio::Behaviour::new(
+                        mdns::Config::default(),
+                        key.public().to_peer_id(),
+                    )?))
+                };
+
+                // mDNSがOffの間は`--bootstrap`ピアを起点にしたKademliaでディスカバリを代替する
+                let kad = if no_mdns {
+                    let mut kad = kad::Behaviour::new(
+                        key.public().to_peer_id(),
+                        kad::store::MemoryStore::new(key.public().to_peer_id()),
+                    );
+                    kad.set_mode(Some(kad::Mode::Server));
+                    Toggle::from(Some(kad))
+                } else {
+                    Toggle::from(None)
+                };
+
+                // task_metric_*/doneのgossipsub取りこぼしを埋め合わせるpullアンチエントロピー用チャネル
+                let metrics_anti_entropy = request_response::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/mapd/metrics-pull/1.0.0"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                let task_dispatch = request_response::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/mapd/task-dispatch/1.0.0"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                // `--rendezvous`で与えられた場合だけ有効にする、静的`--bootstrap`の代替発見経路
+                let rendezvous = if rendezvous_addr.is_some() {
+                    Toggle::from(Some(rendezvous::client::Behaviour::new(key.clone())))
+                } else {
+                    Toggle::from(None)
+                };
+
+                Ok(MapdBehaviour {
+                    gossipsub,
+                    mdns,
+                    kad,
+                    metrics_anti_entropy,
+                    task_dispatch,
+                    rendezvous,
+                })
+            })?
+            .build();
+
+        let topic = gossipsub::IdentTopic::new("mapd");
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        println!("Peer ID: {}", swarm.local_peer_id());
+
+        // Create grid (pass appropriate grid in actual use)
+        let grid = Arc::new(parse_map());
+
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+
+        // `--bootstrap`で渡された既知ピアに直接ダイヤルし、mDNSが届かないネットワーク間でも
+        // メッシュに参加できるようにする
+        for addr in &bootstrap {
+            match swarm.dial(addr.clone()) {
+                Ok(()) => println!("📡 Dialing bootstrap peer: {addr}"),
+                Err(e) => println!("⚠️  Failed to dial bootstrap peer {addr}: {e:?}"),
+            }
+            if let Some(peer_id) = peer_id_from_multiaddr(addr) {
+                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                    kad.add_address(&peer_id, addr.clone());
                 }
+            } else {
+                println!("⚠️  Bootstrap address {addr} has no /p2p/<PeerId> suffix, skipping add_explicit_peer");
+            }
+        }
 
-                // CSV保存コマンド
-                if trimmed.starts_with("save path ") {
-                    let filename = trimmed["save path ".len()..].trim();
-                    if filename.is_empty() {
-                        println!("⚠️  Usage: save path <filename>");
-                    } else {
-                        match std::fs::write(filename, path_metrics.to_csv_string()) {
-                            Ok(_) => println!("💾 Saved path metrics to {}", filename),
-                            Err(e) => println!("⚠️  Failed to save path metrics: {e:?}"),
-                        }
+        // `--rendezvous`ポイントへダイヤルしておく。登録自体はConnectionEstablishedを見てから行う
+        // （rendezvousプロトコルは接続済みの相手にしかregister/discoverを送れないため）
+        let rendezvous_peer = rendezvous_addr.as_ref().and_then(|addr| {
+            match peer_id_from_multiaddr(addr) {
+                Some(peer_id) => {
+                    match swarm.dial(addr.clone()) {
+                        Ok(()) => println!("📡 Dialing rendezvous point: {addr}"),
+                        Err(e) => println!("⚠️  Failed to dial rendezvous point {addr}: {e:?}"),
                     }
-                    continue;
+                    Some(peer_id)
                 }
+                None => {
+                    println!("⚠️  --rendezvous address {addr} has no /p2p/<PeerId> suffix, ignoring");
+                    None
+                }
+            }
+        });
+        let rendezvous_namespace = rendezvous::Namespace::new(rendezvous_namespace)?;
 
-                if trimmed.starts_with("save ") {
-                    let filename = &trimmed[5..];
-                    let csv_content = metrics_collector.to_csv_string();
-                    match std::fs::write(filename, csv_content) {
-                        Ok(_) => println!("✅ Metrics saved to {}", filename),
-                        Err(e) => println!("⚠️  Failed to save metrics: {e:?}"),
-                    }
-                    continue;
+        println!("✅ Manager started fresh!");
+        println!("⏳ Clearing any cached peer information...");
+
+        // Give a brief moment to ensure clean state
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        println!("⏳ Waiting 1 second for initial Gossipsub mesh setup...");
+
+        // Wait for Gossipsub mesh initialization
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        println!("✅ Manager ready! Listening for agents...");
+        println!("📊 Task metrics collection initialized");
+        println!("⏱️ Path computation metrics collection initialized");
+
+        // クラッシュ前の進行中タスク/メトリクスをsledから読み戻す。担当ピアは再接続してから
+        // でないとdeliveryできないので、いったん全てtask_registryへ戻し、30秒後の期限を過ぎた
+        // 最初のtimeout_check_tickに再配布を任せる（task_peer_map/peer_task_mapのbusy印は新しいAckが来るまで付けない）
+        let task_store = TaskStore::open(&db_path)?;
+        let mut task_registry = HashMap::new();
+        let mut task_deadlines = HashMap::new();
+        let mut task_counter = 0u64;
+        for (task_id, stored) in task_store.load_assignments()? {
+            task_counter = task_counter.max(task_id);
+            match stored.peer_id.parse::<libp2p::PeerId>() {
+                Ok(peer_id) => {
+                    println!("♻️  Restored in-flight task {task_id} (was assigned to {peer_id}) from {db_path}");
+                    task_registry.insert(task_id, (peer_id, stored.task));
+                    task_deadlines.insert(task_id, std::time::Instant::now() + Duration::from_secs(30));
                 }
+                Err(e) => println!("⚠️  Skipping restored task {task_id}, bad peer id {}: {e:?}", stored.peer_id),
+            }
+        }
 
-                // タスク分割・送信コマンド
-                if trimmed.starts_with("tasks ") {
-                    let num_str = &trimmed[6..];
-                    if let Ok(num_tasks) = num_str.parse::<usize>() {
-                        // Gossipsubから実際に購読しているピアを取得して同期
-                        for peer in swarm.behaviour_mut().gossipsub.all_peers() {
-                            if peer.1.iter().any(|t| t.as_str() == "mapd") {
-                                subscribed_peers.insert(peer.0.clone());
-                            }
-                        }
+        let mut metrics_collector = TaskMetricsCollector::new();
+        for metric in task_store.load_metrics()? {
+            task_counter = task_counter.max(metric.task_id);
+            metrics_collector.add_metric(metric);
+        }
+        if task_counter > 0 {
+            println!("♻️  Resuming task_counter from {task_counter} (reloaded from {db_path})");
+        }
 
-                        println!("📡 Sending {} tasks to subscribed peers...", num_tasks);
-                        println!("   Subscribed peers: {}", subscribed_peers.len());
+        // `--monitor-addr`が指定された時だけWebSocket監視サーバを立てる。未指定でもbroadcast
+        // チャネル自体は作っておき、ManagerEvent側のコードを条件分岐なしに書けるようにする
+        let monitor_state: MonitorState = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (monitor_tx, _) = tokio::sync::broadcast::channel(256);
+        if let Some(addr) = monitor_addr {
+            tokio::spawn(serve_monitor_ws(addr, monitor_state.clone(), monitor_tx.clone()));
+        }
 
-                        let mut sent_count = 0;
-                        let mut round = 0;
+        Ok(Self {
+            swarm,
+            topic,
+            grid,
+            ignore_mdns,
+            known_peers: HashSet::new(),
+            subscribed_peers: HashSet::new(),
+            peer_task_map: HashMap::new(),
+            task_peer_map: HashMap::new(),
+            outstanding_tasks: HashMap::new(),
+            task_registry,
+            task_deadlines,
+            // 担当ピアが死んでいてもタスクを永遠に失わないための猶予時間
+            task_timeout: Duration::from_secs(30),
+            timeout_check_tick: tokio::time::interval(Duration::from_secs(5)),
+            task_store,
+            rendezvous_peer,
+            rendezvous_namespace,
+            rendezvous_cookie: None,
+            rendezvous_discover_tick: tokio::time::interval(Duration::from_secs(10)),
+            task_counter,
+            peer_positions: HashMap::new(),
+            metrics_collector,
+            path_metrics: PathComputationMetrics::new(),
+            // 直近のタスク計測イベントのログ。pullアンチエントロピーのBloomフィルタの元になる
+            metric_log: MetricAntiEntropyLog::new(500),
+            last_metrics_pull: std::time::Instant::now(),
+            metrics_pull_interval: Duration::from_secs(5),
+            // gossipsub発行の優先度つき送信キューと、それをドレインするティック
+            dispatcher: OutboundDispatcher::new(256),
+            dispatch_tick: tokio::time::interval(Duration::from_millis(20)),
+            // グレーリストされたピアをメッシュ/スケジューリング対象から定期的に除外するティック
+            score_check_tick: tokio::time::interval(Duration::from_secs(2)),
+            monitor_state,
+            monitor_tx,
+            cmd_rx,
+        })
+    }
 
-                        // ラウンドベースでタスクを配分
-                        while sent_count < num_tasks {
-                            let mut sent_in_round = false;
-                            for peer_id in &subscribed_peers {
-                                if sent_count >= num_tasks {
-                                    break;
-                                }
+    /// 割り当て状態が変わるたびに呼ぶ。`monitor_state`のチェックポイントを更新し、
+    /// 接続中の監視クライアントへ差分イベントを配信する（受信者がいなくてもエラーは無視してよい）
+    fn publish_monitor_event(&self, event: MonitorEvent) {
+        {
+            let mut assignments = self.monitor_state.lock().unwrap();
+            match &event {
+                MonitorEvent::Dispatched { task_id, peer_id } => {
+                    assignments.retain(|a| a.task_id != *task_id);
+                    assignments.push(MonitorAssignment {
+                        task_id: *task_id,
+                        peer_id: peer_id.clone(),
+                    });
+                }
+                MonitorEvent::Completed { task_id, .. } | MonitorEvent::TimedOut { task_id, .. } => {
+                    assignments.retain(|a| a.task_id != *task_id);
+                }
+                MonitorEvent::Reassigned { task_id, to_peer, .. } => {
+                    assignments.retain(|a| a.task_id != *task_id);
+                    assignments.push(MonitorAssignment {
+                        task_id: *task_id,
+                        peer_id: to_peer.clone(),
+                    });
+                }
+            }
+        }
+        let _ = self.monitor_tx.send(event);
+    }
 
-                                let busy = peer_task_map.get(peer_id).and_then(|t| t.as_ref()).is_some();
-                                if !busy {
-                                    if let Some(mut task) = task_gen.generate_task() {
-                                        task_counter += 1;
-                                        let task_id = task_counter;
-                                        task.peer_id = Some(peer_id.to_base58());
-                                        task.task_id = Some(task_id);
-
-                                        // タスク計測情報を作成
-                                        let metric = TaskMetric::new(task_id, peer_id.to_base58());
-                                        metrics_collector.add_metric(metric);
-
-                                        match serde_json::to_vec(&task) {
-                                            Ok(task_bytes) => {
-                                                match swarm.behaviour_mut().gossipsub.publish(topic.clone(), task_bytes) {
-                                                    Ok(_) => {
-                                                        println!("✅ Task {} sent to {} (round {})", task_id, peer_id, round + 1);
-                                                        peer_task_map.insert(peer_id.clone(), Some(task.clone()));
-                                                        task_peer_map.insert(task_id, peer_id.clone());
-                                                        sent_count += 1;
-                                                        sent_in_round = true;
-                                                    }
-                                                    Err(e) => {
-                                                        println!("⚠️  Task publish error for {}: {e:?}", peer_id);
-                                                    }
-                                                }
-                                            },
-                                            Err(e) => println!("Task serialization error: {e:?}"),
-                                        }
-                                        tokio::time::sleep(Duration::from_millis(100)).await;
-                                    }
-                                }
-                            }
+    /// 空いているpeerに新しいタスクを1つ生成して割り当てる。`tasks N`の各ラウンド、
+    /// 単発の`task`コマンド、doneメッセージ受信後の再配布、rendezvousで見つけたワーカーへの
+    /// 初回配布の4箇所から共通で呼ばれる。
+    ///
+    /// 宛先は`task.peer_id`で1つのピアに決まっているため、全員へのgossipsub broadcastではなく
+    /// request_responseでそのピアにだけ送る。送達確認(`TaskAck{accepted: true}`)が返るまでは
+    /// `peer_task_map`にbusyとして書き込まない（送達失敗時に別のピアへ回せるようにするため）。
+    fn try_assign_task(
+        &mut self,
+        peer_id: &libp2p::PeerId,
+        task_gen: &mut TaskGeneratorAgent,
+    ) -> Option<u64> {
+        let mut task = task_gen.generate_task()?;
+        self.task_counter += 1;
+        let task_id = self.task_counter;
+        task.peer_id = Some(peer_id.to_base58());
+        task.task_id = Some(task_id);
 
-                            if !sent_in_round {
-                                println!("⚠️  No agents available in round {}", round + 1);
-                                break;
-                            }
-                            round += 1;
-                            tokio::time::sleep(Duration::from_millis(200)).await;
-                        }
+        let metric = TaskMetric::new(task_id, peer_id.to_base58());
+        if let Err(e) = self.task_store.record_metric(&metric) {
+            println!("⚠️  Failed to persist metric for task {task_id}: {e:?}");
+        }
+        self.metrics_collector.add_metric(metric);
 
-                        println!("✅ Sent {} tasks in {} rounds", sent_count, round);
-                        println!("💡 Tip: Use 'metrics' to view statistics, 'save <filename>' for task metrics, or 'save path <filename>' for path metrics");
-                        continue;
-                    } else {
-                        println!("⚠️  Invalid number of tasks. Usage: tasks <number>");
-                        continue;
+        let request_id = self.swarm.behaviour_mut().task_dispatch.send_request(
+            peer_id,
+            TaskRequest {
+                task_id,
+                payload: task.clone(),
+            },
+        );
+        self.task_peer_map
+            .insert(task_id, (peer_id.clone(), request_id));
+        self.task_registry
+            .insert(task_id, (peer_id.clone(), task.clone()));
+        self.task_deadlines
+            .insert(task_id, std::time::Instant::now() + self.task_timeout);
+        if let Err(e) = self.task_store.record_assignment(task_id, &peer_id.to_base58(), &task) {
+            println!("⚠️  Failed to persist assignment for task {task_id}: {e:?}");
+        }
+        self.outstanding_tasks.insert(request_id, (task_id, task));
+        self.publish_monitor_event(MonitorEvent::Dispatched {
+            task_id,
+            peer_id: peer_id.to_base58(),
+        });
+        Some(task_id)
+    }
+
+    /// 送達に失敗した（あるいはAckでaccepted=falseが返ってきた、タイムアウトした、担当ピアが
+    /// 切断した）タスクを、別の空きピアへ回せるなら同じ`task_id`のまま回し、いなければ
+    /// 諦める（sledの記録は残すので、再起動時のload_assignmentsで拾い直される）
+    fn retry_task_on_another_peer(&mut self, failed_peer: &libp2p::PeerId, task_id: u64, task: Task) {
+        let candidate = self.subscribed_peers.iter().find(|p| {
+            *p != failed_peer && self.peer_task_map.get(*p).and_then(|t| t.as_ref()).is_none()
+        }).cloned();
+
+        match candidate {
+            Some(peer_id) => {
+                println!("🔁 Retrying task {task_id} on {peer_id} after failed delivery to {failed_peer}");
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .task_dispatch
+                    .send_request(&peer_id, TaskRequest { task_id, payload: task.clone() });
+                self.task_peer_map.insert(task_id, (peer_id.clone(), request_id));
+                self.task_registry.insert(task_id, (peer_id.clone(), task.clone()));
+                self.task_deadlines
+                    .insert(task_id, std::time::Instant::now() + self.task_timeout);
+                if let Err(e) = self.task_store.record_assignment(task_id, &peer_id.to_base58(), &task) {
+                    println!("⚠️  Failed to persist reassignment for task {task_id}: {e:?}");
+                }
+                self.outstanding_tasks.insert(request_id, (task_id, task));
+                self.publish_monitor_event(MonitorEvent::Reassigned {
+                    task_id,
+                    from_peer: failed_peer.to_base58(),
+                    to_peer: peer_id.to_base58(),
+                });
+            }
+            None => {
+                // sledの`assignments`エントリはあえて消さない。再起動すれば次のload_assignmentsで
+                // 拾い直され、その時点で空きピアがいれば改めて配布される
+                println!("⚠️  No free peer available to retry task {task_id}, giving up on it");
+                self.task_peer_map.remove(&task_id);
+                self.task_registry.remove(&task_id);
+                self.task_deadlines.remove(&task_id);
+            }
+        }
+    }
+
+    /// Gossipsubから実際に購読しているピアを取って`subscribed_peers`と同期する
+    fn resync_subscribed_peers(&mut self) {
+        for peer in self.swarm.behaviour_mut().gossipsub.all_peers() {
+            if peer.1.iter().any(|t| t.as_str() == "mapd") {
+                self.subscribed_peers.insert(peer.0.clone());
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, cmd: ManagerCommand) -> ManagerEvent {
+        match cmd {
+            ManagerCommand::Metrics => {
+                let stats = self.metrics_collector.get_statistics();
+                println!("{}", stats);
+                if let Some(path_stats) = self.path_metrics.get_statistics() {
+                    println!("{}", path_stats);
+                } else {
+                    println!("⏱️ Path Computation: no samples yet");
+                }
+                println!("📤 Dispatch queue: dropped {} non-priority messages", self.dispatcher.dropped_non_priority);
+                println!("📊 Peer scores:");
+                for peer_id in &self.subscribed_peers {
+                    let score = self.swarm.behaviour().gossipsub.peer_score(peer_id).unwrap_or(0.0);
+                    println!("   {peer_id} => {score:.2}");
+                }
+                ManagerEvent::Idle
+            }
+            ManagerCommand::Reset => {
+                self.known_peers.clear();
+                self.subscribed_peers.clear();
+                self.peer_task_map.clear();
+                self.task_peer_map.clear();
+                self.outstanding_tasks.clear();
+                self.task_registry.clear();
+                self.task_deadlines.clear();
+                self.peer_positions.clear();
+                self.metrics_collector = TaskMetricsCollector::new();
+                self.task_counter = 0;
+                if let Err(e) = self.task_store.clear_all() {
+                    println!("⚠️  Failed to clear persisted state: {e:?}");
+                }
+                self.path_metrics.clear();
+                println!("✅ All peers and state cleared. Ready for fresh start!");
+                ManagerEvent::Idle
+            }
+            ManagerCommand::SavePath { path } => {
+                if path.is_empty() {
+                    println!("⚠️  Usage: save path <filename>");
+                } else {
+                    match std::fs::write(&path, self.path_metrics.to_csv_string()) {
+                        Ok(_) => println!("💾 Saved path metrics to {}", path),
+                        Err(e) => println!("⚠️  Failed to save path metrics: {e:?}"),
                     }
                 }
+                ManagerEvent::Idle
+            }
+            ManagerCommand::SaveMetrics { path } => {
+                let csv_content = self.metrics_collector.to_csv_string();
+                match std::fs::write(&path, csv_content) {
+                    Ok(_) => println!("✅ Metrics saved to {}", path),
+                    Err(e) => println!("⚠️  Failed to save metrics: {e:?}"),
+                }
+                ManagerEvent::Idle
+            }
+            ManagerCommand::GenerateTasks(num_tasks) => {
+                self.resync_subscribed_peers();
+                println!("📡 Sending {} tasks to subscribed peers...", num_tasks);
+                println!("   Subscribed peers: {}", self.subscribed_peers.len());
+
+                let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                let mut sent_count = 0;
+                let mut round = 0;
+                let mut last_assigned = None;
 
-                if trimmed == "task" {
-                    // Gossipsubから実際に購読しているピアを取得して同期
-                    for peer in swarm.behaviour_mut().gossipsub.all_peers() {
-                        if peer.1.iter().any(|t| t.as_str() == "mapd") {
-                            subscribed_peers.insert(peer.0.clone());
+                // ラウンドベースでタスクを配分
+                while sent_count < num_tasks {
+                    let mut sent_in_round = false;
+                    let peers: Vec<_> = self.subscribed_peers.iter().cloned().collect();
+                    for peer_id in &peers {
+                        if sent_count >= num_tasks {
+                            break;
+                        }
+                        let busy = self.peer_task_map.get(peer_id).and_then(|t| t.as_ref()).is_some();
+                        if !busy {
+                            if let Some(task_id) = self.try_assign_task(peer_id, &mut task_gen) {
+                                println!("✅ Task {} queued for {} (round {})", task_id, peer_id, round + 1);
+                                sent_count += 1;
+                                sent_in_round = true;
+                                last_assigned = Some((peer_id.clone(), task_id));
+                                tokio::time::sleep(Duration::from_millis(100)).await;
+                            }
                         }
                     }
 
-                    println!("Known peers (mDNS): {:?}", known_peers);
-                    println!("Subscribed peers (Gossipsub): {:?}", subscribed_peers);
-                    println!("📡 Sending tasks to subscribed peers...");
+                    if !sent_in_round {
+                        println!("⚠️  No agents available in round {}", round + 1);
+                        break;
+                    }
+                    round += 1;
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
 
-                    let mut assigned = false;
+                println!("✅ Sent {} tasks in {} rounds", sent_count, round);
+                if sent_count == 0 {
+                    if self.subscribed_peers.is_empty() {
+                        println!("⚠️  No peers have subscribed to the topic yet.");
+                        println!("💡 Tip: Wait for '🔗 Peer XXX subscribed to topic: mapd' messages, then try 'task' again.");
+                    } else {
+                        println!("⚠️  All subscribed peers are busy with tasks.");
+                    }
+                }
+                match last_assigned {
+                    Some((peer, task_id)) => ManagerEvent::TaskAssigned { peer, task_id },
+                    None => ManagerEvent::Idle,
+                }
+            }
+            ManagerCommand::GenerateTask => {
+                self.resync_subscribed_peers();
+                println!("Known peers (mDNS): {:?}", self.known_peers);
+                println!("Subscribed peers (Gossipsub): {:?}", self.subscribed_peers);
+                println!("📡 Sending tasks to subscribed peers...");
 
-                    // subscribed_peersのみに送信
-                    for peer_id in &subscribed_peers {
-                        let busy = peer_task_map.get(peer_id).and_then(|t| t.as_ref()).is_some();
-                        if !busy {
-                            if let Some(mut task) = task_gen.generate_task() {
-                                // タスクIDを付与
-                                task_counter += 1;
-                                let task_id = task_counter;
-                                task.peer_id = Some(peer_id.to_base58());
-                                task.task_id = Some(task_id);
-
-                                // タスク計測情報を作成
-                                let metric = TaskMetric::new(task_id, peer_id.to_base58());
-                                metrics_collector.add_metric(metric);
-
-                                match serde_json::to_vec(&task) {
-                                    Ok(task_bytes) => {
-                                        match swarm.behaviour_mut().gossipsub.publish(topic.clone(), task_bytes) {
-                                            Ok(_) => {
-                                                println!("✅ Task {} sent to {peer_id}: {:?}", task_id, task);
-                                                peer_task_map.insert(peer_id.clone(), Some(task.clone()));
-                                                task_peer_map.insert(task_id, peer_id.clone());
-                                                assigned = true;
-                                            }
-                                            Err(e) => {
-                                                println!("⚠️  Task publish error for {peer_id}: {e:?}");
-                                            }
-                                        }
-                                    },
-                                    Err(e) => println!("Task serialization error: {e:?}"),
-                                }
+                let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                let mut last_assigned = None;
+                let peers: Vec<_> = self.subscribed_peers.iter().cloned().collect();
+                for peer_id in &peers {
+                    let busy = self.peer_task_map.get(peer_id).and_then(|t| t.as_ref()).is_some();
+                    if !busy {
+                        match self.try_assign_task(peer_id, &mut task_gen) {
+                            Some(task_id) => {
+                                println!("✅ Task {} queued for {peer_id}", task_id);
+                                last_assigned = Some((peer_id.clone(), task_id));
                                 tokio::time::sleep(Duration::from_millis(150)).await;
-                            } else {
-                                println!("Task generation failed (not enough free cells)");
                             }
-                        }
-                    }                    if !assigned {
-                        if subscribed_peers.is_empty() {
-                            println!("⚠️  No peers have subscribed to the topic yet.");
-                            println!("💡 Tip: Wait for '🔗 Peer XXX subscribed to topic: mapd' messages, then try 'task' again.");
-                        } else {
-                            println!("⚠️  All subscribed peers are busy with tasks.");
+                            None => println!("Task generation failed (not enough free cells)"),
                         }
                     }
-                } else if trimmed != "metrics" && trimmed != "task" && !trimmed.starts_with("save ") && !trimmed.starts_with("tasks ") {
-                    if let Err(e) = swarm
-                        .behaviour_mut().gossipsub
-                        .publish(topic.clone(), line.as_bytes()) {
-                        println!("Publish error: {e:?}");
+                }
+
+                if last_assigned.is_none() {
+                    if self.subscribed_peers.is_empty() {
+                        println!("⚠️  No peers have subscribed to the topic yet.");
+                        println!("💡 Tip: Wait for '🔗 Peer XXX subscribed to topic: mapd' messages, then try 'task' again.");
+                    } else {
+                        println!("⚠️  All subscribed peers are busy with tasks.");
                     }
                 }
+                match last_assigned {
+                    Some((peer, task_id)) => ManagerEvent::TaskAssigned { peer, task_id },
+                    None => ManagerEvent::Idle,
+                }
+            }
+            ManagerCommand::Broadcast(line) => {
+                // オペレーターが打った生メッセージは雑多な低価値トラフィック扱い
+                self.dispatcher.enqueue(OutboundMessage {
+                    priority: DispatchPriority::NonPriority,
+                    data: line.into_bytes(),
+                });
+                ManagerEvent::Idle
             }
-            event = swarm.select_next_some() => match event {
+        }
+    }
+
+    /// 1ターン分の処理を行い、結果を`ManagerEvent`として返す。`select!`の各枝は独立に
+    /// 1イベントだけ処理して即座に戻るので、どれか1つが重い処理をしても他の枝が飢餓しない。
+    async fn next_event(&mut self) -> ManagerEvent {
+        select! {
+            Some(cmd) = self.cmd_rx.recv() => self.handle_command(cmd).await,
+            event = self.swarm.select_next_some() => match event {
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                    if ignore_mdns {
+                    let mut discovered = None;
+                    if self.ignore_mdns {
                         // In clean mode, ignore all mDNS discoveries
                         for (peer_id, _multiaddr) in list {
                             println!("⏭️  Ignoring mDNS peer (--clean mode): {peer_id}");
@@ -349,48 +1229,84 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     } else {
                         for (peer_id, _multiaddr) in list {
                             println!("mDNS discovered a new peer: {peer_id}");
-                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                            known_peers.insert(peer_id.clone());
-                            peer_task_map.entry(peer_id.clone()).or_insert(None);
+                            self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                            self.known_peers.insert(peer_id.clone());
+                            self.peer_task_map.entry(peer_id.clone()).or_insert(None);
 
                             // 少し待ってからGossipsubの購読状態をチェック
                             tokio::time::sleep(Duration::from_millis(100)).await;
 
                             // ピアがトピックに購読しているかチェック
-                            for peer_info in swarm.behaviour_mut().gossipsub.all_peers() {
+                            for peer_info in self.swarm.behaviour_mut().gossipsub.all_peers() {
                                 if peer_info.0 == &peer_id && peer_info.1.iter().any(|t| t.as_str() == "mapd") {
-                                    subscribed_peers.insert(peer_id.clone());
+                                    self.subscribed_peers.insert(peer_id.clone());
                                     println!("   ✅ Peer {} is already subscribed to 'mapd'", peer_id);
                                     break;
                                 }
                             }
+                            discovered = Some(peer_id);
                         }
                     }
+                    match discovered {
+                        Some(peer_id) => ManagerEvent::PeerDiscovered(peer_id),
+                        None => ManagerEvent::Idle,
+                    }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                    let mut expired = None;
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                        known_peers.remove(&peer_id);
-                        subscribed_peers.remove(&peer_id);
-                        peer_task_map.remove(&peer_id);
+                        self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        self.known_peers.remove(&peer_id);
+                        self.subscribed_peers.remove(&peer_id);
+                        self.peer_task_map.remove(&peer_id);
+                        expired = Some(peer_id);
+                    }
+                    match expired {
+                        Some(peer_id) => ManagerEvent::PeerExpired(peer_id),
+                        None => ManagerEvent::Idle,
                     }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
                     println!("🔗 Peer {} subscribed to topic: {}", peer_id, topic);
-                    subscribed_peers.insert(peer_id);
-                    println!("   ✅ Total subscribed peers: {}", subscribed_peers.len());
+                    self.subscribed_peers.insert(peer_id);
+                    println!("   ✅ Total subscribed peers: {}", self.subscribed_peers.len());
+                    ManagerEvent::Idle
                 }
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
                     println!("❌ Peer {} unsubscribed from topic: {}", peer_id, topic);
-                    subscribed_peers.remove(&peer_id);
+                    self.subscribed_peers.remove(&peer_id);
+                    ManagerEvent::Idle
                 }
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                     propagation_source: peer_id,
-                    message_id: _id,
+                    message_id,
                     message,
                 })) => {
-                    let msg_str = String::from_utf8_lossy(&message.data);
+                    let decoded = decode_payload(&message.data);
+                    let msg_str = String::from_utf8_lossy(&decoded);
+
+                    // 壊れたJSONやグリッド範囲外の座標はReject、未知のtype/statusはIgnoreとして
+                    // gossipsubのスコアリングにフィードバックしてから処理する
+                    let acceptance = match serde_json::from_str::<serde_json::Value>(&msg_str) {
+                        Ok(val) => classify_gossip_message(
+                            &val,
+                            &self.grid,
+                            &peer_id,
+                            &self.task_peer_map,
+                            &self.metrics_collector,
+                        ),
+                        Err(_) => gossipsub::MessageAcceptance::Reject,
+                    };
+                    let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                        &message_id,
+                        &peer_id,
+                        acceptance,
+                    );
+                    if acceptance != gossipsub::MessageAcceptance::Accept {
+                        println!("🚫 [VALIDATE] message from {peer_id} => {acceptance:?}");
+                        return ManagerEvent::Idle;
+                    }
 
                     // occupied_requestの処理
                     if let Ok(request) = serde_json::from_str::<serde_json::Value>(&msg_str) {
@@ -398,7 +1314,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             println!("📍 Received occupied_request from {peer_id}");
 
                             // 現在占有されている位置のリストを作成
-                            let occupied: Vec<(usize, usize)> = peer_positions.values().cloned().collect();
+                            let occupied: Vec<(usize, usize)> = self.peer_positions.values().cloned().collect();
 
                             // タイムスタンプを追加して毎回ユニークなメッセージにする
                             let timestamp = std::time::SystemTime::now()
@@ -414,13 +1330,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             });
 
                             if let Ok(response_bytes) = serde_json::to_vec(&response) {
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), response_bytes) {
-                                    println!("⚠️  Failed to send occupied_response: {e:?}");
-                                } else {
-                                    println!("✅ Sent occupied_response with {} positions (timestamp: {})", occupied.len(), timestamp);
-                                }
+                                self.dispatcher.enqueue(OutboundMessage {
+                                    priority: DispatchPriority::Priority,
+                                    data: response_bytes,
+                                });
+                                println!("✅ Queued occupied_response with {} positions (timestamp: {})", occupied.len(), timestamp);
                             }
-                            continue;
+                            return ManagerEvent::Idle;
                         }
 
                         // 位置情報の更新（position_updateメッセージ）
@@ -431,12 +1347,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             ) {
                                 if pos.len() == 2 {
                                     if let (Some(x), Some(y)) = (pos[0].as_u64(), pos[1].as_u64()) {
-                                        peer_positions.insert(peer_id_str.to_string(), (x as usize, y as usize));
+                                        self.peer_positions.insert(peer_id_str.to_string(), (x as usize, y as usize));
                                         println!("📍 Updated position for {}: ({}, {})", peer_id_str, x, y);
                                     }
                                 }
                             }
-                            continue;
+                            return ManagerEvent::Idle;
                         }
 
                         // タスク計測情報の受信
@@ -444,35 +1360,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             match msg_type {
                                 "task_metric_received" => {
                                     if let Some(task_id) = request.get("task_id").and_then(|v| v.as_u64()) {
-                                        metrics_collector.update_received(task_id);
+                                        self.metrics_collector.update_received(task_id);
+                                        self.metric_log.record(task_id, MetricEvent::Received);
                                         println!("   📊 Task {} received by agent", task_id);
                                     }
-                                    continue;
+                                    return ManagerEvent::Idle;
                                 }
                                 "task_metric_started" => {
                                     if let Some(task_id) = request.get("task_id").and_then(|v| v.as_u64()) {
-                                        metrics_collector.update_started(task_id);
+                                        self.metrics_collector.update_started(task_id);
+                                        self.metric_log.record(task_id, MetricEvent::Started);
                                         println!("   📊 Task {} started processing", task_id);
                                     }
-                                    continue;
+                                    return ManagerEvent::Idle;
                                 }
                                 "task_metric_completed" => {
                                     if let Some(task_id) = request.get("task_id").and_then(|v| v.as_u64()) {
-                                        metrics_collector.update_completed(task_id);
+                                        self.metrics_collector.update_completed(task_id);
+                                        self.metric_log.record(task_id, MetricEvent::Completed);
                                         println!("   📊 Task {} marked as completed", task_id);
                                     }
-                                    continue;
+                                    return ManagerEvent::Idle;
                                 }
                                 "path_metric" => {
                                     if let Some(duration) = request.get("duration_micros").and_then(|v| v.as_u64()) {
-                                        path_metrics.record_micros(duration as u128);
+                                        self.path_metrics.record_micros(duration as u128);
                                         println!(
                                             "⏱️ Path metric from {}: {:.3} ms",
                                             peer_id,
                                             duration as f64 / 1000.0
                                         );
                                     }
-                                    continue;
+                                    return ManagerEvent::Idle;
                                 }
                                 _ => {}
                             }
@@ -485,41 +1404,442 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             let task_id = done_msg.get("task_id").and_then(|v| v.as_u64());
                             println!("✅ Received task completion notification: {peer_id}, task_id: {:?}", task_id);
 
-                            peer_task_map.insert(peer_id.clone(), None);
+                            self.peer_task_map.insert(peer_id.clone(), None);
+                            if let Some(task_id) = task_id {
+                                self.task_peer_map.remove(&task_id);
+                                self.task_registry.remove(&task_id);
+                                self.task_deadlines.remove(&task_id);
+                                if let Err(e) = self.task_store.record_completion(task_id) {
+                                    println!("⚠️  Failed to persist completion for task {task_id}: {e:?}");
+                                }
+                                self.publish_monitor_event(MonitorEvent::Completed {
+                                    task_id,
+                                    peer_id: peer_id.to_base58(),
+                                });
+                            }
                             // 新しいタスクを生成して配布
-                            if let Some(mut task) = task_gen.generate_task() {
-                                task_counter += 1;
-                                let new_task_id = task_counter;
-                                task.peer_id = Some(peer_id.to_base58());
-                                task.task_id = Some(new_task_id);
-
-                                // 新しいタスクの計測情報を作成
-                                let metric = TaskMetric::new(new_task_id, peer_id.to_base58());
-                                metrics_collector.add_metric(metric);
-
-                                match serde_json::to_vec(&task) {
-                                    Ok(task_bytes) => {
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), task_bytes) {
-                                            println!("Task publish error: {e:?}");
-                                        } else {
-                                            println!("✅ Task {} sent to {peer_id}: {:?}", new_task_id, task);
-                                            peer_task_map.insert(peer_id.clone(), Some(task.clone()));
-                                            task_peer_map.insert(new_task_id, peer_id.clone());
-                                        }
-                                    },
-                                    Err(e) => println!("Task serialization error: {e:?}"),
+                            let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                            match self.try_assign_task(&peer_id, &mut task_gen) {
+                                Some(new_task_id) => println!("✅ Task {} queued for {peer_id}", new_task_id),
+                                None => println!("Task generation failed (not enough free cells)"),
+                            }
+                            return ManagerEvent::TaskCompleted { peer: peer_id, task_id };
+                        }
+                    }
+                    ManagerEvent::Idle
+                },
+                // task_metric_*/doneのgossipsub取りこぼしをpullアンチエントロピーで埋め合わせる
+                SwarmEvent::Behaviour(MapdBehaviourEvent::MetricsAntiEntropy(
+                    request_response::Event::Message { peer, message },
+                )) => {
+                    match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            let MetricsAntiEntropyRequest::Pull(filter) = request;
+                            let missing = self.metric_log.entries_missing_from(&filter);
+                            if !missing.is_empty() {
+                                println!("🧩 [ANTI-ENTROPY] Sending {} missing metric entries to {}", missing.len(), peer);
+                            }
+                            let _ = self.swarm
+                                .behaviour_mut()
+                                .metrics_anti_entropy
+                                .send_response(channel, MetricsAntiEntropyResponse::Push(missing));
+                        }
+                        request_response::Message::Response { response, .. } => {
+                            let MetricsAntiEntropyResponse::Push(entries) = response;
+                            for entry in entries {
+                                if self.metric_log.record_entry(entry.clone()) {
+                                    match entry.event {
+                                        MetricEvent::Received => self.metrics_collector.update_received(entry.task_id),
+                                        MetricEvent::Started => self.metrics_collector.update_started(entry.task_id),
+                                        MetricEvent::Completed => self.metrics_collector.update_completed(entry.task_id),
+                                    }
+                                    println!("🧩 [ANTI-ENTROPY] Recovered missed {:?} for task {} from {}", entry.event, entry.task_id, peer);
                                 }
-                            } else {
-                                println!("Task generation failed (not enough free cells)");
                             }
                         }
                     }
+                    ManagerEvent::Idle
                 },
+                SwarmEvent::Behaviour(MapdBehaviourEvent::MetricsAntiEntropy(
+                    request_response::Event::OutboundFailure { peer, error, .. },
+                )) => {
+                    println!("⚠️  [ANTI-ENTROPY] pull request to {} failed: {:?}", peer, error);
+                    ManagerEvent::Idle
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::MetricsAntiEntropy(
+                    request_response::Event::InboundFailure { peer, error, .. },
+                )) => {
+                    println!("⚠️  [ANTI-ENTROPY] inbound pull from {} failed: {:?}", peer, error);
+                    ManagerEvent::Idle
+                }
+                // TaskRequest/TaskAckの往復。acceptedが返って初めてそのピアをbusyにする
+                SwarmEvent::Behaviour(MapdBehaviourEvent::TaskDispatch(
+                    request_response::Event::Message { peer, message },
+                )) => {
+                    match message {
+                        request_response::Message::Response { request_id, response } => {
+                            let TaskAck { task_id, accepted } = response;
+                            match self.outstanding_tasks.remove(&request_id) {
+                                Some((_, task)) if accepted => {
+                                    println!("✅ Task {task_id} acknowledged by {peer}");
+                                    if let Err(e) = self.task_store.record_assignment(task_id, &peer.to_base58(), &task) {
+                                        println!("⚠️  Failed to persist ack for task {task_id}: {e:?}");
+                                    }
+                                    self.peer_task_map.insert(peer.clone(), Some(task));
+                                    return ManagerEvent::TaskAssigned { peer, task_id };
+                                }
+                                Some((_, task)) => {
+                                    println!("⚠️  Task {task_id} rejected by {peer}");
+                                    self.retry_task_on_another_peer(&peer, task_id, task);
+                                }
+                                None => {
+                                    println!("⚠️  Got TaskAck for task {task_id} with no matching outstanding request");
+                                }
+                            }
+                            ManagerEvent::Idle
+                        }
+                        // このmanagerバイナリ自身はTaskRequestを受ける側にはならない想定だが、
+                        // 届いた場合もチャネルを握ったまま放置しないようにacceptedのAckを返す
+                        request_response::Message::Request { request, channel, .. } => {
+                            let _ = self.swarm.behaviour_mut().task_dispatch.send_response(
+                                channel,
+                                TaskAck { task_id: request.task_id, accepted: false },
+                            );
+                            ManagerEvent::Idle
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::TaskDispatch(
+                    request_response::Event::OutboundFailure { peer, request_id, error, .. },
+                )) => {
+                    println!("⚠️  Task dispatch to {} failed: {:?}", peer, error);
+                    if let Some((task_id, task)) = self.outstanding_tasks.remove(&request_id) {
+                        self.retry_task_on_another_peer(&peer, task_id, task);
+                    }
+                    ManagerEvent::Idle
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::TaskDispatch(
+                    request_response::Event::InboundFailure { peer, error, .. },
+                )) => {
+                    println!("⚠️  Inbound task dispatch from {} failed: {:?}", peer, error);
+                    ManagerEvent::Idle
+                }
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Local node is listening on {address}");
+                    ManagerEvent::Idle
+                }
+                // rendezvousポイントへの接続が確立したら、自分を登録してから即座に同じ名前空間を
+                // discoverする（ワーカー同士も同じ名前空間に登録している前提）
+                SwarmEvent::ConnectionEstablished { peer_id, .. } if Some(peer_id) == self.rendezvous_peer => {
+                    if let Some(rendezvous) = self.swarm.behaviour_mut().rendezvous.as_mut() {
+                        rendezvous.register(self.rendezvous_namespace.clone(), peer_id, None);
+                        rendezvous.discover(Some(self.rendezvous_namespace.clone()), None, None, peer_id);
+                    }
+                    println!("🪧 Connected to rendezvous point {peer_id}, registering + discovering");
+                    ManagerEvent::Idle
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Rendezvous(rendezvous::client::Event::Registered {
+                    rendezvous_node,
+                    ttl,
+                    namespace,
+                })) => {
+                    println!("🪧 Registered as '{namespace}' with rendezvous point {rendezvous_node} (ttl {ttl}s)");
+                    ManagerEvent::Idle
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Rendezvous(rendezvous::client::Event::RegisterFailed {
+                    rendezvous_node,
+                    namespace,
+                    error,
+                })) => {
+                    println!("⚠️  Rendezvous registration of '{namespace}' with {rendezvous_node} failed: {error:?}");
+                    ManagerEvent::Idle
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Rendezvous(rendezvous::client::Event::DiscoverFailed {
+                    rendezvous_node,
+                    namespace,
+                    error,
+                })) => {
+                    println!("⚠️  Rendezvous discover under {namespace:?} from {rendezvous_node} failed: {error:?}");
+                    ManagerEvent::Idle
+                }
+                // discoverで見つけたワーカー候補を、mDNS発見と同じ扱いで繋ぎに行く
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered {
+                    registrations,
+                    cookie,
+                    ..
+                })) => {
+                    self.rendezvous_cookie = Some(cookie);
+                    let mut discovered = None;
+                    for registration in registrations {
+                        let peer_id = registration.record.peer_id();
+                        if peer_id == *self.swarm.local_peer_id() {
+                            continue;
+                        }
+                        for addr in registration.record.addresses() {
+                            let _ = self.swarm.dial(addr.clone());
+                        }
+                        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        self.known_peers.insert(peer_id);
+                        self.peer_task_map.entry(peer_id).or_insert(None);
+                        println!("🪧 Discovered worker {peer_id} via rendezvous");
+                        // 静的bootstrapと違い、discoverで見つけたワーカーには出会った瞬間に
+                        // タスクを投げてみる（request_responseの送達なのでgossipsub購読を待たずに済む）
+                        let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                        if let Some(task_id) = self.try_assign_task(&peer_id, &mut task_gen) {
+                            println!("✅ Task {task_id} queued for newly discovered worker {peer_id}");
+                        }
+                        discovered = Some(peer_id);
+                    }
+                    match discovered {
+                        Some(peer_id) => ManagerEvent::PeerDiscovered(peer_id),
+                        None => ManagerEvent::Idle,
+                    }
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::Rendezvous(rendezvous::client::Event::Expired { peer })) => {
+                    println!("🪧 Rendezvous registration for {peer} expired");
+                    ManagerEvent::Idle
+                }
+                // 担当ピアが切断したら、返事を待たずその場でタスクを諦めて別ピアへ回す
+                SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                    let stale: Vec<u64> = self
+                        .task_registry
+                        .iter()
+                        .filter(|(_, (p, _))| *p == peer_id)
+                        .map(|(task_id, _)| *task_id)
+                        .collect();
+                    let mut last = None;
+                    for task_id in stale {
+                        if let Some((_, task)) = self.task_registry.remove(&task_id) {
+                            self.task_deadlines.remove(&task_id);
+                            self.task_peer_map.remove(&task_id);
+                            self.peer_task_map.insert(peer_id, None);
+                            self.metrics_collector.update_reassigned(task_id);
+                            println!("🔌 Peer {peer_id} disconnected mid-task {task_id}, reassigning");
+                            self.retry_task_on_another_peer(&peer_id, task_id, task);
+                            last = Some(task_id);
+                        }
+                    }
+                    match last {
+                        Some(task_id) => ManagerEvent::TaskReassigned { peer: peer_id, task_id },
+                        None => ManagerEvent::Idle,
+                    }
+                }
+                _ => ManagerEvent::Idle,
+            },
+            _ = self.dispatch_tick.tick() => {
+                if let Some(msg) = self.dispatcher.pop_next() {
+                    let payload = encode_payload(&msg.data);
+                    if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), payload) {
+                        println!("⚠️  Dispatch publish error ({:?}): {e:?}", msg.priority);
+                    }
+                }
+                ManagerEvent::Idle
+            }
+            _ = self.score_check_tick.tick() => {
+                let mut graylisted = Vec::new();
+                for peer_id in self.subscribed_peers.iter() {
+                    let score = self.swarm.behaviour().gossipsub.peer_score(peer_id).unwrap_or(0.0);
+                    if score < GRAYLIST_SCORE_THRESHOLD {
+                        graylisted.push(*peer_id);
+                    }
+                }
+                let mut last = None;
+                for peer_id in graylisted {
+                    self.subscribed_peers.remove(&peer_id);
+                    self.peer_task_map.remove(&peer_id);
+                    println!("🚫 Peer {peer_id} graylisted (score below {GRAYLIST_SCORE_THRESHOLD}), removed from mesh and scheduling");
+                    last = Some(peer_id);
+                }
+                match last {
+                    Some(peer_id) => ManagerEvent::PeerGraylisted(peer_id),
+                    None => ManagerEvent::Idle,
+                }
+            }
+            // 締め切りを過ぎたタスクを検知し、担当ピアが生きているかに関わらず別ピアへ回す
+            _ = self.timeout_check_tick.tick() => {
+                let now = std::time::Instant::now();
+                let expired: Vec<u64> = self.task_deadlines.iter()
+                    .filter(|(_, deadline)| **deadline <= now)
+                    .map(|(task_id, _)| *task_id)
+                    .collect();
+                let mut last = None;
+                for task_id in expired {
+                    if let Some((peer, task)) = self.task_registry.remove(&task_id) {
+                        self.task_deadlines.remove(&task_id);
+                        self.task_peer_map.remove(&task_id);
+                        self.peer_task_map.insert(peer.clone(), None);
+                        self.metrics_collector.update_reassigned(task_id);
+                        println!("⏰ Task {task_id} timed out on {peer}, reassigning");
+                        self.publish_monitor_event(MonitorEvent::TimedOut {
+                            task_id,
+                            peer_id: peer.to_base58(),
+                        });
+                        self.retry_task_on_another_peer(&peer, task_id, task);
+                        last = Some((peer, task_id));
+                    }
+                }
+                match last {
+                    Some((peer, task_id)) => ManagerEvent::TaskReassigned { peer, task_id },
+                    None => ManagerEvent::Idle,
+                }
+            }
+            _ = tokio::time::sleep(Duration::from_secs(1)), if self.last_metrics_pull.elapsed() >= self.metrics_pull_interval => {
+                let filter = self.metric_log.build_filter();
+                for peer in &self.subscribed_peers {
+                    self.swarm
+                        .behaviour_mut()
+                        .metrics_anti_entropy
+                        .send_request(peer, MetricsAntiEntropyRequest::Pull(filter.clone()));
                 }
-                _ => {}
+                self.last_metrics_pull = std::time::Instant::now();
+                ManagerEvent::Idle
+            }
+            // rendezvousポイントへ定期的にdiscoverをかけ直し、前回のcookieから差分だけを取得する
+            _ = self.rendezvous_discover_tick.tick() => {
+                if let Some(rendezvous_peer) = self.rendezvous_peer {
+                    if let Some(rendezvous) = self.swarm.behaviour_mut().rendezvous.as_mut() {
+                        rendezvous.discover(
+                            Some(self.rendezvous_namespace.clone()),
+                            self.rendezvous_cookie.clone(),
+                            None,
+                            rendezvous_peer,
+                        );
+                    }
+                }
+                ManagerEvent::Idle
             }
         }
     }
 }
+
+/// stdinの各行を`ManagerCommand`に変換して送るだけの薄いクライアント。
+/// コマンドの実行ロジックは一切持たず、パースして`Manager`へ委譲する。
+async fn run_stdin_client(tx: mpsc::Sender<ManagerCommand>) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+
+    println!("Enter messages via STDIN and they will be sent to connected peers using MAPD topic");
+    println!("Type 'task' to generate and send a task to agents.");
+    println!(
+        "Use 'metrics' for summary stats, 'save <filename>' for task metrics CSV, and 'save path <filename>' for path computation CSV."
+    );
+    println!(
+        "⚠️  IMPORTANT: Wait 3-5 seconds after all agents connect before sending tasks (for Gossipsub mesh to form)!"
+    );
+    println!(
+        "💡 TIP: Look for '🔗 Peer XXX subscribed to topic: mapd' messages to confirm mesh is ready!"
+    );
+
+    while let Ok(Some(line)) = stdin.next_line().await {
+        let trimmed = line.trim();
+
+        let command = if trimmed == "metrics" {
+            ManagerCommand::Metrics
+        } else if trimmed == "reset" {
+            ManagerCommand::Reset
+        } else if let Some(filename) = trimmed.strip_prefix("save path ") {
+            ManagerCommand::SavePath { path: filename.trim().to_string() }
+        } else if let Some(filename) = trimmed.strip_prefix("save ") {
+            ManagerCommand::SaveMetrics { path: filename.to_string() }
+        } else if let Some(num_str) = trimmed.strip_prefix("tasks ") {
+            match num_str.parse::<usize>() {
+                Ok(num_tasks) => ManagerCommand::GenerateTasks(num_tasks),
+                Err(_) => {
+                    println!("⚠️  Invalid number of tasks. Usage: tasks <number>");
+                    continue;
+                }
+            }
+        } else if trimmed == "task" {
+            ManagerCommand::GenerateTask
+        } else {
+            ManagerCommand::Broadcast(line.clone())
+        };
+
+        if tx.send(command).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Check for --clean flag to ignore mDNS discoveries
+    let args: Vec<String> = std::env::args().collect();
+    let ignore_mdns = args.contains(&"--clean".to_string());
+    // --no-mdns: mDNSのビヘイビア自体をOffにする（--cleanと違い、マルチキャストを一切出さない）
+    let no_mdns = args.contains(&"--no-mdns".to_string());
+    // --bootstrap <multiaddr>は繰り返し指定でき、起動直後にそれぞれへダイヤルする
+    let bootstrap: Vec<Multiaddr> = args
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.as_str() == "--bootstrap")
+        .filter_map(|(i, _)| args.get(i + 1))
+        .filter_map(|s| match s.parse::<Multiaddr>() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                println!("⚠️  Invalid --bootstrap multiaddr {s}: {e:?}");
+                None
+            }
+        })
+        .collect();
+
+    // --db-path <dir>: sledの保存先。指定がなければ`mapd-state.db`に書く
+    let db_path = args
+        .iter()
+        .position(|a| a == "--db-path")
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+        .unwrap_or_else(|| "mapd-state.db".to_string());
+
+    // --rendezvous <multiaddr>: 指定した時だけrendezvousビヘイビアを有効化し、そのピアへ登録/discoverする
+    let rendezvous_addr: Option<Multiaddr> = args
+        .iter()
+        .position(|a| a == "--rendezvous")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|s| match s.parse::<Multiaddr>() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                println!("⚠️  Invalid --rendezvous multiaddr {s}: {e:?}");
+                None
+            }
+        });
+    // --rendezvous-ns <namespace>: 登録/discoverに使う名前空間。指定がなければ`mapd`
+    let rendezvous_namespace = args
+        .iter()
+        .position(|a| a == "--rendezvous-ns")
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+        .unwrap_or_else(|| "mapd".to_string());
+
+    // --monitor-addr <host:port>: 指定した時だけ、割り当て状況を流すWebSocket監視サーバを立てる
+    let monitor_addr = args
+        .iter()
+        .position(|a| a == "--monitor-addr")
+        .and_then(|i| args.get(i + 1))
+        .cloned();
+
+    if ignore_mdns {
+        println!("🧹 Running in CLEAN mode - ignoring mDNS discoveries");
+    }
+    if no_mdns {
+        println!("🔇 mDNS disabled (--no-mdns). Using Kademlia + --bootstrap for discovery.");
+    }
+    println!("💾 Persisting scheduler state to {db_path}");
+
+    let (cmd_tx, cmd_rx) = mpsc::channel(32);
+    tokio::spawn(run_stdin_client(cmd_tx));
+
+    let mut manager = Manager::new(
+        ignore_mdns,
+        no_mdns,
+        bootstrap,
+        db_path,
+        rendezvous_addr,
+        rendezvous_namespace,
+        monitor_addr,
+        cmd_rx,
+    )
+    .await?;
+    loop {
+        manager.next_event().await;
+    }
+}
@@ -1,19 +1,108 @@
+use async_trait::async_trait;
+use futures::prelude::*;
 use futures::stream::StreamExt;
 use libp2p::{
-    gossipsub, mdns, noise,
+    StreamProtocol, gossipsub, kad, mdns, noise,
+    request_response::{self, ProtocolSupport, ResponseChannel},
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux,
 };
 use p2p_distributed_tswap::map::map::MAP;
 use p2p_distributed_tswap::map::task_generator::{Task, TaskGeneratorAgent};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
 use std::collections::{HashSet, hash_map::DefaultHasher};
 use std::error::Error;
 use std::hash::{Hash, Hasher};
+use std::io::Error as IoError;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::{io, io::AsyncBufReadExt, select};
+use tokio::{
+    io,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    select,
+};
+
+// マネージャー⇔エージェント間の直接ハンドオフ（request-response）で使うメッセージ。
+// タスクはもうpushされない：KademliaのprovideレコードをidleなエージェントがClaimTaskで
+// 引き当てる市場モデルなので、ここに残るのは原子的なclaimの確定だけ
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum TswapRequest {
+    ClaimTask(u64),
+    QueryOccupied,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum TswapResponse {
+    // Some(task) ならclaim成功、Noneなら他のエージェントに先を越された
+    ClaimResult { task_id: u64, task: Option<Task> },
+    OccupiedSet(Vec<(usize, usize)>),
+}
+
+// request_response::Codec実装: JSON行をそのままストリームに流す
+#[derive(Clone, Default)]
+struct TswapCodec;
+
+#[async_trait]
+impl request_response::Codec for TswapCodec {
+    type Protocol = StreamProtocol;
+    type Request = TswapRequest;
+    type Response = TswapResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(|e| IoError::other(e))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        serde_json::from_slice(&buf).map_err(|e| IoError::other(e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&req).map_err(|e| IoError::other(e))?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&res).map_err(|e| IoError::other(e))?;
+        io.write_all(&bytes).await
+    }
+}
 fn parse_map() -> Vec<Vec<char>> {
     let grid = MAP
         .replace('\r', "")
@@ -30,231 +119,765 @@ fn parse_map() -> Vec<Vec<char>> {
     grid
 }
 
+/// LWW(last-writer-wins)なCRDTエントリ。削除はタイムスタンプ付きトゥームストーンで表現する。
+#[derive(Clone, Debug, PartialEq)]
+enum Deletable<T> {
+    Value(T),
+    Deleted,
+}
+
+/// エージェントの占有位置を保持するCRDTマップ。
+/// `occupied_request`/`occupied_response`のポーリングをやめ、各`position_update`を
+/// タイムスタンプ付きデルタとしてマージすることで、誰でもローカルに占有状況を読める。
+#[derive(Default)]
+struct PositionCrdt {
+    // peer_id -> (timestamp, value)
+    entries: HashMap<String, (u128, Deletable<(usize, usize)>)>,
+}
+
+impl PositionCrdt {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// 既存エントリより新しいタイムスタンプの場合のみ採用する。
+    /// タイムスタンプが同値の場合は値の大きい方を決定的なタイブレークとして採用する。
+    fn merge(&mut self, peer_id: String, ts: u128, value: Deletable<(usize, usize)>) {
+        let should_replace = match self.entries.get(&peer_id) {
+            None => true,
+            Some((existing_ts, existing_value)) => {
+                ts > *existing_ts || (ts == *existing_ts && value_rank(&value) > value_rank(existing_value))
+            }
+        };
+        if should_replace {
+            self.entries.insert(peer_id, (ts, value));
+        }
+    }
+
+    fn remove(&mut self, peer_id: &str, ts: u128) {
+        self.merge(peer_id.to_string(), ts, Deletable::Deleted);
+    }
+
+    fn occupied(&self) -> Vec<(usize, usize)> {
+        self.entries
+            .values()
+            .filter_map(|(_, v)| match v {
+                Deletable::Value(p) => Some(*p),
+                Deletable::Deleted => None,
+            })
+            .collect()
+    }
+}
+
+fn value_rank(v: &Deletable<(usize, usize)>) -> (usize, usize) {
+    match v {
+        Deletable::Value(p) => *p,
+        Deletable::Deleted => (0, 0),
+    }
+}
+
+/// タスクIDをKademliaのレコードキーに変換する（libp2pのファイル共有providerパターンに倣う）
+fn task_record_key(task_id: u64) -> kad::RecordKey {
+    kad::RecordKey::new(&task_id.to_be_bytes())
+}
+
+/// `ClaimTask`の割当確定部分だけを切り出した純粋関数。`unclaimed_tasks`からタスクを取り除き、
+/// 宛先ピアの`peer_id`を刻んで返す。既に他のピアにclaimされていれば`None`（空振り）。
+fn claim_unclaimed_task(
+    unclaimed_tasks: &mut HashMap<u64, Task>,
+    task_id: u64,
+    peer_id_b58: &str,
+) -> Option<Task> {
+    let mut task = unclaimed_tasks.remove(&task_id)?;
+    task.peer_id = Some(peer_id_b58.to_string());
+    Some(task)
+}
+
 #[derive(NetworkBehaviour)]
 struct MapdBehaviour {
     gossipsub: gossipsub::Behaviour,
     mdns: mdns::tokio::Behaviour,
+    request_response: request_response::Behaviour<TswapCodec>,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| {
-            let message_id_fn = |message: &gossipsub::Message| {
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
-            };
-
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_millis(500)) // Heartbeat every 500ms
-                .heartbeat_initial_delay(Duration::from_millis(100)) // Initial heartbeat after 100ms (immediate mesh construction)
-                .mesh_n_low(1) // Minimum mesh peers set to 1 (default 4)
-                .mesh_n(2) // Target mesh peers set to 2 (default 6)
-                .mesh_n_high(3) // Maximum mesh peers set to 3 (default 12)
-                .validation_mode(gossipsub::ValidationMode::Strict)
-                .message_id_fn(message_id_fn)
-                .build()
-                .map_err(io::Error::other)?;
-
-            let gossipsub = gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(key.clone()),
-                gossipsub_config,
-            )?;
-
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MapdBehaviour { gossipsub, mdns })
-        })?
-        .build();
-
-    let topic = gossipsub::IdentTopic::new("mapd");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
-    println!("Peer ID: {}", swarm.local_peer_id());
-
-    // Create grid (pass appropriate grid in actual use)
-    let grid = Arc::new(parse_map());
-    let mut task_gen = TaskGeneratorAgent::new(&grid);
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
-    println!("Enter messages via STDIN and they will be sent to connected peers using MAPD topic");
-    println!("Type 'task' to generate and send a task to agents.");
-    println!(
-        "⚠️  IMPORTANT: Wait 3-5 seconds after all agents connect before sending tasks (for Gossipsub mesh to form)!"
-    );
-    println!(
-        "💡 TIP: Look for '🔗 Peer XXX subscribed to topic: mapd' messages to confirm mesh is ready!"
-    );
-    println!("⏳ Waiting 2 seconds for initial Gossipsub mesh setup...");
-
-    // Wait for Gossipsub mesh initialization
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
-    println!("✅ Manager ready! Listening for agents...");
-
-    // Management variables
-    let mut known_peers: HashSet<libp2p::PeerId> = HashSet::new();
-    // Peers subscribed to topic (joined Gossipsub mesh)
-    let mut subscribed_peers: HashSet<libp2p::PeerId> = HashSet::new();
-    // Task in progress for each peer: peer_id -> Option<Task>
-    let mut peer_task_map: HashMap<libp2p::PeerId, Option<Task>> = HashMap::new();
-    // Task ID to peer mapping: task_id -> peer_id
-    let mut task_peer_map: HashMap<u64, libp2p::PeerId> = HashMap::new();
-    // Task generation counter
-    let mut task_counter: u64 = 0;
-    // Track current position of each agent: peer_id -> (x, y)
-    let mut peer_positions: HashMap<String, (usize, usize)> = HashMap::new();
+/// エージェントの生存確認とタスク再割当てを担当するサブシステム
+/// (fuel-core-p2pのpeer_managerをモデルに、heartbeatタイムアウトで死活判定する)
+struct PeerManager {
+    last_seen: HashMap<libp2p::PeerId, std::time::Instant>,
+    timeout: Duration,
+}
+
+impl PeerManager {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            last_seen: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// ピアから何らかのメッセージ/イベントを受け取るたびに呼び出し、生存時刻を更新する
+    fn touch(&mut self, peer: libp2p::PeerId) {
+        self.last_seen.insert(peer, std::time::Instant::now());
+    }
+
+    fn forget(&mut self, peer: &libp2p::PeerId) {
+        self.last_seen.remove(peer);
+    }
+
+    /// タイムアウトを超えて音沙汰のないピアを死亡とみなし、生存リストから除去して返す
+    fn reap_dead_peers(&mut self) -> Vec<libp2p::PeerId> {
+        let now = std::time::Instant::now();
+        let dead: Vec<libp2p::PeerId> = self
+            .last_seen
+            .iter()
+            .filter(|(_, &last)| now.duration_since(last) > self.timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &dead {
+            self.last_seen.remove(peer);
+        }
+        dead
+    }
+}
+
+/// 優先度つき送信キュー（rust-libp2p PR #4914のgossipsub backpressureモデルを踏襲）。
+/// タスク配布通知(`task_available`)は`Priority`としてキューが溢れてもドロップせず呼び出し側にリトライさせ、
+/// 位置情報等の雑多なブロードキャストは`NonPriority`として輻輳時に古いものから間引く。
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DispatchPriority {
+    Priority,
+    NonPriority,
+}
+
+#[derive(Clone, Debug)]
+struct OutboundMessage {
+    priority: DispatchPriority,
+    data: Vec<u8>,
+    task_id: Option<u64>,
+}
+
+#[derive(Debug)]
+enum DispatchError {
+    /// キューが満杯で受け付けられなかった。呼び出し元にメッセージを返すのでリトライできる。
+    QueueFull(OutboundMessage),
+}
+
+struct OutboundDispatcher {
+    priority_queue: std::collections::VecDeque<OutboundMessage>,
+    non_priority_queue: std::collections::VecDeque<OutboundMessage>,
+    capacity: usize,
+}
+
+impl OutboundDispatcher {
+    fn new(capacity: usize) -> Self {
+        Self {
+            priority_queue: std::collections::VecDeque::new(),
+            non_priority_queue: std::collections::VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn enqueue(&mut self, msg: OutboundMessage) -> Result<(), DispatchError> {
+        match msg.priority {
+            DispatchPriority::Priority => {
+                if self.priority_queue.len() >= self.capacity {
+                    return Err(DispatchError::QueueFull(msg));
+                }
+                self.priority_queue.push_back(msg);
+                Ok(())
+            }
+            DispatchPriority::NonPriority => {
+                if self.non_priority_queue.len() >= self.capacity {
+                    // 非優先メッセージは輻輳時に一番古いものから間引く
+                    self.non_priority_queue.pop_front();
+                }
+                self.non_priority_queue.push_back(msg);
+                Ok(())
+            }
+        }
+    }
+
+    /// 優先メッセージを常に非優先より先に取り出す
+    fn pop_next(&mut self) -> Option<OutboundMessage> {
+        self.priority_queue
+            .pop_front()
+            .or_else(|| self.non_priority_queue.pop_front())
+    }
+
+    fn requeue_front(&mut self, msg: OutboundMessage) {
+        match msg.priority {
+            DispatchPriority::Priority => self.priority_queue.push_front(msg),
+            DispatchPriority::NonPriority => self.non_priority_queue.push_front(msg),
+        }
+    }
+}
+
+/// garageの`tranquilizer.rs`に倣い、直近の送信結果のスライディングウィンドウから
+/// 失敗率が目標値を下回る最速のペースへ、タスク配布の間隔を適応的に調整する。
+/// 固定の300ms sleepだと少人数では遅すぎ、多人数ではメッシュを溢れさせかねない。
+struct DispatchTranquilizer {
+    recent_outcomes: std::collections::VecDeque<bool>, // true = 成功
+    window: usize,
+    min_delay: Duration,
+    max_delay: Duration,
+    current_delay: Duration,
+    target_failure_ratio: f64,
+}
+
+impl DispatchTranquilizer {
+    fn new() -> Self {
+        Self {
+            recent_outcomes: std::collections::VecDeque::new(),
+            window: 20,
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(500),
+            current_delay: Duration::from_millis(300),
+            target_failure_ratio: 0.05,
+        }
+    }
+
+    fn record(&mut self, success: bool) {
+        self.recent_outcomes.push_back(success);
+        if self.recent_outcomes.len() > self.window {
+            self.recent_outcomes.pop_front();
+        }
+        self.retune();
+    }
+
+    fn failure_ratio(&self) -> f64 {
+        if self.recent_outcomes.is_empty() {
+            return 0.0;
+        }
+        let failures = self.recent_outcomes.iter().filter(|&&ok| !ok).count();
+        failures as f64 / self.recent_outcomes.len() as f64
+    }
+
+    fn retune(&mut self) {
+        let ratio = self.failure_ratio();
+        if ratio > self.target_failure_ratio {
+            // 失敗が目標を超えたら間隔を広げて落ち着かせる
+            self.current_delay = (self.current_delay * 2).min(self.max_delay);
+        } else if self.recent_outcomes.len() >= self.window {
+            // 十分なサンプルで失敗率が低いなら、少しずつ間隔を詰めてスループットを上げる
+            let faster = self.current_delay.mul_f64(0.9);
+            self.current_delay = faster.max(self.min_delay);
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        self.current_delay
+    }
+}
+
+/// gossipsub自身のmetricsモジュールやfuel-core-p2pの`P2P_METRICS`と同様に、
+/// prometheus-client でタスクスループット/割当レイテンシ/メッシュ健全性を計測し、
+/// 小さなHTTPエンドポイント（`/metrics`）で公開する。
+struct ManagerMetrics {
+    registry: Arc<Registry>,
+    tasks_generated: Counter,
+    tasks_assigned: Counter,
+    tasks_completed: Counter,
+    publish_failures: Counter,
+    subscribed_peers: Gauge,
+    mesh_size: Gauge,
+    task_latency_ms: Histogram,
+}
+
+impl ManagerMetrics {
+    fn new() -> Self {
+        let mut registry = Registry::default();
+
+        let tasks_generated = Counter::default();
+        registry.register(
+            "manager_tasks_generated",
+            "Total tasks generated by the manager",
+            tasks_generated.clone(),
+        );
+        let tasks_assigned = Counter::default();
+        registry.register(
+            "manager_tasks_assigned",
+            "Total tasks successfully dispatched to an agent",
+            tasks_assigned.clone(),
+        );
+        let tasks_completed = Counter::default();
+        registry.register(
+            "manager_tasks_completed",
+            "Total tasks acknowledged as done by an agent",
+            tasks_completed.clone(),
+        );
+        let publish_failures = Counter::default();
+        registry.register(
+            "manager_publish_failures",
+            "Total gossipsub publish failures",
+            publish_failures.clone(),
+        );
+        let subscribed_peers = Gauge::default();
+        registry.register(
+            "manager_subscribed_peers",
+            "Current number of peers subscribed to the mapd topic",
+            subscribed_peers.clone(),
+        );
+        let mesh_size = Gauge::default();
+        registry.register(
+            "manager_mesh_size",
+            "Approximate current gossipsub mesh size for the mapd topic",
+            mesh_size.clone(),
+        );
+        let task_latency_ms = Histogram::new(
+            [50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0].into_iter(),
+        );
+        registry.register(
+            "manager_task_latency_ms",
+            "Task round-trip latency from assignment to completion, in milliseconds",
+            task_latency_ms.clone(),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            tasks_generated,
+            tasks_assigned,
+            tasks_completed,
+            publish_failures,
+            subscribed_peers,
+            mesh_size,
+            task_latency_ms,
+        }
+    }
+}
+
+/// `/metrics`だけを返す最小限のHTTPエンドポイント。スケーリング試験(3/5/10エージェント)を
+/// println!のログではなく実際のカウンタ/ヒストグラムで観察できるようにする。
+async fn serve_metrics(registry: Arc<Registry>, addr: &str) {
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("⚠️  Failed to bind metrics endpoint on {addr}: {e}");
+            return;
+        }
+    };
+    println!("📊 Metrics available at http://{addr}/metrics");
+
     loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            // リクエストの中身は問わない。このエンドポイントはmetricsしか返さない。
+            let _ = stream.read(&mut buf).await;
+
+            let mut body = String::new();
+            let _ = encode(&mut body, &registry);
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// `next_action`が呼び出し元（`main`のループ）に
+/// 通知する、1ターン分の処理結果。stdinのパース・swarmイベント・タスク台帳更新が
+/// 単一の巨大な`select!`に混在していると、どれかの枝が同期処理で詰まった際に
+/// 他の枝（heartbeatやstdinコマンド）が飢餓を起こしうる。substrateの`NetworkWorker::next_action()`
+/// に倣い、1回の呼び出しで高々1つのswarm/タイマーイベントだけを処理してから
+/// 制御を返すことで、特定の枝が詰まっても他が必ず次のターンで進行できるようにする。
+#[derive(Debug)]
+enum ManagerEvent {
+    TaskAdvertised { task_id: u64 },
+    TaskClaimed { peer: libp2p::PeerId, task_id: u64 },
+    PeerDiscovered(libp2p::PeerId),
+    PeerExpired(libp2p::PeerId),
+    PeerTimedOut(libp2p::PeerId),
+    Idle,
+}
+
+/// manager本体が持つ全ての状態（swarmとタスク台帳）を1箇所にまとめたワーカー。
+/// `main`はこれを生成して`next_action`をループで呼ぶだけになり、`TestRunner`など
+/// 将来のテストハーネスは`cargo run`のサブプロセスを起動せずこのワーカーを
+/// 直接インプロセスで駆動して`ManagerEvent`を検証できる。
+struct ManagerWorker {
+    swarm: libp2p::Swarm<MapdBehaviour>,
+    topic: gossipsub::IdentTopic,
+    grid: Arc<Vec<Vec<char>>>,
+    stdin: io::Lines<io::BufReader<io::Stdin>>,
+    known_peers: HashSet<libp2p::PeerId>,
+    subscribed_peers: HashSet<libp2p::PeerId>,
+    peer_task_map: HashMap<libp2p::PeerId, Option<Task>>,
+    task_peer_map: HashMap<u64, libp2p::PeerId>,
+    task_counter: u64,
+    peer_positions: PositionCrdt,
+    // task_id -> 未claimのタスク。Kademliaにstart_providingした後、ClaimTaskで
+    // 最初に届いたリクエストだけがここから取り除かれ、以降のclaimは拒否される
+    unclaimed_tasks: HashMap<u64, Task>,
+    peer_manager: PeerManager,
+    pending_tasks: std::collections::VecDeque<Task>,
+    heartbeat_check: tokio::time::Interval,
+    dispatcher: OutboundDispatcher,
+    dispatch_tick: tokio::time::Interval,
+    metrics: ManagerMetrics,
+    // task_id -> 割当時刻。完了通知を受けてラウンドトリップレイテンシを計測するために使う
+    task_assigned_at: HashMap<u64, std::time::Instant>,
+    // バッチ配布の間隔を自己調整するペーサー
+    pacer: DispatchTranquilizer,
+}
+
+impl ManagerWorker {
+    async fn new() -> Result<Self, Box<dyn Error>> {
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_behaviour(|key| {
+                let message_id_fn = |message: &gossipsub::Message| {
+                    let mut s = DefaultHasher::new();
+                    message.data.hash(&mut s);
+                    gossipsub::MessageId::from(s.finish().to_string())
+                };
+
+                let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(Duration::from_millis(500)) // Heartbeat every 500ms
+                    .heartbeat_initial_delay(Duration::from_millis(100)) // Initial heartbeat after 100ms (immediate mesh construction)
+                    .mesh_n_low(1) // Minimum mesh peers set to 1 (default 4)
+                    .mesh_n(2) // Target mesh peers set to 2 (default 6)
+                    .mesh_n_high(3) // Maximum mesh peers set to 3 (default 12)
+                    .validation_mode(gossipsub::ValidationMode::Strict)
+                    .message_id_fn(message_id_fn)
+                    .build()
+                    .map_err(io::Error::other)?;
+
+                let gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?;
+
+                let mdns = mdns::tokio::Behaviour::new(
+                    mdns::Config::default(),
+                    key.public().to_peer_id(),
+                )?;
+
+                // タスクclaim確定とoccupied照会はgossipsubの全員フィルタ方式をやめ、
+                // 1対1のrequest-responseで直接やり取りする
+                let request_response = request_response::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/mapd/tswap/1.0.0"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                // タスクの実体はKademliaのprovideレコードとして置き、idleなエージェントが
+                // get_providers/get_recordで引き当てる市場を形成する。このプロセス自身が
+                // レコードを保持・提供するのでサーバーモードに固定する
+                let mut kad = kad::Behaviour::new(
+                    key.public().to_peer_id(),
+                    kad::store::MemoryStore::new(key.public().to_peer_id()),
+                );
+                kad.set_mode(Some(kad::Mode::Server));
+
+                Ok(MapdBehaviour {
+                    gossipsub,
+                    mdns,
+                    request_response,
+                    kad,
+                })
+            })?
+            .build();
+
+        let topic = gossipsub::IdentTopic::new("mapd");
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        println!("Peer ID: {}", swarm.local_peer_id());
+
+        // Create grid (pass appropriate grid in actual use)
+        let grid = Arc::new(parse_map());
+        let stdin = io::BufReader::new(io::stdin()).lines();
+
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+
+        println!(
+            "Enter messages via STDIN and they will be sent to connected peers using MAPD topic"
+        );
+        println!("Type 'task' to generate and send a task to agents.");
+        println!(
+            "⚠️  IMPORTANT: Wait 3-5 seconds after all agents connect before sending tasks (for Gossipsub mesh to form)!"
+        );
+        println!(
+            "💡 TIP: Look for '🔗 Peer XXX subscribed to topic: mapd' messages to confirm mesh is ready!"
+        );
+        println!("⏳ Waiting 2 seconds for initial Gossipsub mesh setup...");
+
+        // Wait for Gossipsub mesh initialization
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        println!("✅ Manager ready! Listening for agents...");
+
+        let metrics = ManagerMetrics::new();
+        tokio::spawn(serve_metrics(metrics.registry.clone(), "127.0.0.1:9898"));
+
+        Ok(Self {
+            swarm,
+            topic,
+            grid,
+            stdin,
+            known_peers: HashSet::new(),
+            subscribed_peers: HashSet::new(),
+            peer_task_map: HashMap::new(),
+            task_peer_map: HashMap::new(),
+            task_counter: 0,
+            peer_positions: PositionCrdt::new(),
+            unclaimed_tasks: HashMap::new(),
+            peer_manager: PeerManager::new(Duration::from_secs(10)),
+            pending_tasks: std::collections::VecDeque::new(),
+            heartbeat_check: tokio::time::interval(Duration::from_secs(3)),
+            dispatcher: OutboundDispatcher::new(64),
+            dispatch_tick: tokio::time::interval(Duration::from_millis(100)),
+            metrics,
+            task_assigned_at: HashMap::new(),
+            pacer: DispatchTranquilizer::new(),
+        })
+    }
+
+    /// タスクをKademliaの市場に並べる：provideレコードを置き、idleなエージェントへ
+    /// 軽量な`task_available`通知をgossipsubで流す。実際のタスク本体はget_record、
+    /// 割当の確定はrequest-responseのClaimTaskで行われる（このメソッドは通知するだけ）。
+    fn advertise_task(&mut self, mut task: Task) -> u64 {
+        let task_id = task.task_id.unwrap_or_else(|| {
+            self.task_counter += 1;
+            self.task_counter
+        });
+        task.task_id = Some(task_id);
+        task.peer_id = None;
+
+        let key = task_record_key(task_id);
+        if let Ok(value) = serde_json::to_vec(&task) {
+            let record = kad::Record::new(key.clone(), value);
+            let _ = self
+                .swarm
+                .behaviour_mut()
+                .kad
+                .put_record(record, kad::Quorum::One);
+        }
+        let _ = self.swarm.behaviour_mut().kad.start_providing(key);
+
+        self.unclaimed_tasks.insert(task_id, task.clone());
+        let announcement = serde_json::json!({"type": "task_available", "task_id": task_id}).to_string();
+        // 配布通知は優先メッセージ：キューが溢れても黙ってドロップしない
+        match self.dispatcher.enqueue(OutboundMessage {
+            priority: DispatchPriority::Priority,
+            data: announcement.into_bytes(),
+            task_id: Some(task_id),
+        }) {
+            Ok(()) => println!("📣 Advertised task {} on the Kademlia market: {:?}", task_id, task),
+            Err(DispatchError::QueueFull(_)) => {
+                println!("⚠️  Priority queue full, task_available notice for task {} dropped (task stays on the Kademlia market)", task_id);
+            }
+        }
+        task_id
+    }
+
+    /// 孤児になったタスクを市場へ再出品する
+    fn try_reassign_one_pending_task(&mut self) -> Option<ManagerEvent> {
+        let task = self.pending_tasks.pop_front()?;
+        let task_id = self.advertise_task(task);
+        Some(ManagerEvent::TaskAdvertised { task_id })
+    }
+
+    /// 1ターン分の処理を行い、結果を`ManagerEvent`として返す。
+    /// `select!`の各枝は独立に1イベントだけ処理して即座に戻るので、
+    /// どれか1つが重い処理（タスク生成・送信リトライ）をしても他の枝が飢餓しない。
+    async fn next_action(&mut self) -> ManagerEvent {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
+            _ = self.dispatch_tick.tick() => {
+                // 1 tickにつき1件だけ送出し、失敗した優先メッセージは先頭へ戻して次のtickでリトライする
+                if let Some(msg) = self.dispatcher.pop_next() {
+                    match self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), msg.data.clone()) {
+                        Ok(_) => {
+                            if let Some(task_id) = msg.task_id {
+                                println!("📤 Dispatched queued message for task {}", task_id);
+                            }
+                        }
+                        Err(e) => {
+                            self.metrics.publish_failures.inc();
+                            println!("⚠️  Publish failed ({:?}): {:?}", msg.priority, e);
+                            if msg.task_id.is_some() {
+                                self.pacer.record(false);
+                            }
+                            if msg.priority == DispatchPriority::Priority {
+                                self.dispatcher.requeue_front(msg);
+                            }
+                        }
+                    }
+                }
+                ManagerEvent::Idle
+            }
+            _ = self.heartbeat_check.tick() => {
+                let mut last_timeout = None;
+                for dead_peer in self.peer_manager.reap_dead_peers() {
+                    self.known_peers.remove(&dead_peer);
+                    self.subscribed_peers.remove(&dead_peer);
+                    if let Some(Some(lost_task)) = self.peer_task_map.remove(&dead_peer) {
+                        if let Some(task_id) = lost_task.task_id {
+                            self.task_peer_map.remove(&task_id);
+                        }
+                        println!("💀 Peer {} timed out, reclaiming its task: {:?}", dead_peer, lost_task);
+                        self.pending_tasks.push_back(lost_task);
+                    } else {
+                        println!("💀 Peer {} timed out", dead_peer);
+                    }
+                    last_timeout = Some(dead_peer);
+                }
+
+                if let Some(event) = self.try_reassign_one_pending_task() {
+                    return event;
+                }
+                match last_timeout {
+                    Some(peer) => ManagerEvent::PeerTimedOut(peer),
+                    None => ManagerEvent::Idle,
+                }
+            }
+            Ok(Some(line)) = self.stdin.next_line() => {
                 if line.trim() == "task" {
                     // Gossipsubから実際に購読しているピアを取得して同期
-                    for peer in swarm.behaviour_mut().gossipsub.all_peers() {
+                    for peer in self.swarm.behaviour_mut().gossipsub.all_peers() {
                         if peer.1.iter().any(|t| t.as_str() == "mapd") {
-                            subscribed_peers.insert(peer.0.clone());
+                            self.subscribed_peers.insert(peer.0.clone());
                         }
                     }
 
-                    println!("Known peers (mDNS): {:?}", known_peers);
-                    println!("Subscribed peers (Gossipsub): {:?}", subscribed_peers);
-                    println!("📡 Sending tasks to subscribed peers...");
-
-                    let mut assigned = false;
-
-                    // subscribed_peersのみに送信
-                    for peer_id in &subscribed_peers {
-                        let busy = peer_task_map.get(peer_id).and_then(|t| t.as_ref()).is_some();
-                        if !busy {
-                            if let Some(mut task) = task_gen.generate_task() {
-                                // タスクIDを付与
-                                task_counter += 1;
-                                let task_id = task_counter;
-                                task.peer_id = Some(peer_id.to_base58());
-                                task.task_id = Some(task_id);
-                                match serde_json::to_vec(&task) {
-                                    Ok(task_bytes) => {
-                                        match swarm.behaviour_mut().gossipsub.publish(topic.clone(), task_bytes) {
-                                            Ok(_) => {
-                                                println!("✅ Task sent to {peer_id}: {:?}", task);
-                                                peer_task_map.insert(peer_id.clone(), Some(task.clone()));
-                                                task_peer_map.insert(task_id, peer_id.clone());
-                                                assigned = true;
-                                            }
-                                            Err(e) => {
-                                                println!("⚠️  Task publish error for {peer_id}: {e:?}");
-                                            }
-                                        }
-                                    },
-                                    Err(e) => println!("Task serialization error: {e:?}"),
-                                }
-                                tokio::time::sleep(Duration::from_millis(300)).await;
-                            } else {
-                                println!("Task generation failed (not enough free cells)");
-                            }
-                        }
-                    }
+                    println!("Known peers (mDNS): {:?}", self.known_peers);
+                    println!("Subscribed peers (Gossipsub): {:?}", self.subscribed_peers);
+
+                    let idle_count = self
+                        .subscribed_peers
+                        .iter()
+                        .filter(|p| self.peer_task_map.get(*p).and_then(|t| t.as_ref()).is_none())
+                        .count();
 
-                    if !assigned {
-                        if subscribed_peers.is_empty() {
+                    if idle_count == 0 {
+                        if self.subscribed_peers.is_empty() {
                             println!("⚠️  No peers have subscribed to the topic yet.");
                             println!("💡 Tip: Wait for '🔗 Peer XXX subscribed to topic: mapd' messages, then try 'task' again.");
                         } else {
                             println!("⚠️  All subscribed peers are busy with tasks.");
                         }
+                        ManagerEvent::Idle
+                    } else {
+                        println!("📣 Putting {} task(s) up on the Kademlia market for idle agents to claim...", idle_count);
+                        let mut last_advertised = None;
+                        for _ in 0..idle_count {
+                            let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                            if let Some(task) = task_gen.generate_task() {
+                                self.metrics.tasks_generated.inc();
+                                last_advertised = Some(self.advertise_task(task));
+                                // 配布は一旦成功とみなし、dispatch_tickでpublishが失敗したらその失敗も記録する
+                                self.pacer.record(true);
+                                tokio::time::sleep(self.pacer.delay()).await;
+                            } else {
+                                println!("Task generation failed (not enough free cells)");
+                                break;
+                            }
+                        }
+                        match last_advertised {
+                            Some(task_id) => ManagerEvent::TaskAdvertised { task_id },
+                            None => ManagerEvent::Idle,
+                        }
                     }
                 } else {
-                    if let Err(e) = swarm
-                        .behaviour_mut().gossipsub
-                        .publish(topic.clone(), line.as_bytes()) {
-                        println!("Publish error: {e:?}");
-                    }
+                    // 手入力のチャットメッセージは非優先：輻輳時は間引かれてよい
+                    let _ = self.dispatcher.enqueue(OutboundMessage {
+                        priority: DispatchPriority::NonPriority,
+                        data: line.as_bytes().to_vec(),
+                        task_id: None,
+                    });
+                    ManagerEvent::Idle
                 }
             }
-            event = swarm.select_next_some() => match event {
+            event = self.swarm.select_next_some() => match event {
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    let mut last_discovered = None;
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discovered a new peer: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                        known_peers.insert(peer_id.clone());
-                        peer_task_map.entry(peer_id.clone()).or_insert(None);
+                        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        self.known_peers.insert(peer_id.clone());
+                        self.peer_task_map.entry(peer_id.clone()).or_insert(None);
+                        self.peer_manager.touch(peer_id.clone());
 
                         // 少し待ってからGossipsubの購読状態をチェック
                         tokio::time::sleep(Duration::from_millis(500)).await;
 
                         // ピアがトピックに購読しているかチェック
-                        for peer_info in swarm.behaviour_mut().gossipsub.all_peers() {
+                        for peer_info in self.swarm.behaviour_mut().gossipsub.all_peers() {
                             if peer_info.0 == &peer_id && peer_info.1.iter().any(|t| t.as_str() == "mapd") {
-                                subscribed_peers.insert(peer_id.clone());
+                                self.subscribed_peers.insert(peer_id.clone());
                                 println!("   ✅ Peer {} is already subscribed to 'mapd'", peer_id);
                                 break;
                             }
                         }
+                        last_discovered = Some(peer_id);
+                    }
+                    match last_discovered {
+                        Some(peer) => ManagerEvent::PeerDiscovered(peer),
+                        None => ManagerEvent::Idle,
                     }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                    let mut last_expired = None;
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                        known_peers.remove(&peer_id);
-                        subscribed_peers.remove(&peer_id);
-                        peer_task_map.remove(&peer_id);
+                        self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        self.known_peers.remove(&peer_id);
+                        self.subscribed_peers.remove(&peer_id);
+                        self.peer_task_map.remove(&peer_id);
+                        self.peer_manager.forget(&peer_id);
+                        let ts = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap()
+                            .as_millis();
+                        self.peer_positions.remove(&peer_id.to_string(), ts);
+                        last_expired = Some(peer_id);
+                    }
+                    match last_expired {
+                        Some(peer) => ManagerEvent::PeerExpired(peer),
+                        None => ManagerEvent::Idle,
                     }
                 },
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
                     println!("🔗 Peer {} subscribed to topic: {}", peer_id, topic);
-                    subscribed_peers.insert(peer_id);
-                    println!("   ✅ Total subscribed peers: {}", subscribed_peers.len());
+                    self.subscribed_peers.insert(peer_id);
+                    println!("   ✅ Total subscribed peers: {}", self.subscribed_peers.len());
+                    self.metrics.subscribed_peers.set(self.subscribed_peers.len() as i64);
+                    // 厳密なメッシュサイズではなく、購読ピア数で近似する
+                    self.metrics.mesh_size.set(self.subscribed_peers.len() as i64);
+                    ManagerEvent::Idle
                 }
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
                     println!("❌ Peer {} unsubscribed from topic: {}", peer_id, topic);
-                    subscribed_peers.remove(&peer_id);
+                    self.subscribed_peers.remove(&peer_id);
+                    self.metrics.subscribed_peers.set(self.subscribed_peers.len() as i64);
+                    self.metrics.mesh_size.set(self.subscribed_peers.len() as i64);
+                    ManagerEvent::Idle
                 }
                 SwarmEvent::Behaviour(MapdBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                     propagation_source: peer_id,
                     message_id: _id,
                     message,
                 })) => {
+                    self.peer_manager.touch(peer_id.clone());
                     let msg_str = String::from_utf8_lossy(&message.data);
 
-                    // occupied_requestの処理
+                    // occupied_requestはrequest_responseに移行済み（下のRequestResponse腕を参照）
                     if let Ok(request) = serde_json::from_str::<serde_json::Value>(&msg_str) {
-                        if request.get("type") == Some(&serde_json::Value::String("occupied_request".to_string())) {
-                            println!("📍 Received occupied_request from {peer_id}");
-
-                            // 現在占有されている位置のリストを作成
-                            let occupied: Vec<(usize, usize)> = peer_positions.values().cloned().collect();
-
-                            // タイムスタンプを追加して毎回ユニークなメッセージにする
-                            let timestamp = std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis();
-
-                            let response = serde_json::json!({
-                                "type": "occupied_response",
-                                "occupied": occupied,
-                                "timestamp": timestamp,
-                                "from_peer": peer_id.to_base58()
-                            });
-
-                            if let Ok(response_bytes) = serde_json::to_vec(&response) {
-                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), response_bytes) {
-                                    println!("⚠️  Failed to send occupied_response: {e:?}");
-                                } else {
-                                    println!("✅ Sent occupied_response with {} positions (timestamp: {})", occupied.len(), timestamp);
-                                }
-                            }
-                            continue;
-                        }
-
                         // 位置情報の更新（position_updateメッセージ）
                         if request.get("type") == Some(&serde_json::Value::String("position_update".to_string())) {
                             if let (Some(peer_id_str), Some(pos)) = (
@@ -263,12 +886,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             ) {
                                 if pos.len() == 2 {
                                     if let (Some(x), Some(y)) = (pos[0].as_u64(), pos[1].as_u64()) {
-                                        peer_positions.insert(peer_id_str.to_string(), (x as usize, y as usize));
-                                        println!("📍 Updated position for {}: ({}, {})", peer_id_str, x, y);
+                                        let ts = request
+                                            .get("timestamp")
+                                            .and_then(|v| v.as_u64())
+                                            .map(|v| v as u128)
+                                            .unwrap_or_else(|| {
+                                                std::time::SystemTime::now()
+                                                    .duration_since(std::time::UNIX_EPOCH)
+                                                    .unwrap()
+                                                    .as_millis()
+                                            });
+                                        self.peer_positions.merge(
+                                            peer_id_str.to_string(),
+                                            ts,
+                                            Deletable::Value((x as usize, y as usize)),
+                                        );
+                                        println!("📍 Merged position delta for {}: ({}, {})", peer_id_str, x, y);
                                     }
                                 }
                             }
-                            continue;
+                            return ManagerEvent::Idle;
                         }
                     }
 
@@ -277,36 +914,156 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         if done_msg.get("status") == Some(&serde_json::Value::String("done".to_string())) {
                             let task_id = done_msg.get("task_id").and_then(|v| v.as_u64());
                             println!("Received task completion notification: {peer_id}, task_id: {:?}", task_id);
-                            peer_task_map.insert(peer_id.clone(), None);
-                            // 新しいタスクを生成して配布
-                            if let Some(mut task) = task_gen.generate_task() {
-                                task_counter += 1;
-                                let new_task_id = task_counter;
-                                task.peer_id = Some(peer_id.to_base58());
-                                task.task_id = Some(new_task_id);
-                                match serde_json::to_vec(&task) {
-                                    Ok(task_bytes) => {
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), task_bytes) {
-                                            println!("Task publish error: {e:?}");
-                                        } else {
-                                            println!("Task sent to {peer_id}: {:?}", task);
-                                            peer_task_map.insert(peer_id.clone(), Some(task.clone()));
-                                            task_peer_map.insert(new_task_id, peer_id.clone());
-                                        }
-                                    },
-                                    Err(e) => println!("Task serialization error: {e:?}"),
+                            self.peer_task_map.insert(peer_id.clone(), None);
+                            if let Some(task_id) = task_id {
+                                self.metrics.tasks_completed.inc();
+                                if let Some(started_at) = self.task_assigned_at.remove(&task_id) {
+                                    self.metrics
+                                        .task_latency_ms
+                                        .observe(started_at.elapsed().as_millis() as f64);
                                 }
+                            }
+                            // 空いた分の新しいタスクを生成し、市場に並べる（特定のピアへは push しない）
+                            let mut task_gen = TaskGeneratorAgent::new(&self.grid);
+                            if let Some(task) = task_gen.generate_task() {
+                                self.metrics.tasks_generated.inc();
+                                let new_task_id = self.advertise_task(task);
+                                println!("Freed capacity from {peer_id}; advertised task {} to the market", new_task_id);
                             } else {
                                 println!("Task generation failed (not enough free cells)");
                             }
                         }
                     }
+                    ManagerEvent::Idle
                 },
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Local node is listening on {address}");
+                    ManagerEvent::Idle
                 }
-                _ => {}
+                SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                    request_response::Event::Message { peer, message },
+                )) => {
+                    self.peer_manager.touch(peer.clone());
+                    match message {
+                        request_response::Message::Request { request, channel, .. } => match request {
+                            TswapRequest::QueryOccupied => {
+                                let occupied: Vec<(usize, usize)> = self.peer_positions.occupied();
+                                let _ = self.swarm
+                                    .behaviour_mut()
+                                    .request_response
+                                    .send_response(channel, TswapResponse::OccupiedSet(occupied));
+                                ManagerEvent::Idle
+                            }
+                            TswapRequest::ClaimTask(task_id) => {
+                                match claim_unclaimed_task(&mut self.unclaimed_tasks, task_id, &peer.to_base58()) {
+                                    Some(task) => {
+                                        let key = task_record_key(task_id);
+                                        self.swarm.behaviour_mut().kad.stop_providing(&key);
+                                        self.swarm.behaviour_mut().kad.remove_record(&key);
+                                        self.peer_task_map.insert(peer.clone(), Some(task.clone()));
+                                        self.task_peer_map.insert(task_id, peer.clone());
+                                        self.task_assigned_at.insert(task_id, std::time::Instant::now());
+                                        if self.swarm.behaviour_mut().request_response.send_response(
+                                            channel,
+                                            TswapResponse::ClaimResult { task_id, task: Some(task.clone()) },
+                                        ).is_err() {
+                                            // 確定応答がピアに届かなかった：このclaimは成立しなかったものとして
+                                            // 割当を巻き戻し、タスクを市場に戻す（silentに失う代わりに再掲）
+                                            println!(
+                                                "⚠️  Failed to send ClaimResult to {} for task {}; rolling back and re-advertising",
+                                                peer, task_id
+                                            );
+                                            self.peer_task_map.insert(peer.clone(), None);
+                                            self.task_peer_map.remove(&task_id);
+                                            self.task_assigned_at.remove(&task_id);
+                                            self.advertise_task(task);
+                                            return ManagerEvent::Idle;
+                                        }
+                                        self.metrics.tasks_assigned.inc();
+                                        println!("🎯 Task {} claimed by {}", task_id, peer);
+                                        ManagerEvent::TaskClaimed { peer: peer.clone(), task_id }
+                                    }
+                                    None => {
+                                        // 既に他のエージェントにclaimされていた：空振りを正直に伝える
+                                        let _ = self.swarm.behaviour_mut().request_response.send_response(
+                                            channel,
+                                            TswapResponse::ClaimResult { task_id, task: None },
+                                        );
+                                        ManagerEvent::Idle
+                                    }
+                                }
+                            }
+                        },
+                        request_response::Message::Response { response, .. } => {
+                            // マネージャーはClaimTask/QueryOccupiedの送り手ではないので、
+                            // ここにレスポンスが来るのは想定外のケースのみ
+                            println!("⚠️  Unexpected response from {peer}: {:?}", response);
+                            ManagerEvent::Idle
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MapdBehaviourEvent::RequestResponse(
+                    request_response::Event::OutboundFailure { peer, error, .. },
+                )) => {
+                    println!("⚠️  Outbound request to {} failed: {:?}", peer, error);
+                    ManagerEvent::Idle
+                }
+                _ => ManagerEvent::Idle,
             }
         }
     }
 }
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut worker = ManagerWorker::new().await?;
+    loop {
+        let _event = worker.next_action().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_with_id(task_id: u64) -> Task {
+        Task {
+            pickup: (0, 0),
+            delivery: (1, 1),
+            peer_id: None,
+            task_id: Some(task_id),
+            priority: 0,
+            deadline_ms: None,
+        }
+    }
+
+    #[test]
+    fn claim_unclaimed_task_assigns_peer_and_removes_from_the_market() {
+        let mut unclaimed = HashMap::new();
+        unclaimed.insert(1, task_with_id(1));
+
+        let claimed = claim_unclaimed_task(&mut unclaimed, 1, "agent-peer").expect("task 1 should be claimable");
+        assert_eq!(claimed.peer_id.as_deref(), Some("agent-peer"));
+        assert!(
+            !unclaimed.contains_key(&1),
+            "a claimed task must be taken off the market so a second claim can't also win it"
+        );
+    }
+
+    #[test]
+    fn claim_unclaimed_task_is_none_once_already_claimed() {
+        let mut unclaimed = HashMap::new();
+        unclaimed.insert(1, task_with_id(1));
+
+        assert!(claim_unclaimed_task(&mut unclaimed, 1, "first-agent").is_some());
+        // 同じtask_idに対する2回目のclaimは、先に取られていれば空振りになるべき
+        // (このレースの決着が「アイドルなエージェント同士がタスクを取り合う」タスク市場の根幹)
+        assert!(claim_unclaimed_task(&mut unclaimed, 1, "second-agent").is_none());
+    }
+
+    #[test]
+    fn claim_unclaimed_task_is_none_for_unknown_task_id() {
+        let mut unclaimed: HashMap<u64, Task> = HashMap::new();
+        assert!(claim_unclaimed_task(&mut unclaimed, 42, "agent-peer").is_none());
+    }
+}
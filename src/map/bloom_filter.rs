@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// ビット数とハッシュ数(k)を自由に選べる汎用Bloomフィルタ。外部クレートを増やさず、
+/// 既存の`DefaultHasher`をソルト(シード値)ごとに取り直すことでk個の独立したハッシュ位置を得る
+/// （CRDS方式のダブルハッシュ法より単純だが、kを大きくすれば誤検知率は同程度に下げられる）。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_hashes: usize,
+}
+
+impl BloomFilter {
+    pub fn new(num_bits: usize, num_hashes: usize) -> Self {
+        let num_bits = num_bits.max(64);
+        let words = num_bits.div_ceil(64);
+        Self {
+            bits: vec![0u64; words],
+            num_bits,
+            num_hashes: num_hashes.max(1),
+        }
+    }
+
+    fn position(&self, key: &str, seed: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        key.hash(&mut hasher);
+        (hasher.finish() % self.num_bits as u64) as usize
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for seed in 0..self.num_hashes {
+            let pos = self.position(key, seed);
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    /// falseなら確実に未挿入。trueは「挿入済みかもしれない」(偽陽性あり得る)。
+    pub fn might_contain(&self, key: &str) -> bool {
+        (0..self.num_hashes).all(|seed| {
+            let pos = self.position(key, seed);
+            self.bits[pos / 64] & (1 << (pos % 64)) != 0
+        })
+    }
+}
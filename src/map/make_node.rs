@@ -1,6 +1,7 @@
 use crate::map;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use rand::Rng;
 
 pub fn get_free_cells(map: &[Vec<char>]) -> Vec<map::map::Point> {
     let mut free = Vec::new();
@@ -47,3 +48,74 @@ pub fn generate_start_positions(grid: &[Vec<char>], agent_count: usize) -> Vec<m
     free.shuffle(&mut rand::thread_rng());
     free.into_iter().take(agent_count).collect()
 }
+
+/// セル`cell`が各ホットスポットからどれだけ重みを受け取るかを合計する。`generate_start_goal_pairs`
+/// の一様シャッフルと違い、倉庫のピッキングラックやドック前のように需要が特定エリアに
+/// 偏る状況をモデル化するために使う。ホットスポットの外側でも一様に選ばれ得るよう、
+/// 基礎重み1.0に各ホットスポットの寄与（中心からの距離に応じて線形に減衰し、
+/// `falloff_radius`の外では0になる）を足し合わせる。
+fn weight_for_cell(
+    cell: map::map::Point,
+    hotspots: &[(map::map::Point, f64)],
+    falloff_radius: f64,
+) -> f64 {
+    const BASE_WEIGHT: f64 = 1.0;
+    let mut weight = BASE_WEIGHT;
+    for &(center, hotspot_weight) in hotspots {
+        let dx = cell.0 as f64 - center.0 as f64;
+        let dy = cell.1 as f64 - center.1 as f64;
+        let dist = (dx * dx + dy * dy).sqrt();
+        if dist < falloff_radius {
+            weight += hotspot_weight * (1.0 - dist / falloff_radius);
+        }
+    }
+    weight
+}
+
+/// 累積分布(CDF)に対する乱数drawで、`cells`から重み付きで1つ選ぶ。
+fn sample_weighted_cell(
+    cells: &[map::map::Point],
+    hotspots: &[(map::map::Point, f64)],
+    falloff_radius: f64,
+    rng: &mut impl Rng,
+) -> map::map::Point {
+    let weights: Vec<f64> = cells
+        .iter()
+        .map(|&cell| weight_for_cell(cell, hotspots, falloff_radius))
+        .collect();
+    let total_weight: f64 = weights.iter().sum();
+
+    let mut draw = rng.gen::<f64>() * total_weight;
+    for (&cell, &weight) in cells.iter().zip(weights.iter()) {
+        if draw < weight {
+            return cell;
+        }
+        draw -= weight;
+    }
+    // 浮動小数点の丸め誤差でdrawが尽きなかった場合のフォールバック
+    *cells.last().expect("cells must not be empty")
+}
+
+/// `generate_start_goal_pairs`の重み付き版。ピックアップ/デリバリー地点を、
+/// `(中心, 重み)`で表されるホットスポット（例えば保管棚や搬出ドック）の近くに
+/// 偏らせてサンプリングする。各地点は累積重みに比例する確率で独立に選ばれるため、
+/// `generate_start_goal_pairs`と違い同じセルが重複して選ばれる可能性がある点に注意。
+/// ベンチマークで現実的な倉庫トラフィック（ピックアップは棚に集中、デリバリーは
+/// ドアに集中、のような偏った需要）を再現したいときに使う。
+pub fn generate_start_goal_pairs_weighted(
+    map: &[Vec<char>],
+    agent_count: usize,
+    hotspots: &[(map::map::Point, f64)],
+    falloff_radius: f64,
+) -> Vec<(map::map::Point, map::map::Point)> {
+    let free_cells = get_free_cells(map);
+    let mut rng = thread_rng();
+
+    (0..agent_count)
+        .map(|_| {
+            let pickup = sample_weighted_cell(&free_cells, hotspots, falloff_radius, &mut rng);
+            let delivery = sample_weighted_cell(&free_cells, hotspots, falloff_radius, &mut rng);
+            (pickup, delivery)
+        })
+        .collect()
+}
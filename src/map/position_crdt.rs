@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// `peer_id`が今どの段階にいるかを表す、マネージャーの`TaskPhase`に対応するゴシップ可能な版。
+/// タスクの割り当て自体はマネージャーが権威を持つが、エージェント自身が申告する現在の段階も
+/// レプリカへ含めておくことで、後から参加した別のマネージャーが誰にも聞かずに復元できる。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AgentTaskPhase {
+    Idle,
+    MovingToPickup,
+    MovingToDelivery,
+}
+
+/// 1エージェントぶんの状態。LWW(last-writer-wins)レジスタとしてマージされる。
+/// `version`はそのピア自身が発行時に単調増加させる値で、通信経路の遅延や再送による
+/// 前後関係のブレを`wallclock_ms`だけに頼らず判定できるようにする。
+/// `tombstone`はエージェントがタスクを終えて離脱したことを示す削除マーカー。ただ取り除くのではなく
+/// `(version, wallclock_ms)`で順序づけられる1つの更新として扱うことで、後から届いた古い
+/// position_updateがエントリを復活させてしまう事態を防ぐ。
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VersionedAgentState {
+    pub current_pos: (usize, usize),
+    pub goal_pos: Option<(usize, usize)>,
+    pub task_phase: AgentTaskPhase,
+    pub version: u64,
+    pub wallclock_ms: u64,
+    pub tombstone: bool,
+}
+
+/// `mapd`トピックでブロードキャストするagent_stateメッセージの全体形。`position_update`など
+/// 既存の生JSON系メッセージと同じく`type`フィールドで判別できるようにしつつ、ペイロード本体は
+/// `AgentStateCrdt::merge`へそのまま渡せる`VersionedAgentState`に寄せてある。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentStateGossip {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub peer_id: String,
+    #[serde(flatten)]
+    pub state: VersionedAgentState,
+}
+
+impl AgentStateGossip {
+    pub fn new(peer_id: String, state: VersionedAgentState) -> Self {
+        Self {
+            kind: "agent_state".to_string(),
+            peer_id,
+            state,
+        }
+    }
+}
+
+/// `peer_id`ごとのLWWレジスタの集合。Solanaの`cluster_info`のゴシップCRDT
+/// (「更新のたびに常に最新versionが採用される」peer-key→versioned-valueのマップ)を手本にした、
+/// エージェント状態のレプリカ。毎tick再送される状態更新を、生JSON比較ではなく
+/// `(version, wallclock_ms)`の比較だけで冪等かつ順序非依存にマージできるようにする。
+/// 届いた順序に関わらず、後から参加したマネージャー(やピア)も同じ最終状態に収束する。
+#[derive(Clone, Debug, Default)]
+pub struct AgentStateCrdt {
+    entries: HashMap<String, VersionedAgentState>,
+}
+
+impl AgentStateCrdt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `peer_id`の更新を取り込む。既存エントリより`version`が大きい場合、または`version`が
+    /// 同値で`wallclock_ms`が大きい場合だけ採用し、それ以外は黙って無視する
+    /// （重複配送や順序の入れ替わりをべき等に吸収する）。採用してエントリを更新したら`true`を返す。
+    pub fn merge(&mut self, peer_id: &str, update: VersionedAgentState) -> bool {
+        let should_replace = match self.entries.get(peer_id) {
+            None => true,
+            Some(existing) => {
+                (update.version, update.wallclock_ms) > (existing.version, existing.wallclock_ms)
+            }
+        };
+        if should_replace {
+            self.entries.insert(peer_id.to_string(), update);
+        }
+        should_replace
+    }
+
+    /// トゥームストーン化されたエントリを取り除く。マネージャーの定期クリーンアップが呼び、
+    /// 離脱済みエージェントのレコードがメモリに残り続けないようにする。
+    pub fn reap_tombstones(&mut self) {
+        self.entries.retain(|_, state| !state.tombstone);
+    }
+
+    /// 現時点で収束している、トゥームストーンを除く1ピアの状態。
+    pub fn get(&self, peer_id: &str) -> Option<&VersionedAgentState> {
+        self.entries.get(peer_id).filter(|state| !state.tombstone)
+    }
+
+    /// 現時点で収束している、トゥームストーンを除く全ピアの状態のスナップショット。
+    pub fn snapshot(&self) -> HashMap<String, VersionedAgentState> {
+        self.entries
+            .iter()
+            .filter(|(_, state)| !state.tombstone)
+            .map(|(peer_id, state)| (peer_id.clone(), *state))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(version: u64, wallclock_ms: u64) -> VersionedAgentState {
+        VersionedAgentState {
+            current_pos: (0, 0),
+            goal_pos: None,
+            task_phase: AgentTaskPhase::Idle,
+            version,
+            wallclock_ms,
+            tombstone: false,
+        }
+    }
+
+    #[test]
+    fn merge_adopts_higher_version() {
+        let mut crdt = AgentStateCrdt::new();
+        assert!(crdt.merge("p1", state(1, 100)));
+        assert_eq!(crdt.get("p1").unwrap().version, 1);
+
+        assert!(crdt.merge("p1", state(2, 50)));
+        assert_eq!(
+            crdt.get("p1").unwrap().version,
+            2,
+            "a higher version must win even with an older wallclock_ms"
+        );
+    }
+
+    #[test]
+    fn merge_ignores_stale_or_duplicate_updates() {
+        let mut crdt = AgentStateCrdt::new();
+        assert!(crdt.merge("p1", state(5, 100)));
+
+        // 同じ(version, wallclock_ms)の再送：べき等に無視されるべき
+        assert!(!crdt.merge("p1", state(5, 100)));
+        // 古いversion：順序の入れ替わりで届いても無視されるべき
+        assert!(!crdt.merge("p1", state(4, 1_000)));
+        assert_eq!(crdt.get("p1").unwrap().wallclock_ms, 100);
+    }
+
+    #[test]
+    fn merge_breaks_version_tie_with_wallclock() {
+        let mut crdt = AgentStateCrdt::new();
+        assert!(crdt.merge("p1", state(3, 100)));
+        assert!(crdt.merge("p1", state(3, 200)));
+        assert_eq!(
+            crdt.get("p1").unwrap().wallclock_ms,
+            200,
+            "equal versions should fall back to comparing wallclock_ms"
+        );
+    }
+
+    #[test]
+    fn reap_tombstones_removes_deleted_entries_from_get_and_snapshot() {
+        let mut crdt = AgentStateCrdt::new();
+        crdt.merge("p1", state(1, 100));
+        let mut tombstoned = state(2, 200);
+        tombstoned.tombstone = true;
+        crdt.merge("p1", tombstoned);
+
+        // トゥームストーンはreap前でもget/snapshotからは見えない
+        assert!(crdt.get("p1").is_none());
+        assert!(crdt.snapshot().is_empty());
+
+        crdt.reap_tombstones();
+        assert!(crdt.get("p1").is_none());
+    }
+}
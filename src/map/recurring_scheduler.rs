@@ -0,0 +1,110 @@
+use crate::map::task_generator::{Task, TaskGeneratorAgent};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// `RecurringTaskScheduler`が`BinaryHeap`で保持する1つの定期登録。[`TaskScheduler`](super::task_scheduler::TaskScheduler)の
+/// 「送信済みタスクの再送」とは別物で、こちらは「一定間隔で新しいタスクを生み出す」ジョブ登録そのものを表す。
+/// `next_run`が最も近いものを先頭に取り出したいので、`Ord`は`next_run`の大小関係を反転させて
+/// `BinaryHeap`（最大heap）を疑似的な最小heapとして使う。
+struct ScheduleEntry {
+    #[allow(dead_code)]
+    id: u64,
+    interval: Duration,
+    priority: u8,
+    deadline_ms: Option<u64>,
+    next_run: Instant,
+}
+
+impl PartialEq for ScheduleEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.next_run == other.next_run
+    }
+}
+impl Eq for ScheduleEntry {}
+
+impl PartialOrd for ScheduleEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for ScheduleEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.next_run.cmp(&self.next_run)
+    }
+}
+
+/// 一定間隔で新しいタスクを生成し続ける、優先度と期限つきの定期スケジューラ。
+/// `BinaryHeap`でnext_runが最も近い登録を管理し、`poll_due`が期限到来ぶんだけ
+/// `TaskGeneratorAgent`にタスクを作らせて返す（期限の来た登録が複数あれば1回の呼び出しでまとめて返すが、
+/// 1つの登録が何間隔ぶん遅れていても生成するタスクは1つだけで、取りこぼした間隔ぶんは補わない）。
+pub struct RecurringTaskScheduler {
+    entries: BinaryHeap<ScheduleEntry>,
+    next_id: u64,
+}
+
+impl RecurringTaskScheduler {
+    pub fn new() -> Self {
+        Self {
+            entries: BinaryHeap::new(),
+            next_id: 1,
+        }
+    }
+
+    /// `interval`おきに優先度`priority`・期限`deadline_ms`（タスク生成時からの相対ミリ秒、
+    /// `None`なら期限なし）のタスクを生み出す定期登録を追加し、その登録IDを返す。
+    pub fn register(&mut self, interval: Duration, priority: u8, deadline_ms: Option<u64>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(ScheduleEntry {
+            id,
+            interval,
+            priority,
+            deadline_ms,
+            next_run: Instant::now() + interval,
+        });
+        id
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 期限の来た登録をすべて取り出し、それぞれ1つずつタスクを生成して返す。取り出した登録は
+    /// `next_run`を現在時刻の1間隔後に設定して積み直すので、呼び出し側が`poll_due`をtickごとに呼ぶだけで
+    /// 定期実行が継続する。
+    pub fn poll_due(&mut self, task_gen: &mut TaskGeneratorAgent) -> Vec<Task> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(entry) = self.entries.peek() {
+            if entry.next_run > now {
+                break;
+            }
+            due.push(self.entries.pop().unwrap());
+        }
+
+        let mut tasks = Vec::new();
+        for mut entry in due {
+            if let Some(task) = task_gen.generate_scheduled_task(entry.priority, entry.deadline_ms) {
+                tasks.push(task);
+            }
+            entry.next_run = now + entry.interval;
+            self.entries.push(entry);
+        }
+        tasks
+    }
+}
+
+impl Default for RecurringTaskScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
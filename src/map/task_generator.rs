@@ -9,6 +9,10 @@ pub struct Task {
     pub delivery: map::map::Point,
     pub peer_id: Option<String>, // タスクの宛先peer id (Base58文字列)
     pub task_id: Option<u64>,    // タスクID
+    #[serde(default)]
+    pub priority: u8, // 大きいほど優先。手動投入や旧バージョンとの互換のため既定は0
+    #[serde(default)]
+    pub deadline_ms: Option<u64>, // タスク生成時からの相対ミリ秒。Noneなら期限なし
 }
 
 pub struct TaskGeneratorAgent<'a> {
@@ -32,9 +36,19 @@ impl<'a> TaskGeneratorAgent<'a> {
             delivery,
             peer_id: None,
             task_id: None,
+            priority: 0,
+            deadline_ms: None,
         })
     }
 
+    /// `generate_task`に加えて、スケジューラが登録した優先度と期限を刻んだタスクを作る。
+    pub fn generate_scheduled_task(&mut self, priority: u8, deadline_ms: Option<u64>) -> Option<Task> {
+        let mut task = self.generate_task()?;
+        task.priority = priority;
+        task.deadline_ms = deadline_ms;
+        Some(task)
+    }
+
     pub fn generate_multiple_tasks(&mut self, count: usize) -> Vec<Task> {
         let mut tasks = Vec::new();
         for _ in 0..count {
@@ -1,6 +1,18 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// 最近接順位法(nearest-rank)でp百分位数のインデックスを求める。
+/// `idx = ceil(p/100 * n) - 1`を`[0, n-1]`にクランプする。
+fn nearest_rank_index(p: f64, n: usize) -> usize {
+    if n == 0 {
+        return 0;
+    }
+    let idx = (p / 100.0 * n as f64).ceil() as isize - 1;
+    idx.clamp(0, n as isize - 1) as usize
+}
 
 /// タスク計測情報
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -12,6 +24,8 @@ pub struct TaskMetric {
     pub start_time: Option<u64>, // agentがタスク処理を開始した時刻
     pub completion_time: Option<u64>, // agentがタスク完了した時刻
     pub status: TaskStatus, // タスクの状態
+    #[serde(default)]
+    pub deadline_at_risk: bool, // 残り経路長からdeadline_msに間に合わないと判定された
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -22,6 +36,24 @@ pub enum TaskStatus {
     Running,   // 処理中
     Completed, // 完了
     Failed,    // 失敗
+    Reassigned, // タイムアウト/担当ピア切断により他ピアへ再割り当て済み
+}
+
+impl TaskStatus {
+    /// CRDTマージで2つの状態がぶつかったときの優先順位。値が大きいほど優先される。
+    /// `Failed`と`Completed`が同時に観測された場合は`Failed`を勝たせる
+    /// （結果が不確かな場合は安全側＝失敗扱いに倒す）。
+    fn merge_rank(&self) -> u8 {
+        match self {
+            TaskStatus::Pending => 0,
+            TaskStatus::Sent => 1,
+            TaskStatus::Received => 2,
+            TaskStatus::Running => 3,
+            TaskStatus::Reassigned => 4,
+            TaskStatus::Completed => 5,
+            TaskStatus::Failed => 6,
+        }
+    }
 }
 
 impl TaskMetric {
@@ -39,6 +71,7 @@ impl TaskMetric {
             start_time: None,
             completion_time: None,
             status: TaskStatus::Sent,
+            deadline_at_risk: false,
         }
     }
 
@@ -59,20 +92,68 @@ impl TaskMetric {
     pub fn get_startup_latency(&self) -> Option<u64> {
         self.start_time.map(|st| st - self.sent_time)
     }
+
+    /// prune/ウィンドウ集計が使う「最後に意味のある更新を受けた時刻」。
+    /// `completion_time`があればそれ、なければ`sent_time`にフォールバックする
+    /// （`update_failed`は`completion_time`を設定しないので、失敗タスクは通常`sent_time`になる）。
+    fn effective_time(&self) -> u64 {
+        self.completion_time.unwrap_or(self.sent_time)
+    }
+
+    /// 同じ`task_id`を指す2つの計測値をCRDTとしてマージする。各タイムスタンプは値がある方/
+    /// 大きい方を採用し（`None`は欠損として扱う）、`sent_time`だけは最初に送信された時刻を
+    /// 失わないよう最小値を取る。`status`は[`TaskStatus::merge_rank`]の優先順位格子で解決する。
+    fn merge(&self, other: &TaskMetric) -> TaskMetric {
+        fn merge_opt(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (Some(a), None) => Some(a),
+                (None, Some(b)) => Some(b),
+                (None, None) => None,
+            }
+        }
+
+        let status = if other.status.merge_rank() > self.status.merge_rank() {
+            other.status.clone()
+        } else {
+            self.status.clone()
+        };
+
+        TaskMetric {
+            task_id: self.task_id,
+            peer_id: self.peer_id.clone(),
+            sent_time: self.sent_time.min(other.sent_time),
+            received_time: merge_opt(self.received_time, other.received_time),
+            start_time: merge_opt(self.start_time, other.start_time),
+            completion_time: merge_opt(self.completion_time, other.completion_time),
+            status,
+            deadline_at_risk: self.deadline_at_risk || other.deadline_at_risk,
+        }
+    }
 }
 
 /// タスク計測マネージャー
 pub struct TaskMetricsCollector {
     pub metrics: HashMap<u64, TaskMetric>,
+    /// `prune`が完了/失敗済みタスクを破棄するまでの保持期間。`None`なら`prune`は何もしない。
+    retention: Option<Duration>,
 }
 
 impl TaskMetricsCollector {
     pub fn new() -> Self {
         TaskMetricsCollector {
             metrics: HashMap::new(),
+            retention: None,
         }
     }
 
+    /// 保持期間を設定したコレクターを作るビルダー。長時間稼働するノードが
+    /// 見届けたタスクを無制限に溜め込まないよう、`prune`に上限を持たせたいときに使う。
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
     pub fn add_metric(&mut self, metric: TaskMetric) {
         self.metrics.insert(metric.task_id, metric);
     }
@@ -116,12 +197,77 @@ impl TaskMetricsCollector {
         }
     }
 
+    /// タイムアウトまたは担当ピアの切断により、タスクが他ピアへ再割り当てされたことを記録する
+    pub fn update_reassigned(&mut self, task_id: u64) {
+        if let Some(metric) = self.metrics.get_mut(&task_id) {
+            metric.status = TaskStatus::Reassigned;
+        }
+    }
+
+    /// 残り経路長からdeadline_msに間に合わないと判定されたタスクにフラグを立てる。
+    /// プランナーが毎tick呼ぶ想定で、一度立てたフラグは下ろさない（`update_reassigned`も
+    /// フラグには触れない）。
+    pub fn flag_deadline_at_risk(&mut self, task_id: u64) {
+        if let Some(metric) = self.metrics.get_mut(&task_id) {
+            metric.deadline_at_risk = true;
+        }
+    }
+
+    /// 他ピアから受け取った`TaskMetricsCollector`を自分にCRDTマージする。同じ`task_id`が
+    /// 両方にあれば[`TaskMetric::merge`]で解決し、片方にしかなければそのまま取り込む。
+    /// `peer_id`以外のフィールドのマージは可換・冪等なので、到着順やゴシップの重複配送に関わらず
+    /// 同じ結果に収束する。`peer_id`は既存エントリ（自分側）の値を残すため、同じ`task_id`が
+    /// 別ピアへ再割り当てされていた場合はレプリカ間で一致するとは限らない。
+    pub fn merge(&mut self, other: &TaskMetricsCollector) {
+        for (task_id, other_metric) in &other.metrics {
+            self.metrics
+                .entry(*task_id)
+                .and_modify(|existing| *existing = existing.merge(other_metric))
+                .or_insert_with(|| other_metric.clone());
+        }
+    }
+
+    /// 保持期間より古い完了/失敗済みタスクを破棄する。まだ`Pending`/`Sent`/`Received`/
+    /// `Running`のタスクは年齢に関係なく保持し続ける（結果がまだ確定していないため）。
+    /// `with_retention`で保持期間を設定していない場合は何もしない。
+    pub fn prune(&mut self, now_ms: u64) {
+        let Some(retention) = self.retention else {
+            return;
+        };
+        let retention_ms = retention.as_millis() as u64;
+        self.metrics.retain(|_, metric| {
+            if matches!(metric.status, TaskStatus::Completed | TaskStatus::Failed) {
+                now_ms.saturating_sub(metric.effective_time()) <= retention_ms
+            } else {
+                true
+            }
+        });
+    }
+
     /// 統計情報を取得
     pub fn get_statistics(&self) -> TaskStatistics {
-        let completed: Vec<&TaskMetric> = self
-            .metrics
-            .values()
+        self.statistics_matching(|_| true)
+    }
+
+    /// 直近`window_ms`ミリ秒以内に最後の更新があったタスクだけを対象にした統計を返す。
+    /// 生涯平均では長時間稼働するエージェントの直近のスループットが見えなくなるため、
+    /// ローリングウィンドウで最新の挙動を報告したいときに使う。
+    pub fn get_statistics_since(&self, window_ms: u64) -> TaskStatistics {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let since = now.saturating_sub(window_ms);
+        self.statistics_matching(|m| m.effective_time() >= since)
+    }
+
+    fn statistics_matching(&self, filter: impl Fn(&TaskMetric) -> bool) -> TaskStatistics {
+        let matching: Vec<&TaskMetric> = self.metrics.values().filter(|m| filter(m)).collect();
+
+        let completed: Vec<&TaskMetric> = matching
+            .iter()
             .filter(|m| m.status == TaskStatus::Completed)
+            .copied()
             .collect();
 
         let total_times: Vec<u64> = completed
@@ -157,12 +303,22 @@ impl TaskMetricsCollector {
             0
         };
 
+        // テール挙動(p95/p99)を見るため、total_timeを昇順ソートして最近接順位法で百分位を取る
+        let mut sorted_total_times = total_times.clone();
+        sorted_total_times.sort_unstable();
+        let percentile = |p: f64| {
+            if sorted_total_times.is_empty() {
+                0
+            } else {
+                sorted_total_times[nearest_rank_index(p, sorted_total_times.len())]
+            }
+        };
+
         TaskStatistics {
-            total_tasks: self.metrics.len(),
+            total_tasks: matching.len(),
             completed_tasks: completed.len(),
-            failed_tasks: self
-                .metrics
-                .values()
+            failed_tasks: matching
+                .iter()
                 .filter(|m| m.status == TaskStatus::Failed)
                 .count(),
             avg_total_time,
@@ -172,6 +328,9 @@ impl TaskMetricsCollector {
             max_total_time: total_times.iter().cloned().max().unwrap_or(0),
             min_processing_time: processing_times.iter().cloned().min().unwrap_or(0),
             max_processing_time: processing_times.iter().cloned().max().unwrap_or(0),
+            p50_total_time: percentile(50.0),
+            p95_total_time: percentile(95.0),
+            p99_total_time: percentile(99.0),
         }
     }
 
@@ -203,6 +362,7 @@ impl TaskMetricsCollector {
                 TaskStatus::Sent => "sent",
                 TaskStatus::Received => "received",
                 TaskStatus::Running => "running",
+                TaskStatus::Reassigned => "reassigned",
                 TaskStatus::Completed => "completed",
                 TaskStatus::Failed => "failed",
             };
@@ -222,8 +382,83 @@ impl TaskMetricsCollector {
             ));
         }
 
+        let stats = self.get_statistics();
+        csv.push_str(&format!(
+            "# p50_total_time_ms={},p95_total_time_ms={},p99_total_time_ms={}\n",
+            stats.p50_total_time, stats.p95_total_time, stats.p99_total_time
+        ));
+
         csv
     }
+
+    /// Prometheusのtext exposition formatで統計をレンダリングする。`to_csv_string`が
+    /// オペレーターの手元の生ログ向けなのに対し、こちらは標準的なスクレイパーで
+    /// 読めるcounter/gaugeとして`get_statistics`のスナップショットを公開する。
+    pub fn to_prometheus_string(&self) -> String {
+        let stats = self.get_statistics();
+        let pending_tasks = stats
+            .total_tasks
+            .saturating_sub(stats.completed_tasks)
+            .saturating_sub(stats.failed_tasks);
+        let mut out = String::new();
+
+        out.push_str("# HELP tswap_tasks_total Number of tasks observed, by status.\n");
+        out.push_str("# TYPE tswap_tasks_total counter\n");
+        out.push_str(&format!(
+            "tswap_tasks_total{{status=\"completed\"}} {}\n",
+            stats.completed_tasks
+        ));
+        out.push_str(&format!(
+            "tswap_tasks_total{{status=\"failed\"}} {}\n",
+            stats.failed_tasks
+        ));
+        out.push_str(&format!(
+            "tswap_tasks_total{{status=\"pending\"}} {}\n",
+            pending_tasks
+        ));
+
+        out.push_str(
+            "# HELP tswap_task_total_time_ms Task total time from send to completion, in milliseconds.\n",
+        );
+        out.push_str("# TYPE tswap_task_total_time_ms gauge\n");
+        for (stat, value) in [
+            ("avg", stats.avg_total_time),
+            ("min", stats.min_total_time),
+            ("max", stats.max_total_time),
+            ("p50", stats.p50_total_time),
+            ("p95", stats.p95_total_time),
+            ("p99", stats.p99_total_time),
+        ] {
+            out.push_str(&format!(
+                "tswap_task_total_time_ms{{stat=\"{stat}\"}} {value}\n"
+            ));
+        }
+
+        out.push_str(
+            "# HELP tswap_task_processing_time_ms Agent-side task processing time, in milliseconds.\n",
+        );
+        out.push_str("# TYPE tswap_task_processing_time_ms gauge\n");
+        for (stat, value) in [
+            ("avg", stats.avg_processing_time),
+            ("min", stats.min_processing_time),
+            ("max", stats.max_processing_time),
+        ] {
+            out.push_str(&format!(
+                "tswap_task_processing_time_ms{{stat=\"{stat}\"}} {value}\n"
+            ));
+        }
+
+        out.push_str(
+            "# HELP tswap_task_startup_latency_ms Delay between manager send and agent processing start, in milliseconds.\n",
+        );
+        out.push_str("# TYPE tswap_task_startup_latency_ms gauge\n");
+        out.push_str(&format!(
+            "tswap_task_startup_latency_ms{{stat=\"avg\"}} {}\n",
+            stats.avg_startup_latency
+        ));
+
+        out
+    }
 }
 
 /// タスク統計情報
@@ -239,6 +474,9 @@ pub struct TaskStatistics {
     pub max_total_time: u64,
     pub min_processing_time: u64,
     pub max_processing_time: u64,
+    pub p50_total_time: u64,
+    pub p95_total_time: u64,
+    pub p99_total_time: u64,
 }
 
 impl std::fmt::Display for TaskStatistics {
@@ -253,7 +491,8 @@ impl std::fmt::Display for TaskStatistics {
             ├─ Avg Processing Time: {} ms\n\
             ├─ Avg Startup Latency: {} ms\n\
             ├─ Min/Max Total Time: {} ms / {} ms\n\
-            └─ Min/Max Processing Time: {} ms / {} ms",
+            ├─ Min/Max Processing Time: {} ms / {} ms\n\
+            └─ Total Time p50/p95/p99: {} ms / {} ms / {} ms",
             self.total_tasks,
             self.completed_tasks,
             if self.total_tasks > 0 {
@@ -269,74 +508,235 @@ impl std::fmt::Display for TaskStatistics {
             self.max_total_time,
             self.min_processing_time,
             self.max_processing_time,
+            self.p50_total_time,
+            self.p95_total_time,
+            self.p99_total_time,
         )
     }
 }
 
+/// 固定長（f32のavg + u8のcount、計5バイト）に収まるストリーミング移動平均。
+/// `push`のたびに`avg = (avg * count + v) / (count + 1)`で更新し、`count`は255で飽和する。
+/// 255を超えた後は新しい`count`が増えないため、古いサンプルの重みが相対的に軽くなる
+/// 指数移動平均に近い挙動になる（厳密なExponential Moving Averageではない）。
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RunningAvg {
+    avg: f32,
+    count: u8,
+}
+
+impl RunningAvg {
+    pub fn new() -> Self {
+        Self { avg: 0.0, count: 0 }
+    }
+
+    pub fn push(&mut self, v: f32) {
+        let n = self.count as f32;
+        self.avg = (self.avg * n + v) / (n + 1.0);
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// マイクロ秒単位のサンプルを追加する。
+    pub fn push_micros(&mut self, micros: u128) {
+        self.push(micros as f32);
+    }
+
+    /// [`Duration`]からサンプルを追加する。
+    pub fn push_duration(&mut self, duration: Duration) {
+        self.push_micros(duration.as_micros());
+    }
+
+    pub fn avg_micros(&self) -> f32 {
+        self.avg
+    }
+
+    pub fn avg_millis(&self) -> f32 {
+        self.avg / 1000.0
+    }
+
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// `PathComputationMetrics`の内部ストレージ。`Full`は全サンプルを保持しp50/p95/p99まで
+/// 算出できるが無制限に増え続ける。`Bounded`は[`RunningAvg`]一本で固定長に収まる代わりに、
+/// 平均以外の統計（min/max/percentile）は平均で代替した近似値になる。
+#[derive(Debug, Clone)]
+enum PathSamples {
+    Full(Vec<u128>),
+    Bounded(RunningAvg),
+}
+
+impl Default for PathSamples {
+    fn default() -> Self {
+        PathSamples::Full(Vec::new())
+    }
+}
+
 /// Path computation metrics shared between centralized and decentralized managers.
 #[derive(Default, Debug, Clone)]
 pub struct PathComputationMetrics {
-    samples: Vec<u128>, // microseconds
+    samples: PathSamples, // microseconds
 }
 
 impl PathComputationMetrics {
     pub fn new() -> Self {
         Self {
-            samples: Vec::new(),
+            samples: PathSamples::Full(Vec::new()),
+        }
+    }
+
+    /// 制約のあるハードウェア向けに、サンプルを`RunningAvg`1個（5バイト）だけで
+    /// 保持する"bounded"モードで生成する。メモリ上限と引き換えにmin/max/percentileは
+    /// 平均値での近似になる。
+    pub fn new_bounded() -> Self {
+        Self {
+            samples: PathSamples::Bounded(RunningAvg::new()),
         }
     }
 
     pub fn clear(&mut self) {
-        self.samples.clear();
+        match &mut self.samples {
+            PathSamples::Full(samples) => samples.clear(),
+            PathSamples::Bounded(running) => *running = RunningAvg::new(),
+        }
     }
 
     /// Record a new duration sample using a [`Duration`].
     pub fn record_duration(&mut self, duration: Duration) {
-        self.samples.push(duration.as_micros());
+        self.record_micros(duration.as_micros());
     }
 
     /// Record a new sample directly in microseconds.
     pub fn record_micros(&mut self, micros: u128) {
-        self.samples.push(micros);
+        match &mut self.samples {
+            PathSamples::Full(samples) => samples.push(micros),
+            PathSamples::Bounded(running) => running.push_micros(micros),
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.samples.is_empty()
+        match &self.samples {
+            PathSamples::Full(samples) => samples.is_empty(),
+            PathSamples::Bounded(running) => running.is_empty(),
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.samples.len()
+        match &self.samples {
+            PathSamples::Full(samples) => samples.len(),
+            PathSamples::Bounded(running) => running.count() as usize,
+        }
     }
 
     pub fn get_statistics(&self) -> Option<PathComputationStatistics> {
-        if self.samples.is_empty() {
-            return None;
+        match &self.samples {
+            PathSamples::Full(samples) => {
+                if samples.is_empty() {
+                    return None;
+                }
+
+                let mut sorted = samples.clone();
+                sorted.sort_unstable();
+
+                let sum: u128 = sorted.iter().sum();
+                let avg = sum as f64 / sorted.len() as f64;
+                let min = *sorted.first().unwrap();
+                let max = *sorted.last().unwrap();
+                let percentile = |p: f64| sorted[nearest_rank_index(p, sorted.len())];
+
+                Some(PathComputationStatistics {
+                    samples: sorted.len(),
+                    avg_micros: avg,
+                    min_micros: min,
+                    max_micros: max,
+                    p50_micros: percentile(50.0),
+                    p95_micros: percentile(95.0),
+                    p99_micros: percentile(99.0),
+                })
+            }
+            PathSamples::Bounded(running) => {
+                if running.is_empty() {
+                    return None;
+                }
+
+                // min/max/percentileは保持していないため、平均値で近似する。
+                let avg = running.avg_micros() as f64;
+                let avg_rounded = running.avg_micros().round() as u128;
+
+                Some(PathComputationStatistics {
+                    samples: running.count() as usize,
+                    avg_micros: avg,
+                    min_micros: avg_rounded,
+                    max_micros: avg_rounded,
+                    p50_micros: avg_rounded,
+                    p95_micros: avg_rounded,
+                    p99_micros: avg_rounded,
+                })
+            }
         }
-
-        let mut sorted = self.samples.clone();
-        sorted.sort_unstable();
-
-        let sum: u128 = sorted.iter().sum();
-        let avg = sum as f64 / sorted.len() as f64;
-        let min = *sorted.first().unwrap();
-        let max = *sorted.last().unwrap();
-
-        Some(PathComputationStatistics {
-            samples: sorted.len(),
-            avg_micros: avg,
-            min_micros: min,
-            max_micros: max,
-        })
     }
 
     pub fn to_csv_string(&self) -> String {
         let mut csv = String::from("sample_index,duration_micros,duration_millis\n");
-        for (idx, &sample) in self.samples.iter().enumerate() {
-            let millis = sample as f64 / 1000.0;
-            csv.push_str(&format!("{idx},{sample},{millis:.3}\n"));
+        if let PathSamples::Full(samples) = &self.samples {
+            for (idx, &sample) in samples.iter().enumerate() {
+                let millis = sample as f64 / 1000.0;
+                csv.push_str(&format!("{idx},{sample},{millis:.3}\n"));
+            }
+        }
+        if let Some(stats) = self.get_statistics() {
+            csv.push_str(&format!(
+                "# p50_micros={},p95_micros={},p99_micros={}\n",
+                stats.p50_micros, stats.p95_micros, stats.p99_micros
+            ));
         }
         csv
     }
+
+    /// Prometheusのtext exposition formatでパス計算時間をヒストグラムとしてレンダリングする。
+    /// `Full`モードでは実サンプルから`le`バケットごとの累積カウントを出す。`Bounded`モードは
+    /// 個別サンプルを保持していないためバケット分布は出せず、`_sum`/`_count`のみを出力する。
+    pub fn to_prometheus_string(&self) -> String {
+        const BUCKETS_MICROS: [u128; 8] = [
+            100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000,
+        ];
+
+        let mut out = String::new();
+        out.push_str("# HELP tswap_path_computation_micros Path computation duration, in microseconds.\n");
+        out.push_str("# TYPE tswap_path_computation_micros histogram\n");
+
+        match &self.samples {
+            PathSamples::Full(samples) => {
+                let count = samples.len() as u128;
+                let sum: u128 = samples.iter().sum();
+                for &le in &BUCKETS_MICROS {
+                    let bucket_count = samples.iter().filter(|&&s| s <= le).count() as u128;
+                    out.push_str(&format!(
+                        "tswap_path_computation_micros_bucket{{le=\"{le}\"}} {bucket_count}\n"
+                    ));
+                }
+                out.push_str(&format!(
+                    "tswap_path_computation_micros_bucket{{le=\"+Inf\"}} {count}\n"
+                ));
+                out.push_str(&format!("tswap_path_computation_micros_sum {sum}\n"));
+                out.push_str(&format!("tswap_path_computation_micros_count {count}\n"));
+            }
+            PathSamples::Bounded(running) => {
+                let count = running.count() as u128;
+                let sum = (running.avg_micros() as u128).saturating_mul(count);
+                out.push_str(&format!("tswap_path_computation_micros_sum {sum}\n"));
+                out.push_str(&format!("tswap_path_computation_micros_count {count}\n"));
+            }
+        }
+
+        out
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -345,6 +745,9 @@ pub struct PathComputationStatistics {
     pub avg_micros: f64,
     pub min_micros: u128,
     pub max_micros: u128,
+    pub p50_micros: u128,
+    pub p95_micros: u128,
+    pub p99_micros: u128,
 }
 
 impl PathComputationStatistics {
@@ -359,6 +762,18 @@ impl PathComputationStatistics {
     pub fn max_millis(&self) -> f64 {
         self.max_micros as f64 / 1000.0
     }
+
+    pub fn p50_millis(&self) -> f64 {
+        self.p50_micros as f64 / 1000.0
+    }
+
+    pub fn p95_millis(&self) -> f64 {
+        self.p95_micros as f64 / 1000.0
+    }
+
+    pub fn p99_millis(&self) -> f64 {
+        self.p99_micros as f64 / 1000.0
+    }
 }
 
 impl std::fmt::Display for PathComputationStatistics {
@@ -369,11 +784,56 @@ impl std::fmt::Display for PathComputationStatistics {
             ├─ Samples: {}\n\
             ├─ Avg: {:.3} ms\n\
             ├─ Min: {:.3} ms\n\
-            └─ Max: {:.3} ms",
+            ├─ Max: {:.3} ms\n\
+            └─ p50/p95/p99: {:.3} ms / {:.3} ms / {:.3} ms",
             self.samples,
             self.avg_millis(),
             self.min_millis(),
-            self.max_millis()
+            self.max_millis(),
+            self.p50_millis(),
+            self.p95_millis(),
+            self.p99_millis()
         )
     }
 }
+
+/// `to_prometheus_string()`を`/metrics`として配る最小限のHTTPエンドポイント（任意）。
+/// `render`は毎リクエストごとに呼ばれるので、呼び出し側で`TaskMetricsCollector`/
+/// `PathComputationMetrics`の最新スナップショットを文字列化するクロージャを渡せばよい。
+/// `src/bin/manager.rs`の`serve_metrics`（prometheus_clientの`Registry`を直接encodeする版）
+/// と同じraw TCPの最小実装だが、こちらはレンダリング元をクロージャとして受け取る分汎用的。
+pub async fn serve_prometheus_text<F>(addr: &str, render: F)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("⚠️  Failed to bind metrics endpoint on {addr}: {e}");
+            return;
+        }
+    };
+    println!("📊 Metrics available at http://{addr}/metrics");
+
+    let render = Arc::new(render);
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        let render = render.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            // リクエストの中身は問わない。このエンドポイントはmetricsしか返さない。
+            let _ = stream.read(&mut buf).await;
+
+            let body = render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,138 @@
+use crate::map::task_generator::Task;
+use crate::map::task_metrics::{TaskMetric, TaskMetricsCollector, TaskStatus};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// `TaskScheduler`が`task_id`ごとに持つ再送状態。
+struct ScheduledTask {
+    task: Task,
+    task_id: u64,
+    deadline_ms: u64,
+    attempt: u32,
+}
+
+/// タスクの締め切り・再試行・指数バックオフを管理するサブシステム。`TaskMetricsCollector`を
+/// 内包し、`Sent`（再送後は`Failed`）のまま締め切りを過ぎた(= `Received`/`Running`/`Completed`に進まなかった)
+/// タスクを`Failed`として記録したうえで再送対象に積み直す。これにより、担当エージェントが
+/// ネットワークから脱落してタスクを黙って失う代わりに、at-least-onceなタスク配送になる。
+///
+/// ピアの再割り当て（「別のエージェントへ再送する」部分）はスケジューラの外側の責務にする。
+/// `poll_due`が返すタスクは`peer_id`がクリアされているので、呼び出し側（manager）が
+/// 空いている別のエージェントを選んで割り当て直す。上限回数再送しても届かなかったタスクは
+/// 追跡から外すので、代わりのタスクを生成するかどうかも呼び出し側の判断に委ねる。
+pub struct TaskScheduler {
+    metrics: TaskMetricsCollector,
+    scheduled: HashMap<u64, ScheduledTask>,
+    next_task_id: u64,
+    task_budget: Duration,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: u32,
+}
+
+impl TaskScheduler {
+    /// - `task_budget`: 初回送信から応答がない場合にタイムアウトと見なすまでの時間。
+    /// - `base_backoff`/`max_backoff`: 再送までの指数バックオフの初期値と上限。
+    /// - `max_attempts`: これを超える再送は諦めて追跡を打ち切る。
+    pub fn new(
+        task_budget: Duration,
+        base_backoff: Duration,
+        max_backoff: Duration,
+        max_attempts: u32,
+    ) -> Self {
+        Self {
+            metrics: TaskMetricsCollector::new(),
+            scheduled: HashMap::new(),
+            next_task_id: 1,
+            task_budget,
+            base_backoff,
+            max_backoff,
+            max_attempts,
+        }
+    }
+
+    pub fn metrics(&self) -> &TaskMetricsCollector {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut TaskMetricsCollector {
+        &mut self.metrics
+    }
+
+    /// 新しいタスクをスケジューラに登録し、払い出した`task_id`を返す。
+    pub fn schedule(&mut self, mut task: Task, peer_id: String, now_ms: u64) -> u64 {
+        let task_id = self.next_task_id;
+        self.next_task_id += 1;
+        task.peer_id = Some(peer_id.clone());
+        task.task_id = Some(task_id);
+
+        self.metrics.add_metric(TaskMetric::new(task_id, peer_id));
+        self.scheduled.insert(
+            task_id,
+            ScheduledTask {
+                task,
+                task_id,
+                deadline_ms: now_ms + self.task_budget.as_millis() as u64,
+                attempt: 0,
+            },
+        );
+        task_id
+    }
+
+    /// エージェントから`Received`/`Running`/`Completed`のいずれかの応答があったタスクを
+    /// 追跡対象から外す。タイムアウト判定の対象でなくなる。
+    pub fn acknowledge(&mut self, task_id: u64) {
+        self.scheduled.remove(&task_id);
+    }
+
+    /// 締め切りを過ぎても応答のないタスクを探し、`Failed`として記録したうえで
+    /// 指数バックオフ後の締め切りで再送対象に積み直す。再送の上限(`max_attempts`)に
+    /// 達したタスクは諦めて追跡から外す。戻り値はこの呼び出しで(再)送信すべきタスクの一覧で、
+    /// `peer_id`はクリア済みなので呼び出し側が新しい宛先を割り当てる。
+    pub fn poll_due(&mut self, now_ms: u64) -> Vec<Task> {
+        let mut due = Vec::new();
+        let mut exhausted = Vec::new();
+
+        for scheduled in self.scheduled.values_mut() {
+            if now_ms < scheduled.deadline_ms {
+                continue;
+            }
+
+            let still_in_flight = self
+                .metrics
+                .metrics
+                .get(&scheduled.task_id)
+                .map(|m| matches!(m.status, TaskStatus::Sent | TaskStatus::Failed))
+                .unwrap_or(false);
+            if !still_in_flight {
+                // Receivedより先に進んでいれば、タイムアウト判定の対象から外れている。
+                continue;
+            }
+
+            if scheduled.attempt >= self.max_attempts {
+                exhausted.push(scheduled.task_id);
+                continue;
+            }
+
+            self.metrics.update_failed(scheduled.task_id);
+            scheduled.attempt += 1;
+            scheduled.deadline_ms =
+                now_ms + Self::backoff_ms(self.base_backoff, self.max_backoff, scheduled.attempt);
+            scheduled.task.peer_id = None;
+            due.push(scheduled.task.clone());
+        }
+
+        for task_id in exhausted {
+            self.scheduled.remove(&task_id);
+        }
+
+        due
+    }
+
+    /// 試行回数に応じた指数バックオフ（1回目はベース遅延、以後倍々にしつつ上限でキャップ）。
+    fn backoff_ms(base_backoff: Duration, max_backoff: Duration, attempt: u32) -> u64 {
+        let base = base_backoff.as_millis() as u64;
+        let cap = max_backoff.as_millis() as u64;
+        base.saturating_mul(1u64 << attempt.saturating_sub(1).min(32)).min(cap)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::map::task_generator::Task;
+use crate::map::task_metrics::TaskMetric;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// `assignments`木に書き込む1レコード。担当ピアのPeerId文字列(Base58)とタスク本体を束ねて保存する
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredAssignment {
+    pub peer_id: String,
+    pub task: Task,
+}
+
+/// スケジューラの状態(進行中の割り当て/完了済みtask_id/TaskMetric)をsledへwrite-throughし、
+/// プロセス再起動後もin-flightなタスクとメトリクスを失わないようにする永続化レイヤー。
+/// `assignments`・`completed`・`metrics`の3本の木に分けて持つ
+pub struct TaskStore {
+    assignments: sled::Tree,
+    completed: sled::Tree,
+    metrics: sled::Tree,
+}
+
+impl TaskStore {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            assignments: db.open_tree("assignments")?,
+            completed: db.open_tree("completed")?,
+            metrics: db.open_tree("metrics")?,
+        })
+    }
+
+    /// タスクをピアへディスパッチ（あるいは再ディスパッチ）した時点でwrite-throughする
+    pub fn record_assignment(
+        &self,
+        task_id: u64,
+        peer_id: &str,
+        task: &Task,
+    ) -> Result<(), Box<dyn Error>> {
+        let record = StoredAssignment {
+            peer_id: peer_id.to_string(),
+            task: task.clone(),
+        };
+        self.assignments
+            .insert(task_id.to_be_bytes(), serde_json::to_vec(&record)?)?;
+        Ok(())
+    }
+
+    /// 完了通知を受けたタスクを`assignments`から`completed`へ移す
+    pub fn record_completion(&self, task_id: u64) -> Result<(), Box<dyn Error>> {
+        self.assignments.remove(task_id.to_be_bytes())?;
+        self.completed.insert(task_id.to_be_bytes(), &[])?;
+        Ok(())
+    }
+
+    /// `reset`コマンドなどで全状態を明示的に捨てたい時に、3本の木を全て空にする
+    pub fn clear_all(&self) -> Result<(), Box<dyn Error>> {
+        self.assignments.clear()?;
+        self.completed.clear()?;
+        self.metrics.clear()?;
+        Ok(())
+    }
+
+    /// TaskMetricの最新スナップショットをwrite-throughする
+    pub fn record_metric(&self, metric: &TaskMetric) -> Result<(), Box<dyn Error>> {
+        self.metrics
+            .insert(metric.task_id.to_be_bytes(), serde_json::to_vec(metric)?)?;
+        Ok(())
+    }
+
+    /// 起動直後に呼び、完了通知が来る前にクラッシュした（＝`assignments`木に残っている）
+    /// タスクを全て読み出す。`task_counter`の再開地点、`peer_task_map`/`task_peer_map`の
+    /// 再構築に使う
+    pub fn load_assignments(&self) -> Result<Vec<(u64, StoredAssignment)>, Box<dyn Error>> {
+        let mut out = Vec::new();
+        for entry in self.assignments.iter() {
+            let (key, value) = entry?;
+            let mut task_id_bytes = [0u8; 8];
+            task_id_bytes.copy_from_slice(&key);
+            let task_id = u64::from_be_bytes(task_id_bytes);
+            out.push((task_id, serde_json::from_slice(&value)?));
+        }
+        Ok(out)
+    }
+
+    /// 起動直後に呼び、保存されていたTaskMetricを全て読み出して`TaskMetricsCollector`を
+    /// 再構築できるようにする
+    pub fn load_metrics(&self) -> Result<Vec<TaskMetric>, Box<dyn Error>> {
+        let mut out = Vec::new();
+        for entry in self.metrics.iter() {
+            let (_, value) = entry?;
+            out.push(serde_json::from_slice(&value)?);
+        }
+        Ok(out)
+    }
+}
@@ -1,12 +1,14 @@
-use futures::StreamExt;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
 use libp2p::{
-    PeerId, gossipsub, identity, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    core::multiaddr::Protocol,
+    dcutr, gossipsub, identify, identity, kad, mdns, noise, ping, relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
     error::Error,
     hash::{Hash, Hasher},
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -14,114 +16,675 @@ use std::{
 use tokio::{
     io::{self, AsyncBufReadExt},
     select,
+    sync::mpsc,
 };
 
 use bincode::serde::{decode_from_slice, encode_to_vec};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Post {
     username: String,
     content: String,
     timestamp: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch {
+    posts: Vec<Post>,
+}
+
+// First byte of every gossipsub payload so old single-Post peers and new batching peers can
+// tell the two encodings apart on the same topic.
+const POST_TAG: u8 = 0;
+const BATCH_TAG: u8 = 1;
+// Flush the pending queue once it reaches this many posts, even before the flush timer fires.
+const BATCH_SIZE_THRESHOLD: usize = 10;
+// How many recently seen posts we keep around to answer HistoryRequests from new joiners.
+const HISTORY_CAPACITY: usize = 200;
+// Rejected beyond this length so a single post can't dominate a batch's bandwidth.
+const MAX_POST_CONTENT_LEN: usize = 500;
+// A Post whose timestamp is further than this from our clock (either direction) is rejected,
+// to catch clock-skewed or forged-timestamp senders.
+const MAX_TIMESTAMP_SKEW_SECS: u64 = 300;
+// Peers scoring below this are pruned from the mesh; mirrors the thresholds passed into
+// `with_peer_score` below.
+const GRAYLIST_SCORE_THRESHOLD: f64 = -80.0;
+
+/// Non-empty username, a bounded content length, and a timestamp within a sane skew of now.
+fn validate_post(post: &Post) -> bool {
+    if post.username.trim().is_empty() || post.content.len() > MAX_POST_CONTENT_LEN {
+        return false;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    now.abs_diff(post.timestamp) <= MAX_TIMESTAMP_SKEW_SECS
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryRequest {
+    since: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryResponse {
+    posts: Vec<Post>,
+}
+
+/// bincode-over-raw-stream codec for the `/sns/history/1.0.0` request/response protocol,
+/// mirroring how `Post`/`Batch` are already encoded for gossipsub.
+#[derive(Clone, Default)]
+struct HistoryCodec;
+
+#[async_trait::async_trait]
+impl request_response::Codec for HistoryCodec {
+    type Protocol = StreamProtocol;
+    type Request = HistoryRequest;
+    type Response = HistoryResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        decode_from_slice(&buf, bincode::config::standard())
+            .map(|(v, _)| v)
+            .map_err(io::Error::other)
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf).await?;
+        decode_from_slice(&buf, bincode::config::standard())
+            .map(|(v, _)| v)
+            .map_err(io::Error::other)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = encode_to_vec(&req, bincode::config::standard()).map_err(io::Error::other)?;
+        io.write_all(&bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = encode_to_vec(&res, bincode::config::standard()).map_err(io::Error::other)?;
+        io.write_all(&bytes).await
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    // Off when `--no-mdns` selects the Kademlia/bootstrap discovery path instead.
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    // Only on when `--no-mdns` is passed, seeded from `--bootstrap <multiaddr>...`.
+    kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+    history: request_response::Behaviour<HistoryCodec>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let username = whoami::username();
-    let key = identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(key.public());
-    println!("Local peer id: {peer_id}");
-
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| {
-            let message_id_fn = |message: &gossipsub::Message| {
-                let mut hasher = DefaultHasher::new();
-                message.data.hash(&mut hasher);
-                gossipsub::MessageId::from(hasher.finish().to_string())
-            };
+/// `/p2p/<PeerId>`-suffixed multiaddr to the `PeerId` it ends in (used for `--bootstrap`).
+fn peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|proto| match proto {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
 
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(10))
-                .validation_mode(gossipsub::ValidationMode::Strict)
-                .message_id_fn(message_id_fn)
-                .build()
-                .map_err(io::Error::other)?;
+/// Single place both discovery sources (mDNS and Kademlia) route newly discovered peers
+/// through: add them to gossipsub, request their backlog, and track them for scoring.
+fn on_peer_discovered(
+    swarm: &mut Swarm<MyBehaviour>,
+    known_peers: &mut HashSet<PeerId>,
+    history_since: u64,
+    peer_id: PeerId,
+) {
+    println!("Peer discovered: {peer_id}");
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+    swarm
+        .behaviour_mut()
+        .history
+        .send_request(&peer_id, HistoryRequest { since: history_since });
+    known_peers.insert(peer_id);
+}
 
-            let gossipsub = gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(key.clone()),
-                gossipsub_config,
-            )?;
+/// Counterpart to `on_peer_discovered`, called when either discovery source reports a peer
+/// is no longer reachable.
+fn on_peer_expired(swarm: &mut Swarm<MyBehaviour>, known_peers: &mut HashSet<PeerId>, peer_id: PeerId) {
+    println!("Peer expired: {peer_id}");
+    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+    known_peers.remove(&peer_id);
+}
 
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
-        })?
-        .build();
+/// Records a newly seen post in the bounded ring buffer used to answer HistoryRequests,
+/// dropping the oldest entry once `HISTORY_CAPACITY` is exceeded.
+fn push_history(history: &mut VecDeque<Post>, post: Post) {
+    if history.len() >= HISTORY_CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(post);
+}
 
-    let topic = gossipsub::IdentTopic::new("sns");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+/// Drains `pending`, wraps it in a `Batch`, and publishes it with `BATCH_TAG` prefixed. No-op
+/// if there is nothing queued (e.g. the 500ms timer fires with an empty queue).
+fn flush_pending_posts(
+    swarm: &mut Swarm<MyBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    pending: &mut VecDeque<Post>,
+) -> Result<(), Box<dyn Error>> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let batch = Batch {
+        posts: pending.drain(..).collect(),
+    };
+    let mut data = vec![BATCH_TAG];
+    data.extend(encode_to_vec(&batch, bincode::config::standard())?);
+    swarm.behaviour_mut().gossipsub.publish(topic.clone(), data)?;
+    Ok(())
+}
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+/// Commands a `Client` handle sends to the `Network` task that owns the swarm.
+#[derive(Debug)]
+enum NetworkCommand {
+    PublishPost(Post),
+    Subscribe(gossipsub::IdentTopic),
+}
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+/// Mirrors `decentralized/manager.rs`'s `ManagerEvent`: `next_event` surfaces at most one
+/// event per call, so a CLI front-end (or an in-process integration test driving several
+/// `Network`s at once) can step the network without being wired into one giant `select!`.
+#[derive(Debug)]
+enum NetworkEvent {
+    PostReceived(Post),
+    PeerDiscovered(PeerId),
+    PeerExpired(PeerId),
+    Idle,
+}
 
-    println!("Type /post [message] to broadcast");
+/// Thin handle a UI (or a test) uses to drive the `Network` task without touching the swarm
+/// directly.
+#[derive(Clone)]
+struct Client {
+    cmd_tx: mpsc::Sender<NetworkCommand>,
+}
 
-    loop {
+impl Client {
+    async fn publish_post(&self, post: Post) {
+        let _ = self.cmd_tx.send(NetworkCommand::PublishPost(post)).await;
+    }
+
+    async fn subscribe(&self, topic: gossipsub::IdentTopic) {
+        let _ = self.cmd_tx.send(NetworkCommand::Subscribe(topic)).await;
+    }
+}
+
+/// Owns the `Swarm<MyBehaviour>` and all the networking state that used to live as locals in
+/// `main`. The caller drives it by looping over `next_event`, which decouples the transport
+/// from whatever is consuming it (a stdin CLI today, an integration test harness tomorrow).
+struct Network {
+    swarm: Swarm<MyBehaviour>,
+    topic: gossipsub::IdentTopic,
+    relay_addr: Option<Multiaddr>,
+    relay_listen_requested: bool,
+    // Posts are queued here instead of being published immediately, and flushed as a single
+    // Batch once BATCH_SIZE_THRESHOLD is reached or the flush timer fires, to amortize
+    // gossipsub's per-message signing/validation/forwarding cost when several posts land at once.
+    pending_posts: VecDeque<Post>,
+    flush_tick: tokio::time::Interval,
+    // Ring buffer of recently seen posts (ours and relayed), answered to HistoryRequests from
+    // peers that just joined and otherwise would see an empty feed.
+    post_history: VecDeque<Post>,
+    // Peers known via mDNS/Kademlia, scanned periodically so ones that scored below the
+    // graylist threshold get dropped from the mesh instead of lingering until they reconnect.
+    known_peers: HashSet<PeerId>,
+    score_check_tick: tokio::time::Interval,
+    cmd_rx: mpsc::Receiver<NetworkCommand>,
+}
+
+impl Network {
+    async fn new(
+        relay_addr: Option<Multiaddr>,
+        no_mdns: bool,
+        bootstrap: Vec<Multiaddr>,
+        cmd_rx: mpsc::Receiver<NetworkCommand>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let key = identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(key.public());
+        println!("Local peer id: {peer_id}");
+
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(key)
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            // QUIC's single-round-trip encrypted handshake (no separate noise+yamux negotiation)
+            // helps on lossy mobile links where many short-lived peers come and go.
+            .with_quic()
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| {
+                let message_id_fn = |message: &gossipsub::Message| {
+                    let mut hasher = DefaultHasher::new();
+                    message.data.hash(&mut hasher);
+                    gossipsub::MessageId::from(hasher.finish().to_string())
+                };
+
+                let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(Duration::from_secs(10))
+                    .validation_mode(gossipsub::ValidationMode::Permissive)
+                    .validate_messages() // we report Accept/Reject/Ignore ourselves after decoding+validating the Post(s)
+                    .message_id_fn(message_id_fn)
+                    .build()
+                    .map_err(io::Error::other)?;
+
+                let mut gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?;
+
+                // Down-score and eventually prune peers that keep sending invalid Posts.
+                // Invalid-message-delivery scoring is topic-gated, so this only takes effect
+                // once a `TopicScoreParams` is registered for the "sns" topic below.
+                let mut topic_score_params = gossipsub::TopicScoreParams::default();
+                topic_score_params.invalid_message_deliveries_weight = -20.0;
+                topic_score_params.invalid_message_deliveries_decay = 0.3;
+
+                let mut peer_score_params = gossipsub::PeerScoreParams::default();
+                peer_score_params
+                    .topics
+                    .insert(gossipsub::IdentTopic::new("sns").hash(), topic_score_params);
+
+                let peer_score_thresholds = gossipsub::PeerScoreThresholds {
+                    gossip_threshold: -10.0,
+                    publish_threshold: -50.0,
+                    graylist_threshold: GRAYLIST_SCORE_THRESHOLD,
+                    ..Default::default()
+                };
+                gossipsub
+                    .with_peer_score(peer_score_params, peer_score_thresholds)
+                    .map_err(io::Error::other)?;
+
+                let mdns = if no_mdns {
+                    Toggle::from(None)
+                } else {
+                    Toggle::from(Some(mdns::tokio::Behaviour::new(
+                        mdns::Config::default(),
+                        key.public().to_peer_id(),
+                    )?))
+                };
+
+                let kad = if no_mdns {
+                    let mut kad = kad::Behaviour::new(
+                        key.public().to_peer_id(),
+                        kad::store::MemoryStore::new(key.public().to_peer_id()),
+                    );
+                    kad.set_mode(Some(kad::Mode::Server));
+                    Toggle::from(Some(kad))
+                } else {
+                    Toggle::from(None)
+                };
+
+                let identify = identify::Behaviour::new(identify::Config::new(
+                    "/sns/0.1.0".to_string(),
+                    key.public(),
+                ));
+                let ping = ping::Behaviour::new(ping::Config::new());
+                let dcutr = dcutr::Behaviour::new(key.public().to_peer_id());
+
+                let history = request_response::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/sns/history/1.0.0"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                Ok(MyBehaviour {
+                    gossipsub,
+                    mdns,
+                    kad,
+                    relay_client,
+                    dcutr,
+                    identify,
+                    ping,
+                    history,
+                })
+            })?
+            .build();
+
+        let topic = gossipsub::IdentTopic::new("sns");
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+
+        // Dial each --bootstrap peer directly and, when mDNS is off, seed Kademlia's routing table
+        // with it, then kick off a bootstrap query to start filling in the rest of the DHT.
+        for addr in &bootstrap {
+            match swarm.dial(addr.clone()) {
+                Ok(()) => println!("Dialing bootstrap peer: {addr}"),
+                Err(e) => println!("Failed to dial bootstrap peer {addr}: {e:?}"),
+            }
+            if let Some(peer_id) = peer_id_from_multiaddr(addr) {
+                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                    kad.add_address(&peer_id, addr.clone());
+                }
+            } else {
+                println!("Bootstrap address {addr} has no /p2p/<PeerId> suffix, skipping add_explicit_peer");
+            }
+        }
+        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+            let _ = kad.bootstrap();
+        }
+
+        // Once identify tells us our externally observed address (via the relay connection), we
+        // listen on a /p2p-circuit address off that relay so remote peers behind their own NATs
+        // can reach us through the reservation; DCUtR then tries to upgrade to a direct connection.
+        if let Some(addr) = &relay_addr {
+            match swarm.dial(addr.clone()) {
+                Ok(()) => println!("Dialing relay: {addr}"),
+                Err(e) => println!("Failed to dial relay {addr}: {e:?}"),
+            }
+        }
+
+        Ok(Self {
+            swarm,
+            topic,
+            relay_addr,
+            relay_listen_requested: false,
+            pending_posts: VecDeque::new(),
+            flush_tick: tokio::time::interval(Duration::from_millis(500)),
+            post_history: VecDeque::new(),
+            known_peers: HashSet::new(),
+            score_check_tick: tokio::time::interval(Duration::from_secs(2)),
+            cmd_rx,
+        })
+    }
+
+    fn handle_command(&mut self, cmd: NetworkCommand) -> NetworkEvent {
+        match cmd {
+            NetworkCommand::PublishPost(post) => {
+                push_history(&mut self.post_history, post.clone());
+                self.pending_posts.push_back(post);
+                if self.pending_posts.len() >= BATCH_SIZE_THRESHOLD {
+                    if let Err(e) =
+                        flush_pending_posts(&mut self.swarm, &self.topic, &mut self.pending_posts)
+                    {
+                        println!("⚠️  Flush error: {e:?}");
+                    }
+                }
+                NetworkEvent::Idle
+            }
+            NetworkCommand::Subscribe(topic) => {
+                if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                    println!("⚠️  Subscribe error: {e:?}");
+                }
+                NetworkEvent::Idle
+            }
+        }
+    }
+
+    /// Processes one command, timer tick, or swarm event and returns the single `NetworkEvent`
+    /// it produced (or `Idle` if it was purely internal bookkeeping).
+    async fn next_event(&mut self) -> NetworkEvent {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                if let Some(msg) = line.strip_prefix("/post ") {
-                    let post = Post {
-                        username: username.clone(),
-                        content: msg.to_string(),
-                        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
-                    };
-                    let data: Vec<u8> = encode_to_vec(&post, bincode::config::standard())?;
-                    swarm.behaviour_mut().gossipsub.publish(topic.clone(), data)?;
+            Some(cmd) = self.cmd_rx.recv() => self.handle_command(cmd),
+            _ = self.flush_tick.tick() => {
+                if let Err(e) = flush_pending_posts(&mut self.swarm, &self.topic, &mut self.pending_posts) {
+                    println!("⚠️  Flush error: {e:?}");
                 }
+                NetworkEvent::Idle
             }
-            event = swarm.select_next_some() => match event {
+            _ = self.score_check_tick.tick() => {
+                let graylisted: Vec<PeerId> = self.known_peers
+                    .iter()
+                    .filter(|peer_id| self.swarm.behaviour().gossipsub.peer_score(peer_id).unwrap_or(0.0) < GRAYLIST_SCORE_THRESHOLD)
+                    .cloned()
+                    .collect();
+                for peer_id in &graylisted {
+                    println!("Peer {peer_id} graylisted (score below {GRAYLIST_SCORE_THRESHOLD}), removing from mesh");
+                    self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(peer_id);
+                    self.known_peers.remove(peer_id);
+                }
+                NetworkEvent::Idle
+            }
+            event = self.swarm.select_next_some() => match event {
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    let since = self.post_history.back().map(|post| post.timestamp).unwrap_or(0);
+                    let mut discovered = None;
                     for (peer_id, _multiaddr) in list {
-                        println!("mDNS discovered a new peer: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        on_peer_discovered(&mut self.swarm, &mut self.known_peers, since, peer_id);
+                        discovered = Some(peer_id);
+                    }
+                    match discovered {
+                        Some(peer_id) => NetworkEvent::PeerDiscovered(peer_id),
+                        None => NetworkEvent::Idle,
                     }
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                    let mut expired = None;
                     for (peer_id, _multiaddr) in list {
-                        println!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        on_peer_expired(&mut self.swarm, &mut self.known_peers, peer_id);
+                        expired = Some(peer_id);
+                    }
+                    match expired {
+                        Some(peer_id) => NetworkEvent::PeerExpired(peer_id),
+                        None => NetworkEvent::Idle,
                     }
                 },
-                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
-                    if let Ok((post, _)) = decode_from_slice::<Post, _>(&message.data, bincode::config::standard()) {
-                        println!("[{}] {}: {}", post.timestamp, post.username, post.content);
+                // Kademlia's routing-table updates are this discovery path's equivalent of
+                // mDNS's Discovered/Expired, routed through the same two helpers above.
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::RoutingUpdated { peer, is_new_peer, .. })) => {
+                    if is_new_peer {
+                        let since = self.post_history.back().map(|post| post.timestamp).unwrap_or(0);
+                        on_peer_discovered(&mut self.swarm, &mut self.known_peers, since, peer);
+                        NetworkEvent::PeerDiscovered(peer)
+                    } else {
+                        NetworkEvent::Idle
+                    }
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::UnroutablePeer { peer })) => {
+                    on_peer_expired(&mut self.swarm, &mut self.known_peers, peer);
+                    NetworkEvent::PeerExpired(peer)
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                    // With `validate_messages()` set, gossipsub withholds forwarding until we
+                    // explicitly report Accept/Reject/Ignore for this message_id.
+                    let mut last_post = None;
+                    let acceptance = match message.data.split_first() {
+                        Some((&BATCH_TAG, rest)) => match decode_from_slice::<Batch, _>(rest, bincode::config::standard()) {
+                            Ok((mut batch, _)) if batch.posts.iter().all(validate_post) => {
+                                batch.posts.sort_by_key(|post| post.timestamp);
+                                for post in batch.posts {
+                                    println!("[{}] {}: {}", post.timestamp, post.username, post.content);
+                                    push_history(&mut self.post_history, post.clone());
+                                    last_post = Some(post);
+                                }
+                                gossipsub::MessageAcceptance::Accept
+                            }
+                            Ok(_) => gossipsub::MessageAcceptance::Reject,
+                            Err(_) => gossipsub::MessageAcceptance::Reject,
+                        },
+                        Some((&POST_TAG, rest)) => match decode_from_slice::<Post, _>(rest, bincode::config::standard()) {
+                            Ok((post, _)) if validate_post(&post) => {
+                                println!("[{}] {}: {}", post.timestamp, post.username, post.content);
+                                push_history(&mut self.post_history, post.clone());
+                                last_post = Some(post);
+                                gossipsub::MessageAcceptance::Accept
+                            }
+                            Ok(_) => gossipsub::MessageAcceptance::Reject,
+                            Err(_) => gossipsub::MessageAcceptance::Reject,
+                        },
+                        _ => gossipsub::MessageAcceptance::Reject,
+                    };
+                    let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance);
+                    match last_post {
+                        Some(post) => NetworkEvent::PostReceived(post),
+                        None => NetworkEvent::Idle,
                     }
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, .. })) => {
                     println!("Subscribed peer: {peer_id}");
+                    NetworkEvent::Idle
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, .. })) => {
                     println!("Unsubscribed peer: {peer_id}");
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { info, .. })) => {
+                    println!("Identify: observed address {}", info.observed_addr);
+                    if !self.relay_listen_requested {
+                        if let Some(relay_addr) = &self.relay_addr {
+                            let circuit_addr = relay_addr.clone().with(Protocol::P2pCircuit);
+                            match self.swarm.listen_on(circuit_addr.clone()) {
+                                Ok(_) => println!("Listening for circuit relay connections on {circuit_addr}"),
+                                Err(e) => println!("Failed to listen on {circuit_addr}: {e:?}"),
+                            }
+                            self.relay_listen_requested = true;
+                        }
+                    }
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, .. })) => {
+                    println!("Relay reservation accepted by {relay_peer_id}");
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                    match result {
+                        Ok(_) => println!("DCUtR: direct connection upgrade with {remote_peer_id} succeeded"),
+                        Err(e) => println!("DCUtR: direct connection upgrade with {remote_peer_id} failed: {e:?}"),
+                    }
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::History(request_response::Event::Message { peer, message })) => {
+                    match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            let posts: Vec<Post> = self.post_history
+                                .iter()
+                                .filter(|post| post.timestamp > request.since)
+                                .cloned()
+                                .collect();
+                            println!("Sending {} backlog post(s) to {peer}", posts.len());
+                            let _ = self.swarm.behaviour_mut().history.send_response(channel, HistoryResponse { posts });
+                            NetworkEvent::Idle
+                        }
+                        request_response::Message::Response { response, .. } => {
+                            let mut posts = response.posts;
+                            posts.sort_by_key(|post| post.timestamp);
+                            let mut last_post = None;
+                            for post in posts {
+                                println!("[{}] {}: {} (backlog from {peer})", post.timestamp, post.username, post.content);
+                                push_history(&mut self.post_history, post.clone());
+                                last_post = Some(post);
+                            }
+                            match last_post {
+                                Some(post) => NetworkEvent::PostReceived(post),
+                                None => NetworkEvent::Idle,
+                            }
+                        }
+                    }
                 },
-                SwarmEvent::Behaviour(_) => {},
+                SwarmEvent::Behaviour(MyBehaviourEvent::History(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                    println!("History request to {peer} failed: {error:?}");
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::History(request_response::Event::InboundFailure { peer, error, .. })) => {
+                    println!("History request from {peer} failed: {error:?}");
+                    NetworkEvent::Idle
+                },
+                SwarmEvent::Behaviour(_) => NetworkEvent::Idle,
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Listening on {address}");
+                    NetworkEvent::Idle
                 },
-                _ => {}
+                _ => NetworkEvent::Idle,
             }
         }
     }
 }
+
+/// Thin CLI front-end: parses `/post` lines from stdin and drives the `Network` task purely
+/// through its `Client` handle, decoupled from the transport entirely.
+async fn run_stdin_client(username: String, client: Client) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    println!("Type /post [message] to broadcast");
+    while let Ok(Some(line)) = stdin.next_line().await {
+        if let Some(msg) = line.strip_prefix("/post ") {
+            let post = Post {
+                username: username.clone(),
+                content: msg.to_string(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            };
+            client.publish_post(post).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let username = whoami::username();
+    let args: Vec<String> = std::env::args().collect();
+
+    // --relay <multiaddr>: a relay server to dial so peers behind NATs can reach us via a
+    // /p2p-circuit reservation, with DCUtR attempting a direct upgrade once connected through it
+    let relay_addr: Option<Multiaddr> = args
+        .iter()
+        .position(|a| a == "--relay")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|s| s.parse().ok());
+
+    // --no-mdns: use Kademlia seeded from --bootstrap instead of LAN-only mDNS discovery
+    let no_mdns = args.contains(&"--no-mdns".to_string());
+    // --bootstrap <multiaddr>: repeatable, always dialed, and fed to Kademlia when --no-mdns is set
+    let bootstrap: Vec<Multiaddr> = args
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.as_str() == "--bootstrap")
+        .filter_map(|(i, _)| args.get(i + 1))
+        .filter_map(|s| match s.parse::<Multiaddr>() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                println!("Invalid --bootstrap multiaddr {s}: {e:?}");
+                None
+            }
+        })
+        .collect();
+
+    let (cmd_tx, cmd_rx) = mpsc::channel(32);
+    let client = Client { cmd_tx };
+    tokio::spawn(run_stdin_client(username, client));
+
+    let mut network = Network::new(relay_addr, no_mdns, bootstrap, cmd_rx).await?;
+    loop {
+        network.next_event().await;
+    }
+}